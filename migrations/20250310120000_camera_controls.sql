@@ -0,0 +1,7 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS camera_controls (
+  device_id VARCHAR NOT NULL,
+  control_id INTEGER NOT NULL,
+  value INTEGER NOT NULL,
+  PRIMARY KEY (device_id, control_id)
+);
@@ -0,0 +1,13 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS recordings (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  file_name VARCHAR NOT NULL UNIQUE,
+  start_time INTEGER NOT NULL,
+  end_time INTEGER,
+  size INTEGER NOT NULL DEFAULT 0,
+  width INTEGER,
+  height INTEGER,
+  camera VARCHAR,
+  closed BOOLEAN NOT NULL DEFAULT FALSE
+);
+CREATE INDEX IF NOT EXISTS recordings_start_time ON recordings (start_time);
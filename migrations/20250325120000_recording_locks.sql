@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE recordings ADD COLUMN locked BOOLEAN NOT NULL DEFAULT FALSE;
@@ -0,0 +1,15 @@
+-- Add migration script here
+CREATE TABLE IF NOT EXISTS timelapses (
+  id INTEGER PRIMARY KEY AUTOINCREMENT,
+  source VARCHAR NOT NULL,
+  from_time INTEGER NOT NULL,
+  to_time INTEGER NOT NULL,
+  interval INTEGER NOT NULL,
+  fps INTEGER NOT NULL,
+  state VARCHAR NOT NULL,
+  progress REAL NOT NULL DEFAULT 0,
+  frames INTEGER NOT NULL DEFAULT 0,
+  size INTEGER NOT NULL DEFAULT 0,
+  error VARCHAR,
+  created_at INTEGER NOT NULL
+);
@@ -0,0 +1,2 @@
+-- Add migration script here
+ALTER TABLE recordings ADD COLUMN remux_pending BOOLEAN NOT NULL DEFAULT FALSE;
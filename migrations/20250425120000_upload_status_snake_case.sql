@@ -0,0 +1,2 @@
+-- Add migration script here
+UPDATE recordings SET upload_status = lower(upload_status);
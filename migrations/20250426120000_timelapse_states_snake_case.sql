@@ -0,0 +1,2 @@
+-- Add migration script here
+UPDATE timelapses SET source = lower(source), state = lower(state);
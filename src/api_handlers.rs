@@ -1,11 +1,14 @@
-use std::{path::PathBuf, str::FromStr, sync::Arc};
+use std::sync::Arc;
 
 use poem::{session::Session, web, FromRequest};
-use poem_openapi::{param::Path, payload::{Binary, Json, Response}, ApiResponse, Object, OpenApi};
-use crate::{models::*, storage::*};
+use poem_openapi::{param::{Path, Query}, payload::{Binary, Json, Response}, ApiResponse, Object, OpenApi};
+use crate::{export::Exports, models::*, storage::*, timelapse::Timelapses, viewers::Viewers};
 
 type Result<T> = std::result::Result<T, Error>;
 
+const DEFAULT_RECORDINGS_LIMIT: u32 = 100;
+const MAX_RECORDINGS_LIMIT: u32 = 1000;
+
 #[derive(ApiResponse)]
 pub enum Error {
     #[oai (status="404")]
@@ -25,6 +28,12 @@ impl Error {
         }))
     }
 
+    pub fn bad_request(error: String) -> Self {
+        Self::BadRequest(Json(ErrorMessage{
+            error
+        }))
+    }
+
     pub fn server_error(error: String) -> Self {
         Self::ServerError(Json(ErrorMessage{
             error
@@ -88,44 +97,308 @@ pub struct Api;
 
 #[OpenApi]
 impl Api {
-    /// Hello world
+    /// Lists indexed recordings, `from` and `to` are unix timestamps in milliseconds
     #[oai(path = "/recordings", method = "get")]
-    async fn list_recordings(&self, storage: web::Data<&Arc<Storage>>) -> Result<Json<Vec<String>>> {
-        let mut recordings = Vec::new();
+    async fn list_recordings(
+        &self,
+        Query(from): Query<Option<i64>>,
+        Query(to): Query<Option<i64>>,
+        Query(camera): Query<Option<String>>,
+        Query(locked): Query<Option<bool>>,
+        Query(sort): Query<Option<RecordingSort>>,
+        Query(order): Query<Option<SortOrder>>,
+        Query(offset): Query<Option<u32>>,
+        Query(limit): Query<Option<u32>>,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Json<RecordingPage>> {
+        let limit = limit.unwrap_or(DEFAULT_RECORDINGS_LIMIT);
+        if limit == 0 || limit > MAX_RECORDINGS_LIMIT {
+            Err(Error::bad_request(format!("limit has to be between 1 and {MAX_RECORDINGS_LIMIT}")))?;
+        }
 
-        let mut dir = tokio::fs::read_dir(storage.config.app_data.clone()).await
-            .map_err(|e| Error::server_error(format!("Failed to create path to app_data dir {e:?}")))?;
+        let query = RecordingQuery {
+            from,
+            to,
+            camera,
+            locked,
+            sort: sort.unwrap_or_default(),
+            order: order.unwrap_or_default(),
+            offset: offset.unwrap_or_default(),
+            limit,
+        };
+        Ok(Json(storage.recordings.list_recordings(&query).await))
+    }
 
-        while let Ok(Some(f)) = dir.next_entry().await {
-            let path = f.path();
-            if path.extension().map(|e| e == "mp4").unwrap_or(false) {
-                recordings.push(path.file_name().unwrap_or_default().to_str().unwrap_or_default().to_string());
+
+    /// Streams a recording by file name, only reaches recordings outside date directories
+    #[oai(path = "/recordings/:recording", method = "get")]
+    async fn download_recordings(
+        &self,
+        Path(recording): Path<String>,
+        headers: &poem::http::HeaderMap,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Response<Binary<poem::Body>>> {
+        let relative = std::path::Path::new(&recording);
+        if !relative.components().all(|c| matches!(c, std::path::Component::Normal(_))) {
+            log::warn!("Rejected recording path {recording:?}");
+            Err(Error::bad_request("Invalid recording name".to_string()))?;
+        }
+        let indexed = storage.recordings.get_recording(&recording).await
+            .ok_or_else(|| Error::not_found("Recording not found".to_string()))?;
+
+        let path = crate::file_sink::recording_path(&storage.config.app_data, &indexed);
+        crate::file_response::serve_file(&path, headers, "video/mp4").await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::not_found("Recording not found".to_string()),
+                _ => Error::server_error(format!("Failed to read content of recording {e:?}")),
+            })
+    }
+
+    /// Streams a recording by id, supports range requests so browsers can seek
+    #[oai(path = "/recordings/:id/download", method = "get")]
+    async fn download_recording_by_id(
+        &self,
+        Path(id): Path<i64>,
+        headers: &poem::http::HeaderMap,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Response<Binary<poem::Body>>> {
+        let recording = storage.recordings.get_recording_by_id(id).await
+            .ok_or_else(|| Error::not_found("Recording not found".to_string()))?;
+
+        let path = crate::file_sink::recording_path(&storage.config.app_data, &recording);
+        crate::file_response::serve_file(&path, headers, "video/mp4").await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::not_found("Recording not found".to_string()),
+                _ => Error::server_error(format!("Failed to read content of recording {e:?}")),
+            })
+    }
+
+
+    /// Protects a recording from retention, within the `max_locked_bytes` quota
+    #[oai(path = "/recordings/:id/lock", method = "post")]
+    async fn lock_recording(&self, Path(id): Path<i64>, storage: web::Data<&Arc<Storage>>) -> Result<Json<Recording>> {
+        let mut recording = storage.recordings.get_recording_by_id(id).await
+            .ok_or_else(|| Error::not_found("Recording not found".to_string()))?;
+        if recording.locked {
+            return Ok(Json(recording));
+        }
+
+        if let Some(max_locked_bytes) = storage.file_config.get().await.max_locked_bytes {
+            let (_, locked_bytes) = storage.recordings.recordings_usage(true).await;
+            if locked_bytes + recording.size > max_locked_bytes {
+                Err(Error::bad_request(format!(
+                    "Locking would use {} of {max_locked_bytes} bytes reserved for locked recordings",
+                    locked_bytes + recording.size
+                )))?;
             }
         }
-        
-        Ok(Json(recordings))
+
+        storage.recordings.set_locked(id, true).await;
+        recording.locked = true;
+        Ok(Json(recording))
     }
 
+    #[oai(path = "/recordings/:id/unlock", method = "post")]
+    async fn unlock_recording(&self, Path(id): Path<i64>, storage: web::Data<&Arc<Storage>>) -> Result<Json<Recording>> {
+        let mut recording = storage.recordings.get_recording_by_id(id).await
+            .ok_or_else(|| Error::not_found("Recording not found".to_string()))?;
+        storage.recordings.set_locked(id, false).await;
+        recording.locked = false;
+        Ok(Json(recording))
+    }
 
-    #[oai(path = "/recordings/:recording", method = "get")]
-    async fn download_recordings(&self, Path(recording): Path<String>, storage: web::Data<&Arc<Storage>>) -> Result<Response<Binary<Vec<u8>>>> {
+    /// JPEG of the first keyframe, generated on first request if it's missing
+    #[oai(path = "/recordings/:id/thumbnail", method = "get")]
+    async fn recording_thumbnail(
+        &self,
+        Path(id): Path<i64>,
+        headers: &poem::http::HeaderMap,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Response<Binary<poem::Body>>> {
+        let recording = storage.recordings.get_recording_by_id(id).await
+            .ok_or_else(|| Error::not_found("Recording not found".to_string()))?;
+        let preview_interval = storage.file_config.get().await.preview_interval;
+        let path = crate::thumbnails::thumbnail(&storage.config.app_data, &recording, preview_interval).await
+            .map_err(Error::server_error)?;
+
+        crate::file_response::serve_file(&path, headers, "image/jpeg").await
+            .map_err(|e| Error::server_error(format!("Failed to read thumbnail {e:?}")))
+    }
 
-        let path = PathBuf::from_str(&(storage.config.app_data.clone() + "/" + &recording))
-            .map_err(|e| Error::server_error(format!("Failed to create path to app_data dir {e:?}")))?;
+    /// Preview frame `index` (from 1), taken `index * preview_interval` seconds into the recording
+    #[oai(path = "/recordings/:id/previews/:index", method = "get")]
+    async fn recording_preview(
+        &self,
+        Path(id): Path<i64>,
+        Path(index): Path<u64>,
+        headers: &poem::http::HeaderMap,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Response<Binary<poem::Body>>> {
+        let path = crate::thumbnails::preview_path(&storage.config.app_data, id, index);
+        crate::file_response::serve_file(&path, headers, "image/jpeg").await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::not_found("Preview not found".to_string()),
+                _ => Error::server_error(format!("Failed to read preview {e:?}")),
+            })
+    }
 
-        if path.parent().map(|p| p.as_os_str() != storage.config.app_data.as_str()).unwrap_or(true) {
-            println!("Tempered path from user side");
+    /// Cuts `start..end` (ms from the start of the recording) into a standalone mp4 in the background,
+    /// without `precise` the cut moves to the enclosing keyframes and nothing is re-encoded
+    #[oai(path = "/recordings/:id/export", method = "post")]
+    async fn export_recording(
+        &self,
+        Path(id): Path<i64>,
+        Query(start): Query<i64>,
+        Query(end): Query<i64>,
+        Query(precise): Query<Option<bool>>,
+        storage: web::Data<&Arc<Storage>>,
+        exports: web::Data<&Exports>,
+    ) -> Result<Json<ExportJob>> {
+        if start < 0 || start >= end {
+            Err(Error::bad_request("start has to be positive and before end".to_string()))?;
         }
-        if !tokio::fs::try_exists(&path).await.map_err(|e| Error::server_error(format!("Failed to read content of recording {e:?}")))? {
-            Err(Error::not_found("Recording not found".to_string()))?;
+        let recording = storage.recordings.get_recording_by_id(id).await
+            .ok_or_else(|| Error::not_found("Recording not found".to_string()))?;
+        if recording.duration.is_some_and(|duration| start >= duration) {
+            Err(Error::bad_request("start is past the end of the recording".to_string()))?;
         }
 
-        let content = tokio::fs::read(&path).await
-            .map_err(|e| Error::server_error(format!("Failed to read content of recording {e:?}")))?; // TODO handle this error
+        let job = exports.start(Arc::clone(storage.0), recording, start, end, precise.unwrap_or(false)).await;
+        Ok(Json(job))
+    }
 
-        Ok(Response::new(Binary(content)).header(poem::http::header::CONTENT_TYPE, "video/mp4"))
+    #[oai(path = "/exports", method = "get")]
+    async fn list_exports(&self, exports: web::Data<&Exports>) -> Json<Vec<ExportJob>> {
+        Json(exports.list())
     }
 
+    /// Progress of an export, `download` is set once it's done
+    #[oai(path = "/exports/:id", method = "get")]
+    async fn get_export(&self, Path(id): Path<u64>, exports: web::Data<&Exports>) -> Result<Json<ExportJob>> {
+        let job = exports.get(id)
+            .ok_or_else(|| Error::not_found("Export not found".to_string()))?;
+        Ok(Json(job))
+    }
+
+    #[oai(path = "/exports/:id/download", method = "get")]
+    async fn download_export(
+        &self,
+        Path(id): Path<u64>,
+        headers: &poem::http::HeaderMap,
+        storage: web::Data<&Arc<Storage>>,
+        exports: web::Data<&Exports>,
+    ) -> Result<Response<Binary<poem::Body>>> {
+        let job = exports.get(id)
+            .filter(|j| j.state == ExportState::Done)
+            .ok_or_else(|| Error::not_found("Export not found or not finished".to_string()))?;
+
+        let path = crate::export::export_path(&storage.config.app_data, job.id);
+        let response = crate::file_response::serve_file(&path, headers, "video/mp4").await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::not_found("Export not found".to_string()),
+                _ => Error::server_error(format!("Failed to read export {e:?}")),
+            })?;
+        Ok(response.header(
+            poem::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"recording-{}-{}-{}.mp4\"", job.recording_id, job.start, job.end),
+        ))
+    }
+
+    /// Samples one frame every `interval` seconds from recordings or the live stream into an mp4
+    #[oai(path = "/timelapses", method = "post")]
+    async fn create_timelapse(&self, Json(request): Json<TimelapseRequest>, timelapses: web::Data<&Timelapses>) -> Result<Json<Timelapse>> {
+        let timelapse = timelapses.start(request).await
+            .map_err(Error::bad_request)?;
+        Ok(Json(timelapse))
+    }
+
+    #[oai(path = "/timelapses", method = "get")]
+    async fn list_timelapses(&self, storage: web::Data<&Arc<Storage>>) -> Json<Vec<Timelapse>> {
+        Json(storage.timelapses.list_timelapses().await)
+    }
+
+    #[oai(path = "/timelapses/config", method = "get")]
+    async fn get_timelapse_config(&self, storage: web::Data<&Arc<Storage>>) -> Json<TimelapseConfig> {
+        Json(storage.timelapse_config.get().await)
+    }
+
+    #[oai(path = "/timelapses/config", method = "post")]
+    async fn set_timelapse_config(&self, Json(config): Json<TimelapseConfig>, storage: web::Data<&Arc<Storage>>) -> Result<()> {
+        crate::timelapse::validate_config(&config)
+            .map_err(Error::bad_request)?;
+        storage.timelapse_config.set(&config).await;
+        crate::timelapse::enforce(&storage.config.app_data, &storage).await;
+        Ok(())
+    }
+
+    #[oai(path = "/timelapses/:id", method = "get")]
+    async fn get_timelapse(&self, Path(id): Path<i64>, storage: web::Data<&Arc<Storage>>) -> Result<Json<Timelapse>> {
+        let timelapse = storage.timelapses.get_timelapse(id).await
+            .ok_or_else(|| Error::not_found("Timelapse not found".to_string()))?;
+        Ok(Json(timelapse))
+    }
+
+    #[oai(path = "/timelapses/:id/download", method = "get")]
+    async fn download_timelapse(
+        &self,
+        Path(id): Path<i64>,
+        headers: &poem::http::HeaderMap,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Response<Binary<poem::Body>>> {
+        storage.timelapses.get_timelapse(id).await
+            .filter(|t| t.state == TimelapseState::Done)
+            .ok_or_else(|| Error::not_found("Timelapse not found or not finished".to_string()))?;
+
+        let path = crate::timelapse::timelapse_path(&storage.config.app_data, id);
+        let response = crate::file_response::serve_file(&path, headers, "video/mp4").await
+            .map_err(|e| match e.kind() {
+                std::io::ErrorKind::NotFound => Error::not_found("Timelapse not found".to_string()),
+                _ => Error::server_error(format!("Failed to read timelapse {e:?}")),
+            })?;
+        Ok(response.header(
+            poem::http::header::CONTENT_DISPOSITION,
+            format!("attachment; filename=\"timelapse-{id}.mp4\""),
+        ))
+    }
+
+    /// Streams recordings between `from` and `to` (unix ms) as one fragmented mp4,
+    /// stops at the first resolution change and points to the rest with `X-Playback-Next`
+    #[oai(path = "/playback", method = "get")]
+    async fn playback(
+        &self,
+        Query(from): Query<i64>,
+        Query(to): Query<i64>,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Response<Binary<poem::Body>>> {
+        if from >= to {
+            Err(Error::bad_request("from has to be before to".to_string()))?;
+        }
+        let mut playbacks = crate::playback::plan(&storage, &storage.config.app_data, from, to).await.into_iter();
+        let playback = playbacks.next()
+            .ok_or_else(|| Error::not_found("No recordings in this time range".to_string()))?;
+
+        let mut response = Response::new(Binary(playback.into_body()))
+            .header(poem::http::header::CONTENT_TYPE, "video/mp4");
+        if let Some(next) = playbacks.next() {
+            response = response.header("X-Playback-Next", next.segment.from.to_string());
+        }
+        Ok(response)
+    }
+
+    /// Segments of a time range that can each be played with `/playback`
+    #[oai(path = "/playback/segments", method = "get")]
+    async fn playback_segments(
+        &self,
+        Query(from): Query<i64>,
+        Query(to): Query<i64>,
+        storage: web::Data<&Arc<Storage>>,
+    ) -> Result<Json<Vec<PlaybackSegment>>> {
+        if from >= to {
+            Err(Error::bad_request("from has to be before to".to_string()))?;
+        }
+        let playbacks = crate::playback::plan(&storage, &storage.config.app_data, from, to).await;
+        Ok(Json(playbacks.into_iter().map(|p| p.segment).collect()))
+    }
 
     #[oai(path= "/recordings/config", method ="get")]
     async fn get_file_config(&self,  storage: web::Data<&Arc<Storage>>) -> Json<FileSinkConfig> {
@@ -133,8 +406,79 @@ impl Api {
         Json(config)
     }
     #[oai(path= "/recordings/config", method ="post")]
-    async fn set_file_config(&self, config: Json<FileSinkConfig>,  storage: web::Data<&Arc<Storage>>) {
+    async fn set_file_config(&self, config: Json<FileSinkConfig>,  storage: web::Data<&Arc<Storage>>) -> Result<()> {
+        if let Some(ref schedule) = config.schedule {
+            crate::recording_schedule::validate_schedule(schedule)
+                .map_err(Error::bad_request)?;
+        }
+        if let Some(ref template) = config.file_name_template {
+            crate::file_sink::validate_file_name_template(template)
+                .map_err(Error::bad_request)?;
+        }
         storage.file_config.set(&config).await;
+        Ok(())
+    }
+
+    /// Whether recording is currently on according to the schedule and when that changes
+    #[oai(path= "/recordings/schedule", method ="get")]
+    async fn get_recording_schedule_state(&self, storage: web::Data<&Arc<Storage>>) -> Json<RecordingScheduleState> {
+        let config = storage.file_config.get().await;
+        Json(crate::recording_schedule::schedule_state(&config.schedule, chrono::Utc::now()))
+    }
+
+    /// Recent recordings deleted by retention and the rule that deleted them
+    #[oai(path= "/recordings/retention", method ="get")]
+    async fn get_retention_log(&self, storage: web::Data<&Arc<Storage>>) -> Json<Vec<RetentionEvent>> {
+        Json(storage.retention_log.get().await)
+    }
+
+    /// Upload backend configuration, the S3 secret key is never returned
+    #[oai(path= "/uploads/config", method ="get")]
+    async fn get_upload_config(&self, storage: web::Data<&Arc<Storage>>) -> Json<UploadConfig> {
+        let mut config = storage.upload_config.get().await;
+        if let Some(ref mut s3) = config.s3 {
+            s3.secret_key = String::new();
+        }
+        if let Some(ref mut webdav) = config.webdav {
+            webdav.password = None;
+        }
+        if let Some(ref mut sftp) = config.sftp {
+            sftp.password = None;
+        }
+        Json(config)
+    }
+
+    #[oai(path= "/uploads/config", method ="post")]
+    async fn set_upload_config(&self, Json(mut config): Json<UploadConfig>, storage: web::Data<&Arc<Storage>>) -> Result<()> {
+        // An empty secret keeps the stored one, GET never hands it out
+        let stored = storage.upload_config.get().await;
+        if let Some(ref mut s3) = config.s3 {
+            if s3.secret_key.is_empty() {
+                s3.secret_key = stored.s3
+                    .map(|s| s.secret_key)
+                    .unwrap_or_default();
+            }
+        }
+        if let Some(ref mut webdav) = config.webdav {
+            if webdav.password.as_deref().unwrap_or_default().is_empty() {
+                webdav.password = stored.webdav.and_then(|w| w.password);
+            }
+        }
+        if let Some(ref mut sftp) = config.sftp {
+            if sftp.password.as_deref().unwrap_or_default().is_empty() {
+                sftp.password = stored.sftp.and_then(|s| s.password);
+            }
+        }
+        crate::backends::from_config(&config)
+            .map_err(Error::bad_request)?;
+        storage.upload_config.set(&config).await;
+        Ok(())
+    }
+
+    /// Recordings waiting for upload or retry
+    #[oai(path= "/uploads", method ="get")]
+    async fn get_uploads(&self, storage: web::Data<&Arc<Storage>>) -> Json<Vec<UploadJob>> {
+        Json(storage.uploads.list_uploads().await)
     }
 
     #[oai(path = "/users/init", method = "post")]
@@ -181,15 +525,128 @@ impl Api {
         storage.users.delete_user(&user).await;
     }
 
+    /// Live view clients with their lag and drop counters
+    #[oai(path = "/viewers", method = "get")]
+    async fn list_viewers(&self, viewers: web::Data<&Viewers>) -> Json<Vec<ViewerStats>> {
+        Json(viewers.list())
+    }
+
+    #[oai(path = "/devices", method = "get")]
+    async fn list_devices(&self, storage: web::Data<&Arc<Storage>>) -> Json<Vec<DeviceInfo>> {
+        let mut devices: Vec<DeviceInfo> = storage.devices.devices().await
+            .values()
+            .map(DeviceInfo::from)
+            .collect();
+        devices.sort_by(|a, b| a.path.cmp(&b.path));
+        Json(devices)
+    }
+
+    #[oai(path = "/devices/:id", method = "get")]
+    async fn get_device(&self, Path(id): Path<String>, storage: web::Data<&Arc<Storage>>) -> Result<Json<DeviceInfo>> {
+        let devices = storage.devices.devices().await;
+        let device = Device::find(devices, &id)
+            .ok_or_else(|| Error::not_found(format!("Device {id} not found")))?;
+        Ok(Json(DeviceInfo::from(device)))
+    }
+
+    #[oai(path = "/devices/:id/controls", method = "get")]
+    async fn get_device_controls(&self, Path(id): Path<String>, storage: web::Data<&Arc<Storage>>) -> Result<Json<Vec<CameraControl>>> {
+        let devices = storage.devices.devices().await;
+        let device = Device::find(devices, &id)
+            .ok_or_else(|| Error::not_found(format!("Device {id} not found")))?;
+
+        let controls = device.controls()
+            .map_err(|e| Error::server_error(format!("Failed to query device controls {e}")))?;
+        Ok(Json(controls))
+    }
+
+    #[oai(path = "/devices/:id/controls", method = "post")]
+    async fn set_device_controls(&self, Path(id): Path<String>, Json(values): Json<Vec<ControlValue>>, storage: web::Data<&Arc<Storage>>) -> Result<()> {
+        let devices = storage.devices.devices().await;
+        let device = Device::find(devices, &id)
+            .ok_or_else(|| Error::not_found(format!("Device {id} not found")))?;
+
+        let controls = device.controls()
+            .map_err(|e| Error::server_error(format!("Failed to query device controls {e}")))?;
+        for value in values.iter() {
+            let control = controls.iter()
+                .find(|c| c.id == value.id)
+                .ok_or_else(|| Error::bad_request(format!("Unknown control {}", value.id)))?;
+            if value.value < control.minimum || value.value > control.maximum {
+                Err(Error::bad_request(format!("Value {} out of range for {}", value.value, control.name)))?;
+            }
+            // Menus can have gaps, only the indices the driver lists are valid
+            if !control.menu.is_empty() && !control.menu.iter().any(|item| item.index as i64 == value.value) {
+                Err(Error::bad_request(format!("Value {} is not a menu item of {}", value.value, control.name)))?;
+            }
+            if control.step > 1 && (value.value - control.minimum) % control.step as i64 != 0 {
+                Err(Error::bad_request(format!("Value {} of {} is not a multiple of step {} from {}",
+                    value.value, control.name, control.step, control.minimum)))?;
+            }
+        }
+
+        // Stored values are re-applied on every pipeline start, keep the ones the driver rejects out
+        device.set_controls(&values)
+            .map_err(Error::server_error)?;
+        storage.controls.set_controls(&device.id, &values).await;
+        Ok(())
+    }
+
+    #[oai(path = "/profiles", method = "get")]
+    async fn get_profiles(&self, storage: web::Data<&Arc<Storage>>) -> Json<Vec<CameraProfile>> {
+        Json(storage.profiles.get().await)
+    }
+
+    #[oai(path = "/profiles", method = "post")]
+    async fn set_profiles(&self, Json(profiles): Json<Vec<CameraProfile>>, storage: web::Data<&Arc<Storage>>) -> Result<()> {
+        for profile in profiles.iter() {
+            crate::video::validate_pipeline_config(&profile.pipeline)
+                .map_err(|e| Error::bad_request(format!("Invalid profile {}: {e}", profile.name)))?;
+        }
+        storage.profiles.set(&profiles).await;
+        Ok(())
+    }
+
+    #[oai(path = "/profiles/schedule", method = "get")]
+    async fn get_profile_schedule(&self, storage: web::Data<&Arc<Storage>>) -> Json<ProfileSchedule> {
+        Json(storage.profile_schedule.get().await)
+    }
+
+    #[oai(path = "/profiles/schedule", method = "post")]
+    async fn set_profile_schedule(&self, Json(schedule): Json<ProfileSchedule>, storage: web::Data<&Arc<Storage>>) -> Result<()> {
+        crate::profile_scheduler::validate_schedule(&schedule, &storage.profiles.get().await)
+            .map_err(Error::bad_request)?;
+        storage.profile_schedule.set(&schedule).await;
+        Ok(())
+    }
+
     #[oai(path= "/pipeline/config", method ="get")]
     async fn get_config(&self,  storage: web::Data<&Arc<Storage>>) -> Json<PipelineConfig> {
         let config = storage.camera_config.get().await;
         Json(config)
     }
 
+    /// State of the running pipeline and the reason of the last failed config
+    #[oai(path = "/pipeline/status", method = "get")]
+    async fn get_pipeline_status(&self, storage: web::Data<&Arc<Storage>>) -> Json<PipelineStatus> {
+        Json(storage.pipeline_status.get().await)
+    }
+
+    /// Resolves a config against the current devices without applying it
+    #[oai(path = "/pipeline/preview", method = "post")]
+    async fn preview_config(&self, Json(config): Json<PipelineConfig>, storage: web::Data<&Arc<Storage>>) -> Json<PipelinePreview> {
+        let devices = storage.devices.devices().await;
+        let unsatisfied = crate::video::unsatisfied_constraints(devices, &config);
+        let resolved = crate::video::Config::find_optimal_settings(devices, config);
+        Json(resolved.preview(devices, unsatisfied))
+    }
+
     #[oai(path= "/pipeline/config", method ="post")]
-    async fn set_config(&self, config: Json<PipelineConfig>,  storage: web::Data<&Arc<Storage>>) {
+    async fn set_config(&self, config: Json<PipelineConfig>,  storage: web::Data<&Arc<Storage>>) -> Result<()> {
+        crate::video::validate_pipeline_config(&config)
+            .map_err(Error::bad_request)?;
         storage.camera_config.set(&config).await;
+        Ok(())
     }
 
 
@@ -0,0 +1,57 @@
+use std::{future::Future, path::Path};
+
+use crate::models::*;
+
+mod local;
+mod s3;
+mod sftp;
+mod throttle;
+mod webdav;
+
+/// Destination of finished recordings
+#[async_trait::async_trait]
+pub trait RecordingBackend {
+    fn name(&self) -> &'static str;
+    /// Stores a closed recording, `path` is its local file
+    async fn store(&self, path: &Path, recording: &Recording) -> Result<(), String>;
+    /// Whether the local copy may be removed once `store` succeeded
+    fn is_remote(&self) -> bool;
+}
+
+/// Uploads a recording and then its metadata as `<file_name>.json` next to it. The metadata goes last,
+/// its presence marks a complete recording on the target. Whatever `upload` returns is handed to
+/// `upload_metadata`, so both can share a connection.
+async fn store_with_metadata<T, M>(
+    recording: &Recording,
+    upload: impl Future<Output = Result<T, String>>,
+    upload_metadata: impl FnOnce(T, String, Vec<u8>) -> M,
+) -> Result<(), String>
+where
+    M: Future<Output = Result<(), String>>,
+{
+    let metadata = serde_json::to_vec_pretty(recording)
+        .map_err(|e| format!("Failed to serialize metadata {e:?}"))?;
+    let uploaded = upload.await?;
+    upload_metadata(uploaded, format!("{}.json", recording.file_name), metadata).await
+}
+
+pub fn from_config(config: &UploadConfig) -> Result<Box<dyn RecordingBackend + Send + Sync>, String> {
+    match config.backend {
+        UploadBackend::Local => Ok(Box::new(local::LocalBackend)),
+        UploadBackend::S3 => {
+            let s3_config = config.s3.as_ref()
+                .ok_or("S3 backend selected without S3 configuration")?;
+            Ok(Box::new(s3::S3Backend::new(s3_config, config.max_bytes_per_second)?))
+        },
+        UploadBackend::WebDav => {
+            let webdav_config = config.webdav.as_ref()
+                .ok_or("WebDAV backend selected without WebDAV configuration")?;
+            Ok(Box::new(webdav::WebDavBackend::new(webdav_config, config.max_bytes_per_second)?))
+        },
+        UploadBackend::Sftp => {
+            let sftp_config = config.sftp.as_ref()
+                .ok_or("SFTP backend selected without SFTP configuration")?;
+            Ok(Box::new(sftp::SftpBackend::new(sftp_config, config.max_bytes_per_second)?))
+        }
+    }
+}
@@ -0,0 +1,22 @@
+use std::path::Path;
+
+use super::RecordingBackend;
+use crate::models::Recording;
+
+/// Recordings stay in app_data where `file_saver` wrote them
+pub struct LocalBackend;
+
+#[async_trait::async_trait]
+impl RecordingBackend for LocalBackend {
+    fn name(&self) -> &'static str {
+        "local"
+    }
+
+    async fn store(&self, _path: &Path, _recording: &Recording) -> Result<(), String> {
+        Ok(())
+    }
+
+    fn is_remote(&self) -> bool {
+        false
+    }
+}
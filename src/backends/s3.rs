@@ -0,0 +1,69 @@
+use std::path::Path;
+
+use ::s3::{creds::Credentials, Bucket, Region};
+use futures_util::TryStreamExt;
+use tokio_util::io::StreamReader;
+
+use super::{throttle, RecordingBackend};
+use crate::models::{Recording, S3Config};
+
+/// Uploads recordings to an S3 compatible bucket, large files go through multipart upload
+pub struct S3Backend {
+    bucket: Box<Bucket>,
+    prefix: String,
+    max_bytes_per_second: Option<u64>,
+}
+
+impl S3Backend {
+    pub fn new(config: &S3Config, max_bytes_per_second: Option<u64>) -> Result<Self, String> {
+        let region = Region::Custom {
+            region: config.region.clone(),
+            endpoint: config.endpoint.clone(),
+        };
+        let credentials = Credentials::new(Some(&config.access_key), Some(&config.secret_key), None, None, None)
+            .map_err(|e| format!("Invalid S3 credentials {e:?}"))?;
+        let mut bucket = Bucket::new(&config.bucket, region, credentials)
+            .map_err(|e| format!("Invalid S3 bucket {e:?}"))?;
+        if config.path_style {
+            bucket = bucket.with_path_style();
+        }
+
+        Ok(Self {
+            bucket,
+            prefix: config.prefix.clone().unwrap_or_default(),
+            max_bytes_per_second,
+        })
+    }
+
+    fn key(&self, recording: &Recording) -> String {
+        let prefix = self.prefix.trim_matches('/');
+        if prefix.is_empty() {
+            recording.file_name.clone()
+        } else {
+            format!("{prefix}/{}", recording.file_name)
+        }
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordingBackend for S3Backend {
+    fn name(&self) -> &'static str {
+        "s3"
+    }
+
+    async fn store(&self, path: &Path, recording: &Recording) -> Result<(), String> {
+        let file = tokio::fs::File::open(path).await
+            .map_err(|e| format!("Failed to open {path:?} {e:?}"))?;
+        let mut reader = StreamReader::new(Box::pin(
+            throttle::file_stream(file, self.max_bytes_per_second).map_ok(bytes::Bytes::from)
+        ));
+        // Switches to multipart upload once the file is larger than a single chunk
+        self.bucket.put_object_stream_with_content_type(&mut reader, self.key(recording), "video/mp4").await
+            .map_err(|e| format!("Failed to upload {} {e:?}", recording.file_name))?;
+        Ok(())
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,178 @@
+use std::{
+    fs::File,
+    io::{Read, Write},
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use base64::{engine::general_purpose::STANDARD_NO_PAD, Engine};
+use ssh2::{HashType, OpenFlags, OpenType, RenameFlags, Session, Sftp};
+
+use super::{throttle::{Throttle, CHUNK_SIZE}, RecordingBackend};
+use crate::models::{Recording, SftpConfig};
+
+const DEFAULT_PORT: u16 = 22;
+const IO_TIMEOUT: Duration = Duration::from_secs(30);
+/// Uploads go to this suffix first so the target never shows half a recording
+const PARTIAL_SUFFIX: &str = ".part";
+
+/// Mirrors recordings and their metadata into a directory over SFTP
+pub struct SftpBackend {
+    config: SftpConfig,
+    max_bytes_per_second: Option<u64>,
+}
+
+impl SftpBackend {
+    pub fn new(config: &SftpConfig, max_bytes_per_second: Option<u64>) -> Result<Self, String> {
+        if config.host.is_empty() || config.username.is_empty() {
+            return Err("SFTP host and username are required".to_string());
+        }
+        if config.password.is_none() && config.private_key.is_none() {
+            return Err("SFTP needs a password or a private key".to_string());
+        }
+        if config.host_key_fingerprint.as_deref().unwrap_or_default().is_empty() {
+            return Err("SFTP needs the host key fingerprint of the server".to_string());
+        }
+
+        Ok(Self {
+            config: config.clone(),
+            max_bytes_per_second,
+        })
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordingBackend for SftpBackend {
+    fn name(&self) -> &'static str {
+        "sftp"
+    }
+
+    async fn store(&self, path: &Path, recording: &Recording) -> Result<(), String> {
+        let config = self.config.clone();
+        let directory = PathBuf::from(&self.config.directory);
+        let path = path.to_path_buf();
+        let file_name = recording.file_name.clone();
+        let max_bytes_per_second = self.max_bytes_per_second;
+
+        // libssh2 is blocking
+        let upload_recording = async move {
+            tokio::task::spawn_blocking(move || upload(&config, &path, &file_name, max_bytes_per_second))
+                .await
+                .map_err(|e| format!("SFTP upload task failed {e:?}"))?
+        };
+        super::store_with_metadata(recording, upload_recording, |sftp, metadata_name, metadata| async move {
+            let target = directory.join(metadata_name);
+            tokio::task::spawn_blocking(move || write_file(&sftp, &target, &mut &metadata[..], None))
+                .await
+                .map_err(|e| format!("SFTP upload task failed {e:?}"))?
+        }).await
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
+
+fn connect(config: &SftpConfig) -> Result<Sftp, String> {
+    let address = (config.host.as_str(), config.port.unwrap_or(DEFAULT_PORT));
+    let tcp = TcpStream::connect(address)
+        .map_err(|e| format!("Failed to connect to {}: {e:?}", config.host))?;
+    tcp.set_read_timeout(Some(IO_TIMEOUT))
+        .and_then(|_| tcp.set_write_timeout(Some(IO_TIMEOUT)))
+        .map_err(|e| format!("Failed to configure connection {e:?}"))?;
+
+    let mut session = Session::new()
+        .map_err(|e| format!("Failed to create SSH session {e:?}"))?;
+    session.set_tcp_stream(tcp);
+    session.handshake()
+        .map_err(|e| format!("SSH handshake with {} failed {e:?}", config.host))?;
+    verify_host_key(&session, config)?;
+
+    match (&config.private_key, &config.password) {
+        (Some(key), passphrase) => session.userauth_pubkey_file(&config.username, None, Path::new(key), passphrase.as_deref()),
+        (None, Some(password)) => session.userauth_password(&config.username, password),
+        (None, None) => return Err("SFTP needs a password or a private key".to_string()),
+    }
+        .map_err(|e| format!("SSH authentication as {} failed {e:?}", config.username))?;
+
+    session.sftp()
+        .map_err(|e| format!("Failed to start SFTP {e:?}"))
+}
+
+/// Refuses servers whose host key doesn't match the configured fingerprint, before any credential is sent
+fn verify_host_key(session: &Session, config: &SftpConfig) -> Result<(), String> {
+    let hash = session.host_key_hash(HashType::Sha256)
+        .ok_or_else(|| format!("{} sent no host key", config.host))?;
+    let presented = format!("SHA256:{}", STANDARD_NO_PAD.encode(hash));
+
+    // Accept the fingerprint with or without prefix and base64 padding
+    let expected = config.host_key_fingerprint.as_deref().unwrap_or_default().trim();
+    let expected = expected.strip_prefix("SHA256:").unwrap_or(expected).trim_end_matches('=');
+    if presented.strip_prefix("SHA256:") != Some(expected) {
+        return Err(format!("Host key of {} does not match, the server presented {presented}", config.host));
+    }
+    Ok(())
+}
+
+/// Creates every missing directory leading to `directory`
+fn create_directories(sftp: &Sftp, directory: &Path) -> Result<(), String> {
+    let mut current = PathBuf::new();
+    for component in directory.components() {
+        current.push(component);
+        if sftp.stat(&current).is_err() {
+            sftp.mkdir(&current, 0o755)
+                .map_err(|e| format!("Failed to create directory {current:?} {e:?}"))?;
+        }
+    }
+    Ok(())
+}
+
+fn with_suffix(path: &Path, suffix: &str) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(suffix);
+    PathBuf::from(path)
+}
+
+fn write_file(sftp: &Sftp, target: &Path, source: &mut impl Read, max_bytes_per_second: Option<u64>) -> Result<(), String> {
+    let partial = with_suffix(target, PARTIAL_SUFFIX);
+    let mut remote = sftp.open_mode(&partial, OpenFlags::WRITE | OpenFlags::CREATE | OpenFlags::TRUNCATE, 0o644, OpenType::File)
+        .map_err(|e| format!("Failed to create {partial:?} {e:?}"))?;
+
+    let mut throttle = Throttle::new(max_bytes_per_second);
+    let mut chunk = vec![0u8; CHUNK_SIZE];
+    loop {
+        let read = source.read(&mut chunk)
+            .map_err(|e| format!("Failed to read recording {e:?}"))?;
+        if read == 0 {
+            break;
+        }
+        remote.write_all(&chunk[..read])
+            .map_err(|e| format!("Failed to write {partial:?} {e:?}"))?;
+        std::thread::sleep(throttle.sent(read));
+    }
+    drop(remote);
+
+    // SFTP v3 servers like OpenSSH ignore the overwrite flag and refuse to rename onto an
+    // existing file, which a retry after a failed metadata upload would otherwise hit forever
+    if sftp.stat(target).is_ok() {
+        sftp.unlink(target)
+            .map_err(|e| format!("Failed to replace {target:?} {e:?}"))?;
+    }
+    sftp.rename(&partial, target, Some(RenameFlags::OVERWRITE | RenameFlags::ATOMIC | RenameFlags::NATIVE))
+        .map_err(|e| format!("Failed to rename {partial:?} {e:?}"))
+}
+
+/// Uploads the recording and returns the session for the metadata upload
+fn upload(config: &SftpConfig, path: &Path, file_name: &str, max_bytes_per_second: Option<u64>) -> Result<Sftp, String> {
+    let sftp = connect(config)?;
+    let target = Path::new(&config.directory).join(file_name);
+    if let Some(parent) = target.parent() {
+        create_directories(&sftp, parent)?;
+    }
+
+    let mut file = File::open(path)
+        .map_err(|e| format!("Failed to open {path:?} {e:?}"))?;
+    write_file(&sftp, &target, &mut file, max_bytes_per_second)?;
+    Ok(sftp)
+}
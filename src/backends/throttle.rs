@@ -0,0 +1,51 @@
+use std::time::{Duration, Instant};
+use futures_util::Stream;
+use tokio::{fs::File, io::AsyncReadExt};
+
+/// Size of the chunks read from recordings while uploading
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Keeps the average upload rate under a limit since the transfer started
+pub struct Throttle {
+    bytes_per_second: Option<u64>,
+    started: Instant,
+    sent: u64,
+}
+
+impl Throttle {
+    pub fn new(bytes_per_second: Option<u64>) -> Self {
+        Self {
+            bytes_per_second: bytes_per_second.filter(|b| *b > 0),
+            started: Instant::now(),
+            sent: 0,
+        }
+    }
+
+    /// Records `bytes` as sent, returns how long to wait before sending more
+    pub fn sent(&mut self, bytes: usize) -> Duration {
+        self.sent += bytes as u64;
+        let Some(bytes_per_second) = self.bytes_per_second else {
+            return Duration::ZERO;
+        };
+        Duration::from_secs_f64(self.sent as f64 / bytes_per_second as f64)
+            .saturating_sub(self.started.elapsed())
+    }
+}
+
+/// Reads a file as a stream of chunks no faster than `bytes_per_second`
+pub fn file_stream(file: File, bytes_per_second: Option<u64>) -> impl Stream<Item = std::io::Result<Vec<u8>>> {
+    futures_util::stream::unfold(Some((file, Throttle::new(bytes_per_second))), |state| async move {
+        let (mut file, mut throttle) = state?;
+        let mut chunk = vec![0u8; CHUNK_SIZE];
+        match file.read(&mut chunk).await {
+            Ok(0) => None,
+            Ok(read) => {
+                chunk.truncate(read);
+                tokio::time::sleep(throttle.sent(read)).await;
+                Some((Ok(chunk), Some((file, throttle))))
+            },
+            // The stream ends after the error
+            Err(e) => Some((Err(e), None)),
+        }
+    })
+}
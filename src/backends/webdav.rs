@@ -0,0 +1,111 @@
+use std::path::Path;
+
+use reqwest::{header, Client, Method, RequestBuilder, StatusCode, Url};
+
+use super::{throttle, RecordingBackend};
+use crate::models::{Recording, WebDavConfig};
+
+/// Mirrors recordings and their metadata into a WebDAV collection
+pub struct WebDavBackend {
+    client: Client,
+    base: Url,
+    username: Option<String>,
+    password: Option<String>,
+    max_bytes_per_second: Option<u64>,
+}
+
+impl WebDavBackend {
+    pub fn new(config: &WebDavConfig, max_bytes_per_second: Option<u64>) -> Result<Self, String> {
+        let base = Url::parse(&config.url)
+            .map_err(|e| format!("Invalid WebDAV url {} {e:?}", config.url))?;
+        if base.cannot_be_a_base() {
+            return Err(format!("Invalid WebDAV url {}", config.url));
+        }
+
+        Ok(Self {
+            client: Client::new(),
+            base,
+            username: config.username.clone().filter(|u| !u.is_empty()),
+            password: config.password.clone(),
+            max_bytes_per_second,
+        })
+    }
+
+    /// Url of a resource below the base collection, segments get percent encoded
+    fn url(&self, segments: &[&str]) -> Url {
+        let mut url = self.base.clone();
+        if let Ok(mut path) = url.path_segments_mut() {
+            path.pop_if_empty().extend(segments);
+        }
+        url
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match self.username {
+            Some(ref username) => request.basic_auth(username, self.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// Creates the collections leading to a recording, the base collection has to exist
+    async fn create_collections(&self, directories: &[&str]) -> Result<(), String> {
+        let mkcol = Method::from_bytes(b"MKCOL").expect("MKCOL is a valid method");
+        for depth in 1..=directories.len() {
+            let url = self.url(&directories[..depth]);
+            let response = self.request(mkcol.clone(), url.clone()).send().await
+                .map_err(|e| format!("Failed to create collection {url} {e:?}"))?;
+            // 405 is returned for collections that already exist
+            if !response.status().is_success() && response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                return Err(format!("Failed to create collection {url}, status {}", response.status()));
+            }
+        }
+        Ok(())
+    }
+
+    async fn put(&self, url: Url, body: reqwest::Body, length: u64, content_type: &str) -> Result<(), String> {
+        let response = self.request(Method::PUT, url.clone())
+            .header(header::CONTENT_TYPE, content_type)
+            .header(header::CONTENT_LENGTH, length)
+            .body(body)
+            .send().await
+            .map_err(|e| format!("Failed to upload {url} {e:?}"))?;
+        if !response.status().is_success() {
+            return Err(format!("Failed to upload {url}, status {}", response.status()));
+        }
+        Ok(())
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordingBackend for WebDavBackend {
+    fn name(&self) -> &'static str {
+        "webdav"
+    }
+
+    async fn store(&self, path: &Path, recording: &Recording) -> Result<(), String> {
+        let segments: Vec<&str> = recording.file_name.split('/').collect();
+        let (_, directories) = segments.split_last()
+            .ok_or_else(|| format!("Recording {} has no name", recording.id))?;
+        self.create_collections(directories).await?;
+
+        let upload = async {
+            let file = tokio::fs::File::open(path).await
+                .map_err(|e| format!("Failed to open {path:?} {e:?}"))?;
+            let length = file.metadata().await
+                .map_err(|e| format!("Failed to read {path:?} {e:?}"))?
+                .len();
+            let body = reqwest::Body::wrap_stream(throttle::file_stream(file, self.max_bytes_per_second));
+            self.put(self.url(&segments), body, length, "video/mp4").await
+        };
+        super::store_with_metadata(recording, upload, |_, metadata_name, metadata| async move {
+            let metadata_segments: Vec<&str> = metadata_name.split('/').collect();
+            let length = metadata.len() as u64;
+            self.put(self.url(&metadata_segments), metadata.into(), length, "application/json").await
+        }).await
+    }
+
+    fn is_remote(&self) -> bool {
+        true
+    }
+}
@@ -0,0 +1,249 @@
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{atomic::{AtomicU64, Ordering}, Arc, Mutex},
+};
+use gstreamer::{prelude::*, ClockTime, MessageType, MessageView, SeekFlags, SeekType, State};
+use log::*;
+use tokio::sync::Semaphore;
+use crate::{models::*, storage::Storage};
+
+/// Finished and running exports
+pub const EXPORT_DIR: &str = "exports";
+/// Finished exports are removed after a day
+const EXPORT_TTL: i64 = 24 * 60 * 60 * 1000;
+const PROGRESS_INTERVAL: ClockTime = ClockTime::from_mseconds(500);
+const PREROLL_TIMEOUT: ClockTime = ClockTime::from_seconds(10);
+/// A re-encode whose position doesn't move for this long is failed, it would hold the export slot forever
+const STALL_TIMEOUT: std::time::Duration = std::time::Duration::from_secs(60);
+
+pub fn export_path(app_data: &str, id: u64) -> PathBuf {
+    crate::file_sink::private_dir(app_data, EXPORT_DIR).join(format!("{id}.mp4"))
+}
+
+/// Exports don't survive a restart, removes the files left behind
+pub async fn clear(app_data: &str) {
+    let dir = crate::file_sink::private_dir(app_data, EXPORT_DIR);
+    if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            warn!("Failed to remove old exports {dir:?} {e:?}");
+        }
+    }
+}
+
+/// Export jobs since startup, they run one at a time so encoding doesn't starve the pipeline
+#[derive(Clone)]
+pub struct Exports {
+    next_id: Arc<AtomicU64>,
+    jobs: Arc<Mutex<HashMap<u64, ExportJob>>>,
+    running: Arc<Semaphore>
+}
+
+impl Default for Exports {
+    fn default() -> Self {
+        Self {
+            next_id: Arc::default(),
+            jobs: Arc::default(),
+            running: Arc::new(Semaphore::new(1))
+        }
+    }
+}
+
+impl Exports {
+    pub fn get(&self, id: u64) -> Option<ExportJob> {
+        self.jobs.lock().ok()?.get(&id).cloned()
+    }
+
+    pub fn list(&self) -> Vec<ExportJob> {
+        let Ok(jobs) = self.jobs.lock() else {
+            return Vec::new();
+        };
+        let mut jobs: Vec<ExportJob> = jobs.values().cloned().collect();
+        jobs.sort_by_key(|j| j.id);
+        jobs
+    }
+
+    fn update(&self, id: u64, update: impl FnOnce(&mut ExportJob)) {
+        if let Ok(mut jobs) = self.jobs.lock() {
+            if let Some(job) = jobs.get_mut(&id) {
+                update(job);
+            }
+        }
+    }
+
+    /// Queues an export of `start..end` (ms from the start of the recording)
+    pub async fn start(&self, storage: Arc<Storage>, recording: Recording, start: i64, end: i64, precise: bool) -> ExportJob {
+        self.expire(&storage.config.app_data).await;
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        let job = ExportJob {
+            id,
+            recording_id: recording.id,
+            start,
+            end,
+            precise,
+            created_at: chrono::Utc::now().timestamp_millis(),
+            ..Default::default()
+        };
+        if let Ok(mut jobs) = self.jobs.lock() {
+            jobs.insert(id, job.clone());
+        }
+
+        let exports = self.clone();
+        tokio::spawn(async move {
+            exports.run(&storage, recording, id).await;
+        });
+        job
+    }
+
+    /// Removes finished exports older than `EXPORT_TTL` together with their files
+    async fn expire(&self, app_data: &str) {
+        let now = chrono::Utc::now().timestamp_millis();
+        let expired: Vec<u64> = match self.jobs.lock() {
+            Ok(mut jobs) => {
+                let expired = jobs.values()
+                    .filter(|j| matches!(j.state, ExportState::Done | ExportState::Failed))
+                    .filter(|j| j.created_at + EXPORT_TTL < now)
+                    .map(|j| j.id)
+                    .collect();
+                jobs.retain(|id, _| !expired.contains(id));
+                expired
+            },
+            Err(_) => return,
+        };
+        for id in expired {
+            let _ = tokio::fs::remove_file(export_path(app_data, id)).await;
+        }
+    }
+
+    async fn run(&self, storage: &Storage, recording: Recording, id: u64) {
+        let Ok(_permit) = self.running.acquire().await else {
+            return;
+        };
+        let Some(job) = self.get(id) else {
+            return;
+        };
+        self.update(id, |j| j.state = ExportState::Running);
+
+        let app_data = &storage.config.app_data;
+        let result = match tokio::fs::create_dir_all(crate::file_sink::private_dir(app_data, EXPORT_DIR)).await {
+            Err(e) => Err(format!("Failed to create export directory {e:?}")),
+            Ok(()) if job.precise => self.reencode(app_data, &recording, &job).await,
+            Ok(()) => self.cut(app_data, &recording, &job).await,
+        };
+
+        match result {
+            Ok(()) => {
+                info!("Exported {}ms to {}ms of {}", job.start, job.end, recording.file_name);
+                self.update(id, |j| {
+                    j.state = ExportState::Done;
+                    j.progress = 1.0;
+                    j.download = Some(format!("/api/exports/{id}/download"));
+                });
+            },
+            Err(e) => {
+                warn!("Export of {} failed {e}", recording.file_name);
+                let _ = tokio::fs::remove_file(export_path(app_data, id)).await;
+                self.update(id, |j| {
+                    j.state = ExportState::Failed;
+                    j.error = Some(e);
+                });
+            }
+        }
+    }
+
+    /// Copies the fragments between the enclosing keyframes, nothing is re-encoded
+    async fn cut(&self, app_data: &str, recording: &Recording, job: &ExportJob) -> Result<(), String> {
+        let playback = crate::playback::plan_recording(
+            app_data,
+            recording,
+            recording.start_time + job.start,
+            recording.start_time + job.end,
+        ).await
+            .ok_or_else(|| "No keyframe in the requested range".to_string())?;
+        let (start, end) = (playback.segment.from - recording.start_time, playback.segment.to - recording.start_time);
+        self.update(job.id, |j| {
+            j.start = start;
+            j.end = end;
+        });
+
+        let path = export_path(app_data, job.id);
+        let mut file = tokio::fs::File::create(&path).await
+            .map_err(|e| format!("Failed to create {path:?} {e:?}"))?;
+        let total = playback.size().max(1);
+        playback.write_to(&mut file, |written| self.update(job.id, |j| j.progress = written as f64 / total as f64)).await
+            .map_err(|e| format!("Failed to write {path:?} {e:?}"))?;
+        file.sync_all().await
+            .map_err(|e| format!("Failed to write {path:?} {e:?}"))
+    }
+
+    async fn reencode(&self, app_data: &str, recording: &Recording, job: &ExportJob) -> Result<(), String> {
+        let source = crate::file_sink::recording_path(app_data, recording);
+        let target = export_path(app_data, job.id);
+        let start = ClockTime::from_mseconds(job.start.max(0) as u64);
+        let end = ClockTime::from_mseconds(job.end.max(0) as u64);
+
+        let exports = self.clone();
+        let id = job.id;
+        tokio::task::spawn_blocking(move || {
+            encode_clip(&source, &target, start, end, |progress| exports.update(id, |j| j.progress = progress))
+        })
+            .await
+            .map_err(|e| format!("Export task failed {e:?}"))?
+    }
+}
+
+/// Decodes `start..end` of a recording and encodes it again, so the cut is frame accurate
+fn encode_clip(source: &Path, target: &Path, start: ClockTime, end: ClockTime, progress: impl Fn(f64)) -> Result<(), String> {
+    let pipeline = gstreamer::parse::launch(
+        "filesrc name=src ! decodebin ! videoconvert ! \
+        x264enc speed-preset=veryfast key-int-max=60 ! h264parse ! mp4mux faststart=true ! filesink name=sink"
+    )
+        .map_err(|e| format!("Failed to create export pipeline {e:?}"))?
+        .downcast::<gstreamer::Pipeline>()
+        .map_err(|_| "Export pipeline is not a pipeline".to_string())?;
+
+    let src = pipeline.by_name("src").ok_or("Export pipeline has no source")?;
+    src.set_property("location", source.to_string_lossy().as_ref());
+    let sink = pipeline.by_name("sink").ok_or("Export pipeline has no sink")?;
+    sink.set_property("location", target.to_string_lossy().as_ref());
+    let bus = pipeline.bus().ok_or("Export pipeline has no bus")?;
+
+    let result = (|| {
+        pipeline.set_state(State::Paused)
+            .map_err(|e| format!("Failed to open {source:?} {e:?}"))?;
+        let _ = pipeline.state(PREROLL_TIMEOUT);
+        // The pipeline sends EOS once it reaches the stop position
+        pipeline.seek(1.0, SeekFlags::FLUSH | SeekFlags::ACCURATE, SeekType::Set, start, SeekType::Set, end)
+            .map_err(|e| format!("Failed to seek {source:?} to {start} {e:?}"))?;
+        pipeline.set_state(State::Playing)
+            .map_err(|e| format!("Failed to start export of {source:?} {e:?}"))?;
+
+        let length = end.saturating_sub(start).nseconds().max(1);
+        let mut last_position = None;
+        let mut last_progress = std::time::Instant::now();
+        loop {
+            if let Some(message) = bus.timed_pop_filtered(PROGRESS_INTERVAL, &[MessageType::Eos, MessageType::Error]) {
+                match message.view() {
+                    MessageView::Eos(_) => return Ok(()),
+                    MessageView::Error(e) => return Err(format!("Export of {source:?} failed {} {:?}", e.error(), e.debug())),
+                    _ => {}
+                }
+            }
+            let position = pipeline.query_position::<ClockTime>();
+            if let Some(position) = position {
+                let done = position.saturating_sub(start).nseconds() as f64 / length as f64;
+                progress(done.clamp(0.0, 1.0));
+            }
+            if position != last_position {
+                last_position = position;
+                last_progress = std::time::Instant::now();
+            } else if last_progress.elapsed() > STALL_TIMEOUT {
+                return Err(format!("Export of {source:?} stalled at {position:?}"));
+            }
+        }
+    })();
+
+    let _ = pipeline.set_state(State::Null);
+    result
+}
@@ -0,0 +1,152 @@
+use std::{ops::Range, path::Path, time::SystemTime};
+use chrono::{DateTime, Utc};
+use poem::{http::{header, HeaderMap, StatusCode}, Body};
+use poem_openapi::payload::{Binary, Response};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt, SeekFrom}};
+
+const HTTP_DATE: &str = "%a, %d %b %Y %H:%M:%S GMT";
+
+/// Streams a file from disk honouring `Range`, `If-Range`, `If-None-Match` and `If-Modified-Since`
+pub async fn serve_file(path: &Path, request: &HeaderMap, content_type: &str) -> std::io::Result<Response<Binary<Body>>> {
+    let mut file = File::open(path).await?;
+    let metadata = file.metadata().await?;
+    let len = metadata.len();
+    let modified = metadata.modified().unwrap_or(SystemTime::UNIX_EPOCH);
+    let modified = DateTime::<Utc>::from(modified);
+    let etag = format!("\"{:x}-{:x}\"", len, modified.timestamp_millis());
+    let last_modified = modified.format(HTTP_DATE).to_string();
+
+    let response = |body: Body| Response::new(Binary(body))
+        .header(header::ETAG, &etag)
+        .header(header::LAST_MODIFIED, &last_modified)
+        .header(header::ACCEPT_RANGES, "bytes");
+
+    if not_modified(request, &etag, &modified) {
+        return Ok(response(Body::empty()).status(StatusCode::NOT_MODIFIED));
+    }
+
+    // A stale If-Range means the client's partial copy is outdated, send everything
+    let if_range = header_str(request, header::IF_RANGE)
+        .map(|v| v == etag || v == last_modified)
+        .unwrap_or(true);
+    let range = header_str(request, header::RANGE)
+        .filter(|_| if_range)
+        .map(|v| parse_range(v, len));
+
+    match range {
+        Some(Some(range)) => {
+            let length = range.end - range.start;
+            file.seek(SeekFrom::Start(range.start)).await?;
+            Ok(response(Body::from_async_read(file.take(length)))
+                .status(StatusCode::PARTIAL_CONTENT)
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, length)
+                .header(header::CONTENT_RANGE, format!("bytes {}-{}/{len}", range.start, range.end - 1)))
+        },
+        Some(None) => {
+            Ok(response(Body::empty())
+                .status(StatusCode::RANGE_NOT_SATISFIABLE)
+                .header(header::CONTENT_RANGE, format!("bytes */{len}")))
+        },
+        None => {
+            Ok(response(Body::from_async_read(file))
+                .header(header::CONTENT_TYPE, content_type)
+                .header(header::CONTENT_LENGTH, len))
+        }
+    }
+}
+
+fn header_str(request: &HeaderMap, name: header::HeaderName) -> Option<&str> {
+    request.get(name).and_then(|v| v.to_str().ok()).map(str::trim)
+}
+
+fn not_modified(request: &HeaderMap, etag: &str, modified: &DateTime<Utc>) -> bool {
+    if let Some(if_none_match) = header_str(request, header::IF_NONE_MATCH) {
+        return if_none_match
+            .split(',')
+            .map(|t| t.trim().trim_start_matches("W/"))
+            .any(|t| t == "*" || t == etag);
+    }
+    header_str(request, header::IF_MODIFIED_SINCE)
+        .and_then(|v| DateTime::parse_from_rfc2822(v).ok())
+        .map(|since| modified.timestamp() <= since.timestamp())
+        .unwrap_or(false)
+}
+
+/// Parses a single `bytes=` range, `Some(None)` means it can't be satisfied.
+/// Multiple ranges are not supported and answered with the whole file.
+fn parse_range(value: &str, len: u64) -> Option<Option<Range<u64>>> {
+    let spec = value.strip_prefix("bytes=")?.trim();
+    if spec.contains(',') {
+        return None;
+    }
+    let (start, end) = spec.split_once('-')?;
+    let (start, end) = (start.trim(), end.trim());
+
+    let range = if start.is_empty() {
+        // Suffix range, the last `end` bytes
+        let suffix = end.parse::<u64>().ok()?;
+        if suffix == 0 {
+            return Some(None);
+        }
+        len.saturating_sub(suffix)..len
+    } else {
+        let start = start.parse::<u64>().ok()?;
+        let end = if end.is_empty() {
+            len
+        } else {
+            end.parse::<u64>().ok()?.saturating_add(1).min(len)
+        };
+        start..end
+    };
+
+    if range.start >= len || range.start >= range.end {
+        return Some(None);
+    }
+    Some(Some(range))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn parses_closed_and_open_ranges() {
+        assert_eq!(parse_range("bytes=0-99", 1000), Some(Some(0..100)));
+        assert_eq!(parse_range("bytes=500-", 1000), Some(Some(500..1000)));
+        assert_eq!(parse_range("bytes= 10 - 19 ", 1000), Some(Some(10..20)));
+    }
+
+    #[test]
+    fn clamps_end_to_length() {
+        assert_eq!(parse_range("bytes=900-2000", 1000), Some(Some(900..1000)));
+        assert_eq!(parse_range(&format!("bytes=0-{}", u64::MAX), 1000), Some(Some(0..1000)));
+    }
+
+    #[test]
+    fn parses_suffix_ranges() {
+        assert_eq!(parse_range("bytes=-100", 1000), Some(Some(900..1000)));
+        assert_eq!(parse_range("bytes=-5000", 1000), Some(Some(0..1000)));
+        assert_eq!(parse_range("bytes=-0", 1000), Some(None));
+    }
+
+    #[test]
+    fn rejects_unsatisfiable_ranges() {
+        assert_eq!(parse_range("bytes=1000-", 1000), Some(None));
+        assert_eq!(parse_range("bytes=1000-1100", 1000), Some(None));
+        assert_eq!(parse_range("bytes=20-10", 1000), Some(None));
+        assert_eq!(parse_range("bytes=0-", 0), Some(None));
+        assert_eq!(parse_range("bytes=-10", 0), Some(None));
+    }
+
+    #[test]
+    fn ignores_malformed_and_multiple_ranges() {
+        assert_eq!(parse_range("0-99", 1000), None);
+        assert_eq!(parse_range("items=0-99", 1000), None);
+        assert_eq!(parse_range("bytes=a-99", 1000), None);
+        assert_eq!(parse_range("bytes=0-b", 1000), None);
+        assert_eq!(parse_range("bytes=99", 1000), None);
+        assert_eq!(parse_range("bytes=-", 1000), None);
+        assert_eq!(parse_range("bytes=0-9,20-29", 1000), None);
+    }
+}
@@ -1,44 +1,154 @@
-use std::{path::PathBuf, str::FromStr, sync::Arc, time::SystemTime};
+use std::{collections::HashMap, path::{Path, PathBuf}, sync::Arc, time::{Duration, Instant}};
 use tokio::{
     fs::File,
     io::AsyncWriteExt,
-    sync::{broadcast::Receiver, RwLock},
+    sync::{broadcast::Receiver, mpsc, watch, RwLock},
 };
 use log::*;
-use crate::{storage::Storage, MessageType, ParsedBuffer, models::*};
+use crate::{mp4, recording_schedule, remux, retention, shutdown, storage::Storage, thumbnails, uploader, video::InitSegment, MessageType, ParsedBuffer, models::{file_sink_config::{CAMERA_PLACEHOLDER, DEFAULT_FILE_NAME_TEMPLATE, DEFAULT_FSYNC_INTERVAL}, *}};
+
+/// Suffix of recordings that are still being written, renamed away on close
+pub const PARTIAL_SUFFIX: &str = ".part";
+/// strftime format of the directories recordings are sorted into when `date_directories` is set
+const DATE_DIRECTORIES: &str = "%Y/%m/%d";
+/// Names tried before giving up on a collision
+const MAX_NAME_ATTEMPTS: u32 = 100;
+
+/// Path of a recording on disk, open recordings still carry the partial suffix
+pub fn recording_path(app_data: &str, recording: &Recording) -> PathBuf {
+    let path = PathBuf::from(app_data).join(&recording.file_name);
+    if recording.closed {
+        path
+    } else {
+        partial_path(&path)
+    }
+}
+
+fn partial_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(PARTIAL_SUFFIX);
+    path.into()
+}
+
+/// Recording being written together with its index entry
+struct OpenRecording {
+    file: File,
+    path: PathBuf,
+    recording: Recording,
+    last_sync: Instant,
+}
+
+impl OpenRecording {
+    async fn write(&mut self, data: &[u8]) -> Result<(), std::io::Error> {
+        self.file.write_all(data).await?;
+        self.recording.size += data.len() as u64;
+        Ok(())
+    }
+
+    /// Syncs to disk once `fsync_interval` has passed since the last sync
+    async fn sync_if_due(&mut self, config: &FileSinkConfig) {
+        let interval = config.fsync_interval.unwrap_or(DEFAULT_FSYNC_INTERVAL);
+        if interval == 0 || self.last_sync.elapsed() < Duration::from_secs(interval) {
+            return;
+        }
+        self.last_sync = Instant::now();
+        if let Err(e) = self.file.sync_data().await {
+            error!("Failed to sync recording {e:?}");
+        }
+    }
+
+    /// Updates the index with the current size and end of the recording
+    async fn update_index(&mut self, moov: &Arc<RwLock<InitSegment>>, storage: &Storage) {
+        let init = moov.read().await;
+        if let (Some(width), Some(height)) = (init.width, init.height) {
+            self.recording.width = Some(width as u32);
+            self.recording.height = Some(height as u32);
+        }
+        drop(init);
+        let end_time = chrono::Utc::now().timestamp_millis();
+        self.recording.end_time = Some(end_time);
+        // Only queries compute the duration, closed recordings are handed on before they are read back
+        self.recording.duration = Some(end_time - self.recording.start_time);
+        storage.recordings.update_recording(&self.recording).await;
+    }
+
+    async fn close(mut self, moov: &Arc<RwLock<InitSegment>>, storage: &Storage) -> Recording {
+        if let Err(e) = self.file.flush().await {
+            error!("Failed to flush recording {e:?}");
+        }
+        if let Err(e) = self.file.sync_all().await {
+            error!("Failed to sync recording {e:?}");
+        }
+        if let Err(e) = tokio::fs::rename(partial_path(&self.path), &self.path).await {
+            error!("Failed to rename recording {:?} {e:?}", self.path);
+        }
+        self.recording.closed = true;
+        self.update_index(moov, storage).await;
+        self.recording
+    }
+}
 
 pub async fn file_saver(
     mut recv: Receiver<Arc<ParsedBuffer>>,
-    moov: Arc<RwLock<Vec<Vec<u8>>>>,
+    moov: Arc<RwLock<InitSegment>>,
     app_data: &str,
     storage: Arc<Storage>,
+    remux: mpsc::UnboundedSender<Recording>,
+    mut shutdown: watch::Receiver<bool>,
 ) {
     let mut config = storage.file_config.get().await;
-    let mut file = generate_new_file(&app_data).await;
+    let mut current: Option<OpenRecording> = None;
     let mut config_reciver = storage.file_config.subscribe().await;
     let mut timestamp_when_file_is_created = 0;
+    let mut schedule = recording_schedule::schedule_state(&config.schedule, chrono::Utc::now());
 
     loop {
         tokio::select! {
             recv = recv.recv() => {
                 match recv {
+                    Ok(buffer) if buffer.message_type == MessageType::EndOfStream => {
+                        if let Some(recording) = current.as_mut() {
+                            if let Err(e) = recording.file.flush().await {
+                                error!("Failed to flush recording {e:?}");
+                            }
+                            recording.update_index(&moov, &storage).await;
+                        }
+                    },
+                    // Outside the schedule fragments are dropped, live view keeps its own subscription
+                    Ok(_) if !schedule.recording => {},
                     Ok(buffer) => {
-                        if buffer.message_type == MessageType::FirstFrame ||
+                        let starts_file = buffer.message_type == MessageType::FirstFrame ||
+                            (buffer.message_type == MessageType::KeyFrame && current.is_none());
+                        if starts_file ||
                     (
                         buffer.message_type == MessageType::KeyFrame &&
                         should_create_new_file(&buffer,&mut timestamp_when_file_is_created, &config)
                     ) {
-                        while should_file_be_rotated(&config, app_data).await {
-                            remove_oldest_file(&app_data).await;
+                        if let Some(recording) = current.take() {
+                            let recording = recording.close(&moov, &storage).await;
+                            post_process(app_data, &storage, recording, &config, &remux).await;
                         }
+                        retention::enforce(app_data, &storage, &config).await;
 
-                        file = generate_new_file(&app_data).await;
-                        if let Err(_) = save_moov_header(&moov, &mut file).await {
-                            // TODO log and handle error
+                        current = open_recording(app_data, &storage, &config).await;
+                        // A new pipeline sends its own init segment, don't copy the previous one
+                        if buffer.message_type != MessageType::FirstFrame {
+                            if let Some(recording) = current.as_mut() {
+                                if let Err(e) = save_moov_header(&moov, recording).await {
+                                    error!("Failed to write init segment to recording {e:?}");
+                                }
+                            }
+                        }
+                    } else if buffer.message_type == MessageType::KeyFrame {
+                        if let Some(recording) = current.as_mut() {
+                            recording.update_index(&moov, &storage).await;
                         }
                     }
-                    if let Err(_ ) = file.write(&buffer.data).await {
-                        // TODO log error and create new file
+                    if let Some(recording) = current.as_mut() {
+                        if let Err(e) = recording.write(&buffer.data).await {
+                            error!("Failed to write to recording {} {e:?}", recording.recording.file_name);
+                        }
+                        recording.sync_if_due(&config).await;
                     }
                 },
                 Err(e) => {
@@ -48,62 +158,325 @@ pub async fn file_saver(
         },
         _ = config_reciver.recv() => {
             config = storage.file_config.get().await;
+            schedule = recording_schedule::schedule_state(&config.schedule, chrono::Utc::now());
+            pause_outside_schedule(&schedule, &mut current, app_data, &moov, &storage, &config, &remux).await;
+        }
+        _ = tokio::time::sleep(recording_schedule::time_until_transition(&schedule)) => {
+            schedule = recording_schedule::schedule_state(&config.schedule, chrono::Utc::now());
+            pause_outside_schedule(&schedule, &mut current, app_data, &moov, &storage, &config, &remux).await;
         }
+        _ = shutdown::requested(&mut shutdown) => {
+            break;
+        }
+        }
+    }
+
+    if let Some(recording) = current {
+        finalise_file(&mut recv, recording, &moov, &storage).await;
+    }
+}
+
+/// Writes fragments flushed by EOS and syncs the current file to disk
+async fn finalise_file(
+    recv: &mut Receiver<Arc<ParsedBuffer>>,
+    mut recording: OpenRecording,
+    moov: &Arc<RwLock<InitSegment>>,
+    storage: &Storage,
+) {
+    info!("Finalising recording before shutdown");
+    let drain = async {
+        while let Ok(buffer) = recv.recv().await {
+            match buffer.message_type {
+                MessageType::EndOfStream => break,
+                MessageType::KeyFrame | MessageType::Fragment => {
+                    if let Err(e) = recording.write(&buffer.data).await {
+                        error!("Failed to write last fragment {e:?}");
+                        break;
+                    }
+                },
+                _ => {}
+            }
         }
+    };
+    if tokio::time::timeout(shutdown::EOS_TIMEOUT, drain).await.is_err() {
+        warn!("Pipeline did not reach EOS, closing recording without the last fragment");
     }
+
+    // The thumbnail is generated lazily on first request and the remux skipped, there's no time left during shutdown
+    let recording = recording.close(moov, storage).await;
+    uploader::enqueue(storage, &recording).await;
 }
 
-async fn remove_oldest_file(app_data: &str) {
-    let mut files = tokio::fs::read_dir(app_data).await.unwrap();
+/// Closes the current recording when the schedule stops recording, the next keyframe reopens one
+async fn pause_outside_schedule(
+    schedule: &RecordingScheduleState,
+    current: &mut Option<OpenRecording>,
+    app_data: &str,
+    moov: &Arc<RwLock<InitSegment>>,
+    storage: &Storage,
+    config: &FileSinkConfig,
+    remux: &mpsc::UnboundedSender<Recording>,
+) {
+    if schedule.recording {
+        return;
+    }
+    if let Some(recording) = current.take() {
+        info!("Outside recording schedule, closing {}", recording.recording.file_name);
+        let recording = recording.close(moov, storage).await;
+        post_process(app_data, storage, recording, config, remux).await;
+    }
+}
 
-    let mut max_time = SystemTime::now();
-    let mut path = PathBuf::new();
-    while let Ok(Some(file)) = files.next_entry().await {
-        if let Ok(metadata) = file.metadata().await {
-            if metadata.is_file() && file.path().extension().map(|e| e == "mp4").unwrap_or(false) {
-                let time_created = metadata.created().unwrap();
+/// Queues the upload, remux and thumbnails of a closed recording.
+/// Both queues are persisted before the remux starts, the uploader waits for `remux_pending` to clear.
+async fn post_process(
+    app_data: &str,
+    storage: &Storage,
+    recording: Recording,
+    config: &FileSinkConfig,
+    remux: &mpsc::UnboundedSender<Recording>,
+) {
+    if config.faststart.unwrap_or(false) {
+        storage.recordings.set_remux_pending(recording.id, true).await;
+    }
+    uploader::enqueue(storage, &recording).await;
+    if config.faststart.unwrap_or(false) && remux.send(recording.clone()).is_err() {
+        // The worker is gone, it picks the recording up from the index on the next start
+        warn!("Remux queue closed, {} is remuxed after a restart", recording.file_name);
+    }
+    spawn_thumbnail(app_data, recording, config.preview_interval);
+}
 
-                if time_created < max_time {
-                    max_time = time_created;
-                    path = file.path();
-                }
+fn spawn_thumbnail(app_data: &str, recording: Recording, preview_interval: Option<u64>) {
+    let app_data = app_data.to_string();
+    tokio::spawn(async move {
+        if let Err(e) = thumbnails::generate(&app_data, &recording, preview_interval).await {
+            warn!("Failed to generate thumbnail for {} {e}", recording.file_name);
+        }
+    });
+}
+
+async fn open_recording(app_data: &str, storage: &Storage, config: &FileSinkConfig) -> Option<OpenRecording> {
+    let devices = storage.devices.devices().await;
+    let pipeline_config = crate::video::Config::find_optimal_settings(devices, storage.camera_config.get().await);
+    let device = Device::find(devices, pipeline_config.source());
+    let camera = device.map(|d| d.id.clone());
+    let camera_name = config.camera_name.as_deref()
+        .or(device.map(|d| d.card.as_str()))
+        .unwrap_or("camera");
+
+    let file_name = generate_file_name(config, camera_name, chrono::Local::now());
+    let (file_name, file) = generate_new_file(app_data, &file_name).await?;
+    let path = PathBuf::from(app_data).join(&file_name);
+
+    let mut recording = Recording {
+        file_name,
+        start_time: chrono::Utc::now().timestamp_millis(),
+        camera,
+        ..Default::default()
+    };
+    // Without an index row there is nothing to update, thumbnail or remux, the next keyframe tries again
+    let Some(id) = storage.recordings.create_recording(&recording).await else {
+        drop(file);
+        if let Err(e) = tokio::fs::remove_file(partial_path(&path)).await {
+            warn!("Failed to remove unindexed recording {path:?} {e:?}");
+        }
+        return None;
+    };
+    recording.id = id;
+
+    Some(OpenRecording {
+        file,
+        path,
+        recording,
+        last_sync: Instant::now(),
+    })
+}
+
+/// Brings the index in line with the files in app_data, run before recording starts.
+/// Recordings interrupted by a crash are cut back to their last complete fragment.
+pub async fn reconcile_recordings(app_data: &str, storage: &Storage) {
+    let config = storage.file_config.get().await;
+    let files = match walk_recordings(Path::new(app_data)).await {
+        Ok(files) => files,
+        Err(e) => {
+            error!("Failed to read recordings directory {e:?}");
+            return;
+        }
+    };
+
+    let mut on_disk = HashMap::new();
+    let mut partial = Vec::new();
+    for (name, metadata) in files {
+        let path = PathBuf::from(app_data).join(&name);
+        if let Some(target) = name.strip_suffix(PARTIAL_SUFFIX).filter(|n| n.ends_with(".mp4")) {
+            partial.push((target.to_string(), path));
+        } else if name.ends_with(&format!(".mp4{}", remux::REMUX_SUFFIX)) {
+            info!("Removing interrupted remux {path:?}");
+            if let Err(e) = tokio::fs::remove_file(&path).await {
+                error!("Failed to remove {path:?} {e:?}");
             }
+        } else if name.ends_with(".mp4") {
+            on_disk.insert(name, metadata);
+        }
+    }
+
+    // Durations of repaired recordings, their modification time is no longer the end
+    let mut repaired = HashMap::new();
+    for (name, path) in partial {
+        let target = PathBuf::from(app_data).join(&name);
+        match repair_recording(&path).await {
+            Ok(Some(duration)) => {
+                if let Err(e) = tokio::fs::rename(&path, &target).await {
+                    error!("Failed to rename repaired recording {path:?} {e:?}");
+                    continue;
+                }
+                if let Ok(metadata) = tokio::fs::metadata(&target).await {
+                    repaired.insert(name.clone(), duration);
+                    on_disk.insert(name, metadata);
+                }
+            },
+            Ok(None) => {
+                warn!("Recording {path:?} has no complete fragment, removing it");
+                if let Err(e) = tokio::fs::remove_file(&path).await {
+                    error!("Failed to remove recording {path:?} {e:?}");
+                }
+            },
+            Err(e) => error!("Failed to repair recording {path:?} {e:?}"),
+        }
+    }
+
+    for mut recording in storage.recordings.all_recordings().await {
+        match on_disk.remove(&recording.file_name) {
+            None => {
+                info!("Recording {} no longer exists, removing it from the index", recording.file_name);
+                storage.recordings.delete_recording(&recording.file_name).await;
+                thumbnails::remove(app_data, recording.id).await;
+            },
+            Some(metadata) if !recording.closed => {
+                info!("Closing recording {} left open by a previous run", recording.file_name);
+                recording.size = metadata.len();
+                recording.end_time = repaired.get(&recording.file_name)
+                    .map(|duration| recording.start_time + duration)
+                    .or(modified_millis(&metadata))
+                    .or(recording.end_time);
+                recording.closed = true;
+                storage.recordings.update_recording(&recording).await;
+            },
+            Some(_) => {}
         }
     }
 
-    if let Err(_) = tokio::fs::remove_file(path).await {
-        // TODO log error and handle it
+    for (file_name, metadata) in on_disk {
+        info!("Indexing recording {file_name}");
+        let start_time = start_time_from_file_name(&file_name, &config);
+        let end_time = match (start_time, repaired.get(&file_name)) {
+            (Some(start_time), Some(duration)) => Some(start_time + duration),
+            _ => modified_millis(&metadata),
+        };
+        let recording = Recording {
+            file_name,
+            start_time: start_time.or(end_time).unwrap_or_default(),
+            end_time,
+            size: metadata.len(),
+            closed: true,
+            ..Default::default()
+        };
+        storage.recordings.create_recording(&recording).await;
     }
 }
 
-async fn should_file_be_rotated(config: &FileSinkConfig, app_data: &str) -> bool {
-    percentage_of_file_system_usage(app_data) > config.max_system_usage
-        || (config.max_number_of_file.is_some()
-            && &number_of_mp4_files(app_data).await
-                > config.max_number_of_file.as_ref().unwrap_or(&0))
+/// Directory inside app_data for files the app keeps next to the recordings, like thumbnails or exports.
+/// The name is prefixed with a dot, reconciliation skips hidden directories so their files aren't mistaken for recordings.
+pub fn private_dir(app_data: &str, name: &str) -> PathBuf {
+    PathBuf::from(app_data).join(format!(".{name}"))
 }
 
-pub async fn number_of_mp4_files(app_data: &str) -> u64 {
-    let mut files = tokio::fs::read_dir(app_data).await.unwrap();
-    let mut number_of_mp4_files = 0;
-    while let Ok(Some(file)) = files.next_entry().await {
-        if file
-            .file_name()
-            .to_str()
-            .map(|s| s.ends_with(".mp4"))
-            .unwrap_or(false)
-        {
-            number_of_mp4_files += 1;
+fn is_private_dir(name: &str) -> bool {
+    name.starts_with('.')
+}
+
+/// Files below app_data with their path relative to it, skipping the private directories
+async fn walk_recordings(app_data: &Path) -> std::io::Result<Vec<(String, std::fs::Metadata)>> {
+    let mut files = Vec::new();
+    let mut directories = vec![PathBuf::new()];
+    while let Some(directory) = directories.pop() {
+        let mut entries = tokio::fs::read_dir(app_data.join(&directory)).await?;
+        while let Some(entry) = entries.next_entry().await? {
+            let Some(name) = entry.file_name().to_str().map(str::to_string) else {
+                continue;
+            };
+            let Ok(metadata) = entry.metadata().await else {
+                continue;
+            };
+            let relative = directory.join(&name);
+            if metadata.is_dir() && !is_private_dir(&name) {
+                directories.push(relative);
+            } else if metadata.is_file() {
+                if let Some(relative) = relative.to_str() {
+                    files.push((relative.to_string(), metadata));
+                }
+            }
         }
     }
-    number_of_mp4_files
+    Ok(files)
+}
+
+/// Truncates a recording to its last complete moof + mdat pair, returns its duration in
+/// milliseconds or none if not a single fragment survived
+async fn repair_recording(path: &Path) -> std::io::Result<Option<i64>> {
+    let layout = match mp4::scan(path).await {
+        Ok(layout) => layout,
+        Err(e) if e.kind() == std::io::ErrorKind::InvalidData => return Ok(None),
+        Err(e) => return Err(e),
+    };
+    if layout.fragments.is_empty() {
+        return Ok(None);
+    }
+
+    let length = layout.complete_length();
+    let file = tokio::fs::OpenOptions::new().write(true).open(path).await?;
+    if file.metadata().await?.len() > length {
+        warn!("Truncating {path:?} to its last complete fragment at {length} bytes");
+        file.set_len(length).await?;
+        file.sync_all().await?;
+    }
+    Ok(Some(layout.duration_millis()))
 }
 
-pub fn percentage_of_file_system_usage(app_data: &str) -> f64 {
-    let total_space = fs2::total_space(app_data).unwrap_or(1);
-    let free_space = fs2::free_space(app_data).unwrap_or(1);
-    let ret = 1.0 - (free_space as f64 / total_space as f64);
-    return ret;
+fn modified_millis(metadata: &std::fs::Metadata) -> Option<i64> {
+    metadata.modified().ok()
+        .map(|t| chrono::DateTime::<chrono::Utc>::from(t).timestamp_millis())
+}
+
+/// Start time encoded in the name, recordings named by templates with a camera name are not parsed
+fn start_time_from_file_name(file_name: &str, config: &FileSinkConfig) -> Option<i64> {
+    let name = file_name.strip_suffix(".mp4")?;
+    // Names that collided carry the counter added by `generate_new_file`
+    let uncounted = name.rsplit_once('-')
+        .filter(|(_, counter)| !counter.is_empty() && counter.bytes().all(|b| b.is_ascii_digit()))
+        .map(|(name, _)| name);
+    std::iter::once(name)
+        .chain(uncounted)
+        .find_map(|name| parse_start_time(name, config))
+}
+
+fn parse_start_time(name: &str, config: &FileSinkConfig) -> Option<i64> {
+    let stem = name.rsplit('/').next().unwrap_or(name);
+    // Names written before templates existed
+    if let Ok(time) = chrono::DateTime::parse_from_str(stem, "%Y-%m-%d %H:%M:%S%.f %:z") {
+        return Some(time.timestamp_millis());
+    }
+
+    let template = file_name_template(config);
+    if template.contains(CAMERA_PLACEHOLDER) {
+        return None;
+    }
+    chrono::NaiveDateTime::parse_from_str(name, &format!("{DATE_DIRECTORIES}/{template}"))
+        .or_else(|_| chrono::NaiveDateTime::parse_from_str(stem, template))
+        .ok()
+        .and_then(|t| t.and_local_timezone(chrono::Local).earliest())
+        .map(|t| t.timestamp_millis())
 }
 
 fn should_create_new_file(
@@ -125,21 +498,248 @@ fn should_create_new_file(
 }
 
 async fn save_moov_header(
-    moov: &Arc<RwLock<Vec<Vec<u8>>>>,
-    file: &mut File,
+    moov: &Arc<RwLock<InitSegment>>,
+    recording: &mut OpenRecording,
 ) -> Result<(), std::io::Error> {
-    for header in moov.read().await.iter() {
-        file.write(&header).await?;
+    for header in moov.read().await.packets.iter() {
+        recording.write(&header).await?;
+    }
+    Ok(())
+}
+
+/// Checks that a naming template only uses known strftime specifiers and can't leave app_data
+pub fn validate_file_name_template(template: &str) -> Result<(), String> {
+    if template.is_empty() {
+        return Err("File name template is empty".to_string());
+    }
+    if template.contains('/') || template.contains('\\') {
+        return Err("File name template can't contain directories, use date_directories".to_string());
+    }
+    if chrono::format::StrftimeItems::new(template).any(|item| matches!(item, chrono::format::Item::Error)) {
+        return Err(format!("File name template {template} has an unknown specifier"));
+    }
+
+    // Names are only parsed back into start times if sanitizing leaves them alone
+    let sample = chrono::FixedOffset::east_opt(90 * 60)
+        .and_then(|offset| chrono::TimeZone::with_ymd_and_hms(&offset, 2001, 2, 3, 4, 5, 6).single())
+        .ok_or("Failed to check file name template")?;
+    let formatted = sample.format(&template.replace(CAMERA_PLACEHOLDER, "")).to_string();
+    if let Some(c) = formatted.chars().find(|c| !is_file_name_char(*c)) {
+        return Err(format!("File name template {template} produces {c:?}, only letters, digits, '-', '_' and '.' are allowed"));
+    }
+    if formatted.starts_with('.') {
+        return Err(format!("File name template {template} produces hidden files"));
     }
     Ok(())
 }
 
-fn generate_file_name() -> String {
-    format!("{}.mp4", chrono::Local::now())
+/// Configured template, or the default when it's unset or invalid
+fn file_name_template(config: &FileSinkConfig) -> &str {
+    config.file_name_template.as_deref()
+        .filter(|t| validate_file_name_template(t).is_ok())
+        .unwrap_or(DEFAULT_FILE_NAME_TEMPLATE)
+}
+
+/// Characters kept in names, portable to FAT/exFAT and shell scripts
+fn is_file_name_char(c: char) -> bool {
+    c.is_ascii_alphanumeric() || matches!(c, '-' | '_' | '.')
+}
+
+fn sanitize_file_name(name: &str) -> String {
+    name.chars()
+        .map(|c| if is_file_name_char(c) { c } else { '_' })
+        .collect()
+}
+
+/// Relative path of a new recording, inside date directories when they are enabled
+fn generate_file_name(config: &FileSinkConfig, camera_name: &str, now: chrono::DateTime<chrono::Local>) -> String {
+    // The camera name goes in after formatting, so it can't inject specifiers
+    let name = now.format(file_name_template(config)).to_string()
+        .split(CAMERA_PLACEHOLDER)
+        .map(sanitize_file_name)
+        .collect::<Vec<_>>()
+        .join(&sanitize_file_name(camera_name));
+    // Hidden names would be skipped when the index is reconciled
+    let name = match name.trim_start_matches('.') {
+        "" => now.format(DEFAULT_FILE_NAME_TEMPLATE).to_string(),
+        name => name.to_string(),
+    };
+
+    if config.date_directories.unwrap_or(false) {
+        format!("{}/{name}.mp4", now.format(DATE_DIRECTORIES))
+    } else {
+        format!("{name}.mp4")
+    }
+}
+
+/// Creates the partial file of a new recording, a counter is appended when the name is taken
+async fn generate_new_file(app_data: &str, file_name: &str) -> Option<(String, File)> {
+    let first = PathBuf::from(app_data).join(file_name);
+    if let Some(directory) = first.parent() {
+        if let Err(e) = tokio::fs::create_dir_all(directory).await {
+            error!("Failed to create recording directory {directory:?} {e:?}");
+            return None;
+        }
+    }
+
+    let stem = file_name.strip_suffix(".mp4").unwrap_or(file_name);
+    for attempt in 0..MAX_NAME_ATTEMPTS {
+        let name = match attempt {
+            0 => file_name.to_string(),
+            n => format!("{stem}-{n}.mp4"),
+        };
+        let path = PathBuf::from(app_data).join(&name);
+        // Closing renames the partial file onto the final name, so that has to be free as well
+        if tokio::fs::try_exists(&path).await.unwrap_or(false) {
+            continue;
+        }
+        match File::create_new(partial_path(&path)).await {
+            Ok(file) => return Some((name, file)),
+            Err(e) if e.kind() == std::io::ErrorKind::AlreadyExists => continue,
+            Err(e) => {
+                error!("Failed to create recording {path:?} {e:?}");
+                return None;
+            }
+        }
+    }
+    error!("No free name for recording {file_name}");
+    None
 }
 
-async fn generate_new_file(app_data: &str) -> File {
-    let file_name = generate_file_name();
-    let file_path = std::path::PathBuf::from_str(&format!("{app_data}/{file_name}")).unwrap();
-    File::create_new(file_path).await.unwrap() // TODO: Handle errors
+#[cfg(test)]
+mod tests {
+    use gstreamer::{prelude::*, ClockTime, MessageType as BusMessage, State};
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    fn naming(template: Option<&str>, date_directories: bool) -> FileSinkConfig {
+        FileSinkConfig {
+            file_name_template: template.map(str::to_string),
+            date_directories: Some(date_directories),
+            ..Default::default()
+        }
+    }
+
+    fn local(year: i32, month: u32, day: u32, hour: u32, minute: u32, second: u32) -> chrono::DateTime<chrono::Local> {
+        chrono::TimeZone::with_ymd_and_hms(&chrono::Local, year, month, day, hour, minute, second).unwrap()
+    }
+
+    #[test]
+    fn validates_templates() {
+        for template in [DEFAULT_FILE_NAME_TEMPLATE, "%Y%m%d-%H%M%S", "{camera}_%Y-%m-%d_%H.%M.%S", "rec_%s"] {
+            assert_eq!(validate_file_name_template(template), Ok(()), "{template}");
+        }
+        // Empty, directories, unknown specifiers, characters lost by sanitizing and hidden names
+        for template in ["", "%Y/%m", "a\\b", "%Q", "%Y-%m-%d %H:%M:%S", "%T", "%z", "%Y{camera}:", ".%Y"] {
+            assert!(validate_file_name_template(template).is_err(), "{template}");
+        }
+    }
+
+    #[test]
+    fn names_recordings_from_template() {
+        let now = local(2025, 3, 4, 5, 6, 7);
+        assert_eq!(generate_file_name(&naming(None, false), "cam", now), "2025-03-04_05-06-07.mp4");
+        assert_eq!(generate_file_name(&naming(None, true), "cam", now), "2025/03/04/2025-03-04_05-06-07.mp4");
+        assert_eq!(
+            generate_file_name(&naming(Some("{camera}_%H%M%S"), false), "Front door/1", now),
+            "Front_door_1_050607.mp4"
+        );
+        // Invalid templates fall back to the default
+        assert_eq!(generate_file_name(&naming(Some("%H:%M"), false), "cam", now), "2025-03-04_05-06-07.mp4");
+        // A camera name can't make the file hidden
+        assert_eq!(generate_file_name(&naming(Some("{camera}%S"), false), "..", now), "07.mp4");
+    }
+
+    #[test]
+    fn parses_start_time_from_generated_names() {
+        let now = local(2025, 3, 4, 5, 6, 7);
+        for (template, date_directories) in [(None, false), (None, true), (Some("%Y%m%d-%H%M%S"), false), (Some("%Y%m%d-%H%M%S"), true)] {
+            let config = naming(template, date_directories);
+            let name = generate_file_name(&config, "cam", now);
+            assert_eq!(start_time_from_file_name(&name, &config), Some(now.timestamp_millis()), "{name}");
+
+            // Collision counters are ignored
+            let counted = format!("{}-12.mp4", name.strip_suffix(".mp4").unwrap());
+            assert_eq!(start_time_from_file_name(&counted, &config), Some(now.timestamp_millis()), "{counted}");
+        }
+    }
+
+    #[test]
+    fn parses_start_time_from_legacy_names() {
+        let config = naming(Some("%Y%m%d-%H%M%S"), false);
+        let legacy = chrono::DateTime::parse_from_rfc3339("2025-03-04T05:06:07.123+01:00").unwrap();
+        assert_eq!(start_time_from_file_name("2025-03-04 05:06:07.123 +01:00.mp4", &config), Some(legacy.timestamp_millis()));
+    }
+
+    #[test]
+    fn camera_names_and_other_files_have_no_start_time() {
+        let config = naming(Some("{camera}_%Y%m%d-%H%M%S"), false);
+        let name = generate_file_name(&config, "cam", local(2025, 3, 4, 5, 6, 7));
+        assert_eq!(start_time_from_file_name(&name, &config), None);
+        assert_eq!(start_time_from_file_name("holiday.mp4", &naming(None, false)), None);
+        assert_eq!(start_time_from_file_name("2025-03-04_05-06-07.mkv", &naming(None, false)), None);
+    }
+
+    #[tokio::test]
+    async fn counts_up_on_collisions() {
+        let app_data = TempDir::new("collisions");
+        let root = app_data.app_data();
+
+        let (first, _file) = generate_new_file(&root, "2025/03/04/a.mp4").await.unwrap();
+        assert_eq!(first, "2025/03/04/a.mp4");
+        assert!(app_data.path().join("2025/03/04/a.mp4.part").exists());
+        let (second, _file) = generate_new_file(&root, "2025/03/04/a.mp4").await.unwrap();
+        assert_eq!(second, "2025/03/04/a-1.mp4");
+
+        // Closed recordings block their name as well
+        std::fs::write(app_data.path().join("b.mp4"), b"").unwrap();
+        let (name, _file) = generate_new_file(&root, "b.mp4").await.unwrap();
+        assert_eq!(name, "b-1.mp4");
+    }
+
+    /// Encodes `seconds` of test pattern into an mp4 at `path`
+    fn write_test_clip(path: &Path, seconds: u32) {
+        gstreamer::init().unwrap();
+        let pipeline = gstreamer::parse::launch(&format!(
+            "videotestsrc num-buffers={} ! video/x-raw,width=320,height=240,framerate=30/1 ! \
+            x264enc key-int-max=30 ! h264parse ! mp4mux ! filesink location=\"{}\"",
+            seconds * 30,
+            path.display()
+        )).unwrap();
+        pipeline.set_state(State::Playing).unwrap();
+        let bus = pipeline.bus().unwrap();
+        let message = bus.timed_pop_filtered(ClockTime::from_seconds(30), &[BusMessage::Eos, BusMessage::Error]);
+        pipeline.set_state(State::Null).unwrap();
+        assert!(matches!(message.map(|m| m.type_()), Some(BusMessage::Eos)), "Failed to encode test clip");
+    }
+
+    #[tokio::test]
+    async fn closed_recordings_get_previews() {
+        let app_data = TempDir::new("previews");
+        let storage = test_util::storage(&app_data).await;
+        let path = app_data.path().join("clip.mp4");
+        write_test_clip(&partial_path(&path), 3);
+
+        let mut recording = Recording {
+            file_name: "clip.mp4".to_string(),
+            start_time: chrono::Utc::now().timestamp_millis() - 3000,
+            ..Default::default()
+        };
+        recording.id = storage.recordings.create_recording(&recording).await.unwrap();
+        let open = OpenRecording {
+            file: File::options().append(true).open(partial_path(&path)).await.unwrap(),
+            path,
+            recording,
+            last_sync: Instant::now(),
+        };
+        let moov = Arc::new(RwLock::new(InitSegment::default()));
+        let recording = open.close(&moov, &storage).await;
+        assert!(recording.duration.unwrap() >= 3000);
+
+        thumbnails::generate(&app_data.app_data(), &recording, Some(1)).await.unwrap();
+        assert!(thumbnails::thumbnail_path(&app_data.app_data(), recording.id).exists());
+        for index in 1..=2 {
+            assert!(thumbnails::preview_path(&app_data.app_data(), recording.id, index).exists(), "Preview {index} is missing");
+        }
+    }
 }
@@ -1,20 +1,25 @@
 use std::sync::Arc;
 use std::time::Duration;
 use config::Config;
-use futures_util::{SinkExt, StreamExt};
+use futures_util::StreamExt;
 use gstreamer::glib::ControlFlow;
 use gstreamer::{prelude::*, ClockTime, MessageView, State};
 use poem::endpoint::StaticFilesEndpoint;
 use poem::http::Method;
 use poem::listener::TcpListener;
-use poem::web::{cookie::CookieKey, websocket::{Message, WebSocket}, Data};
+use poem::web::{cookie::CookieKey, websocket::{CloseCode, Message, WebSocket}, Data, RemoteAddr};
 use poem::{get, middleware::Cors, EndpointExt, IntoResponse, Route, Server, handler};
 use poem_openapi::OpenApiService;
 use storage::Storage;
-use tokio::sync::broadcast::Sender;
-use tokio::sync::RwLock;
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
+use tokio::sync::{watch, RwLock};
 use poem::session::{CookieConfig, CookieSession};
 use log::*;
+use models::{PipelineConfig, PipelineState};
+use export::Exports;
+use timelapse::Timelapses;
+use video::InitSegment;
+use viewers::{QueueError, ViewerQueue, Viewers};
 
 mod api_handlers;
 mod users;
@@ -24,98 +29,250 @@ mod file_sink;
 mod storage;
 mod models;
 mod frontend;
+mod profile_scheduler;
+mod shutdown;
+mod viewers;
+mod mp4;
+mod playback;
+mod file_response;
+mod thumbnails;
+mod retention;
+mod recording_schedule;
+mod backends;
+mod uploader;
+mod export;
+mod timelapse;
+mod remux;
+#[cfg(test)]
+mod test_util;
 
 #[handler]
 fn ws(
     ws: WebSocket,
     recv: Data<& tokio::sync::broadcast::Sender<Arc<ParsedBuffer>>>,
-    Data(moov): Data<&Arc<RwLock<Vec<Vec<u8>>>>>
+    Data(moov): Data<&Arc<RwLock<InitSegment>>>,
+    Data(shutdown): Data<&watch::Receiver<bool>>,
+    Data(viewers): Data<&Viewers>,
+    remote_addr: &RemoteAddr
 ) -> impl IntoResponse {
     let mut receiver = recv.subscribe();
 
     let moov = Arc::clone(moov);
+    let mut shutdown = shutdown.clone();
+    let viewers = viewers.clone();
+    let address = remote_addr.to_string();
     ws.on_upgrade(move |socket| async move {
-        let (mut sink, mut stream) = socket.split();
-        for pack in moov.read().await.iter() {
-            let data = pack.clone();
-            if sink.send(poem::web::websocket::Message::binary(data)).await.is_err() {
-                break;
-            };
-        }
+        let (sink, mut stream) = socket.split();
+        let queue = ViewerQueue::spawn(sink);
+        let id = viewers.register(address, &queue);
+
+        let init = moov.read().await;
+        let mut generation = init.generation;
+        let _ = send_init_segment(&queue, &init);
+        drop(init);
         drop(moov);
-        // Start with IFrame
-        let mut iframe_sent = false;
+        // Pipeline restarts announce a new init segment in-band instead of closing the socket
+        let mut init_collector = video::InitSegmentCollector::new();
+        // Start with IFrame, and resync on one after falling behind
+        let mut waiting_for_keyframe = true;
         loop {
             tokio::select! {
                 msg = receiver.recv() => {
-                    if let Ok(msg) = msg {
-                        match msg.message_type {
-                            MessageType::FirstFrame => {
-                                let _ = sink.send(Message::Close(None)).await; // TODO research and specify reason
-                                let _ = sink.close().await;
-                                return;
-                            },
-                            MessageType::KeyFrame if !iframe_sent => {
-                                iframe_sent = true;
-                                let _ = sink.send(poem::web::websocket::Message::binary(msg.data.clone())).await;
-                            },
-                            _ if iframe_sent => {
-                                let _ = sink.send(poem::web::websocket::Message::binary(msg.data.clone())).await;
-                            },
-                            _ => {
-                                continue;
+                    match msg {
+                        Ok(msg) => {
+                            if let Some(init) = init_collector.push(&msg).filter(|i| i.generation != generation) {
+                                generation = init.generation;
+                                debug!("Viewer {id} switching to init segment generation {}", init.generation);
+                                if send_init_segment(&queue, &init).is_err() {
+                                    break;
+                                }
+                                waiting_for_keyframe = true;
                             }
-                        };
+                            match msg.message_type {
+                                MessageType::FirstFrame | MessageType::MoovPacket | MessageType::EndOfStream => {
+                                    continue;
+                                },
+                                MessageType::KeyFrame | MessageType::Fragment => {
+                                    if waiting_for_keyframe && msg.message_type != MessageType::KeyFrame {
+                                        continue;
+                                    }
+                                    match queue.try_push(Message::binary(msg.data.clone())) {
+                                        Ok(()) => {
+                                            waiting_for_keyframe = false;
+                                        },
+                                        Err(QueueError::Full) => {
+                                            if !waiting_for_keyframe {
+                                                debug!("Viewer {id} is over its queue budget, waiting for next keyframe");
+                                                viewers.update(id, |v| v.resyncs += 1);
+                                            }
+                                            viewers.update(id, |v| v.dropped += 1);
+                                            waiting_for_keyframe = true;
+                                        },
+                                        Err(QueueError::Closed) => {
+                                            break;
+                                        }
+                                    }
+                                }
+                            };
+                        },
+                        Err(RecvError::Lagged(skipped)) => {
+                            warn!("Viewer {id} lagged behind by {skipped} messages, waiting for next keyframe");
+                            viewers.update(id, |v| {
+                                v.lagged += skipped;
+                                v.resyncs += 1;
+                            });
+                            waiting_for_keyframe = true;
+                        },
+                        Err(RecvError::Closed) => {
+                            break;
+                        }
                     }
             },
                 msg = stream.next() => {
-                    if let Some(Ok(msg)) = msg {
-                        match msg {
-                            Message::Ping(bytes) => {
-                                let _ = sink.send(Message::Pong(bytes)).await;
-                            },
-                            Message::Close(status_code) => {
-                                let _ = sink.send(Message::Close(status_code));
-                                let _ = sink.close();
-                                return;
-                            },
-                            _ => {}
+                    match msg {
+                        Some(Ok(Message::Ping(bytes))) => {
+                            let _ = queue.push(Message::Pong(bytes));
+                        },
+                        Some(Ok(Message::Close(status_code))) => {
+                            let _ = queue.push(Message::Close(status_code));
+                            break;
+                        },
+                        Some(Ok(_)) => {},
+                        Some(Err(_)) | None => {
+                            break;
                         }
-                    }    
+                    }
+                },
+                _ = shutdown::requested(&mut shutdown) => {
+                    let _ = queue.push(Message::close_with(CloseCode::Away, "Server shutting down"));
+                    break;
                 }
             }
         }
+        viewers.unregister(id);
     })
 }
 
 
+/// Sends the JSON control message followed by the init segment, bypassing the queue budget
+fn send_init_segment(queue: &ViewerQueue, init: &InitSegment) -> Result<(), QueueError> {
+    if init.packets.is_empty() {
+        return Ok(());
+    }
+    queue.push(Message::text(init.control_message()))?;
+    for packet in init.packets.iter() {
+        queue.push(Message::binary(packet.clone()))?;
+    }
+    Ok(())
+}
+
+const PIPELINE_APPLY_TIMEOUT: Duration = Duration::from_secs(20);
+
 #[derive(PartialEq, Debug)]
 pub enum MessageType {
     KeyFrame,
     FirstFrame,
     MoovPacket,
-    Fragment
+    Fragment,
+    /// Pipeline reached EOS, all fragments have been sent
+    EndOfStream
 }
 
 pub struct ParsedBuffer {
+    /// Pipeline instance that produced the buffer
+    generation: u64,
     data: Vec<u8>,
     message_type: MessageType,
     timestamp: Option<ClockTime>
 }
 
-#[allow(unreachable_code)]
-pub async fn pipeline_watchdog(storage: Arc<Storage>, tx: Sender<Arc<ParsedBuffer>>) {
+async fn apply_camera_controls(storage: &Storage, config: &video::Config) {
+    let devices = storage.devices.devices().await;
+    let Some(device) = models::Device::find(devices, config.source()) else {
+        return;
+    };
+
+    let controls = storage.controls.get_controls(&device.id).await;
+    if controls.is_empty() {
+        return;
+    }
+    info!("Applying camera controls for {}: {controls:?}", device.id);
+    if let Err(e) = device.set_controls(&controls) {
+        warn!("{e}");
+    }
+}
+
+/// Waits for the first fragment from a freshly started pipeline, quitting it if none arrives in time
+async fn probe_pipeline(mut recv: Receiver<Arc<ParsedBuffer>>, tx_quit: tokio::sync::mpsc::Sender<()>) -> bool {
+    let first_fragment = async {
+        loop {
+            match recv.recv().await {
+                Ok(buffer) if buffer.message_type == MessageType::KeyFrame
+                    || buffer.message_type == MessageType::Fragment => return true,
+                Err(RecvError::Closed) => return false,
+                _ => {}
+            }
+        }
+    };
+
+    let healthy = tokio::time::timeout(PIPELINE_APPLY_TIMEOUT, first_fragment)
+        .await
+        .unwrap_or(false);
+    if !healthy {
+        warn!("Pipeline did not produce fragments within {PIPELINE_APPLY_TIMEOUT:?}");
+        let _ = tx_quit.try_send(());
+    }
+    healthy
+}
+
+async fn mark_pipeline_running(storage: &Storage, config: PipelineConfig) {
+    let mut status = storage.pipeline_status.get().await;
+    // Keep the failure visible while running on the config we rolled back to
+    let rolled_back = status.state == PipelineState::RolledBack
+        && status.last_known_good.as_ref() == Some(&config);
+    if !rolled_back {
+        status.state = PipelineState::Running;
+        status.error = None;
+        status.failed_config = None;
+    }
+    status.last_known_good = Some(config);
+    storage.pipeline_status.set(&status).await;
+}
+
+async fn rollback_pipeline_config(storage: &Storage, failed: &PipelineConfig, error: String) {
+    let mut status = storage.pipeline_status.get().await;
+    status.error = Some(error.clone());
+    status.failed_config = Some(failed.clone());
+
+    match status.last_known_good.clone() {
+        Some(known_good) if &known_good != failed => {
+            warn!("Pipeline config failed ({error}), rolling back to last known good config");
+            status.state = PipelineState::RolledBack;
+            storage.pipeline_status.set(&status).await;
+            storage.camera_config.set(&known_good).await;
+        },
+        _ => {
+            error!("Pipeline config failed ({error}), no other known good config to roll back to");
+            status.state = PipelineState::Failed;
+            storage.pipeline_status.set(&status).await;
+        }
+    }
+}
+
+pub async fn pipeline_watchdog(storage: Arc<Storage>, tx: Sender<Arc<ParsedBuffer>>, mut shutdown: watch::Receiver<bool>) {
 
     gstreamer::init().unwrap();
 
+    let mut generation = 0;
     loop {
-        let config = storage.camera_config.get().await;
+        generation += 1;
+        let pipeline_config = storage.camera_config.get().await;
         let devices = storage.devices.devices().await;
-        let config = video::Config::find_optimal_settings(devices, config);
+        let config = video::Config::find_optimal_settings(devices, pipeline_config.clone());
 
         info!("Starting new pipline with config: {config:?}");
 
-        let pipeline = video::build_gstreamer_pipline(tx.clone(), &config);
+        let pipeline = video::build_gstreamer_pipline(tx.clone(), &config, generation);
 
         match pipeline {
             Ok(pipeline) => {
@@ -126,6 +283,8 @@ pub async fn pipeline_watchdog(storage: Arc<Storage>, tx: Sender<Arc<ParsedBuffe
                 let main_loop = glib::MainLoop::new(None, false);
                 let (tx_quit,mut rx_quit) = tokio::sync::mpsc::channel(1);
                 let tx_ref = tx_quit.clone();
+                let last_error = Arc::new(std::sync::Mutex::new(None));
+                let last_error_ref = Arc::clone(&last_error);
 
                 let _ = bus.add_watch(move |_, message| {
                     let tx_quit = &tx_quit;
@@ -137,6 +296,9 @@ pub async fn pipeline_watchdog(storage: Arc<Storage>, tx: Sender<Arc<ParsedBuffe
                         },
                         MessageView::Error(err) => {
                             warn!("Pipeline error: {err:?}");
+                            if let Ok(mut last_error) = last_error_ref.lock() {
+                                let _ = last_error.insert(err.error().to_string());
+                            }
                             let _ = tx_quit.try_send(());
                         },
                         MessageView::StateChanged(statechange) => {
@@ -193,20 +355,47 @@ pub async fn pipeline_watchdog(storage: Arc<Storage>, tx: Sender<Arc<ParsedBuffe
                     error!("Failed to start pipline {e:?}");
                 }
 
+                apply_camera_controls(&storage, &config).await;
+
+                let fragments = tx.subscribe();
+                let tx_probe = tx_ref.clone();
+                let storage_ref = Arc::clone(&storage);
+                let applied_config = pipeline_config.clone();
+                let probe = tokio::task::spawn(async move {
+                    let healthy = probe_pipeline(fragments, tx_probe).await;
+                    if healthy {
+                        mark_pipeline_running(&storage_ref, applied_config).await;
+                    }
+                    healthy
+                });
+
                 let main_loop_ref = main_loop.clone();
                 let storage_ref = Arc::clone(&storage);
-                tokio::task::spawn(async move {
+                let pipeline_eos = pipeline.downgrade();
+                let mut shutdown_ref = shutdown.clone();
+                let quit = tokio::task::spawn(async move {
                     let mut storage_change = storage_ref
                         .camera_config
                         .subscribe()
                         .await;
-                    tokio::select! {
+                    let config_changed = tokio::select! {
                         _ = rx_quit.recv() => {
+                            false
                         }             
                          _ = storage_change.recv()=> {
+                            true
                         }
-                    }
+                        _ = shutdown::requested(&mut shutdown_ref) => {
+                            info!("Sending EOS to finalise recordings");
+                            if let Some(pipeline) = pipeline_eos.upgrade() {
+                                pipeline.send_event(gstreamer::event::Eos::new());
+                            }
+                            let _ = tokio::time::timeout(shutdown::EOS_TIMEOUT, rx_quit.recv()).await;
+                            false
+                        }
+                    };
                     main_loop_ref.quit();
+                    config_changed
                 });
 
                 let _ = tokio::task::spawn_blocking(move || {
@@ -215,14 +404,35 @@ pub async fn pipeline_watchdog(storage: Arc<Storage>, tx: Sender<Arc<ParsedBuffe
 
                 let _ = tx_ref.send(()).await;
                 let _ = pipeline.set_state(State::Null);
+
+                let config_changed = quit.await.unwrap_or(false);
+                if !probe.is_finished() {
+                    probe.abort();
+                }
+                let healthy = probe.await.unwrap_or(false);
+                if shutdown::is_requested(&shutdown) {
+                    break;
+                }
+                if !healthy && !config_changed {
+                    let error = last_error.lock().ok()
+                        .and_then(|e| e.clone())
+                        .unwrap_or_else(|| format!("Pipeline did not produce fragments within {PIPELINE_APPLY_TIMEOUT:?}"));
+                    rollback_pipeline_config(&storage, &pipeline_config, error).await;
+                }
             },
             Err(e) => {
                 error!("Error creating pipline: {e:?}");
+                rollback_pipeline_config(&storage, &pipeline_config, e).await;
+            }
+        }
+        tokio::select! {
+            _ = tokio::time::sleep(Duration::from_secs(15)) => {}
+            _ = shutdown::requested(&mut shutdown) => {
+                break;
             }
         }
-        tokio::time::sleep(Duration::from_secs(15)).await;
     }
-    error!("Exiting watchdog!");
+    info!("Pipeline stopped");
 }
 
 #[tokio::main]
@@ -237,10 +447,18 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     info!("Config: {config:?}");
     info!("Devices found: {:?}", storage.devices.devices().await);
-
-    let (tx, _) = tokio::sync::broadcast::channel::<Arc<ParsedBuffer>>(1024); 
+    file_sink::reconcile_recordings(&config.app_data, &storage).await;
+    export::clear(&config.app_data).await;
+    timelapse::recover(&config.app_data, &storage).await;
+
+    // Viewers buffer in their own queues, this only has to cover the file sink
+    let (tx, _) = tokio::sync::broadcast::channel::<Arc<ParsedBuffer>>(256); 
+    let viewers = Viewers::default();
+    let exports = Exports::default();
+    let (shutdown_tx, shutdown_rx) = watch::channel(false);
     
-    let moov: Arc<RwLock<Vec<Vec<u8>>>> = Arc::new(RwLock::new(Vec::new()));
+    let moov: Arc<RwLock<InitSegment>> = Arc::new(RwLock::new(InitSegment::default()));
+    let timelapses = Timelapses::new(Arc::clone(&storage), tx.clone(), Arc::clone(&moov));
 
     let moov2 = Arc::clone(&moov);
     let file_sink_subscirber = tx.subscribe();
@@ -250,15 +468,45 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
 
     let tx2 = tx.clone();
     let storage2 = Arc::clone(&storage);
-    tokio::task::spawn(async {
-        pipeline_watchdog(storage2, tx).await;
+    let shutdown = shutdown_rx.clone();
+    let watchdog = tokio::task::spawn(async {
+        pipeline_watchdog(storage2, tx, shutdown).await;
+    });
+
+    let storage_ref = Arc::clone(&storage);
+    tokio::spawn(async move {
+        profile_scheduler::profile_scheduler(storage_ref).await;
+    });
+
+    let storage_ref = Arc::clone(&storage);
+    let shutdown = shutdown_rx.clone();
+    let app_data = config.app_data.clone();
+    tokio::spawn(async move {
+        retention::retention_task(storage_ref, app_data, shutdown).await;
+    });
+
+    let storage_ref = Arc::clone(&storage);
+    let shutdown = shutdown_rx.clone();
+    let app_data = config.app_data.clone();
+    tokio::spawn(async move {
+        uploader::upload_worker(storage_ref, app_data, shutdown).await;
+    });
+
+    let (remux_tx, remux_rx) = tokio::sync::mpsc::unbounded_channel();
+    let storage_ref = Arc::clone(&storage);
+    let shutdown = shutdown_rx.clone();
+    let app_data = config.app_data.clone();
+    tokio::spawn(async move {
+        remux::remux_worker(storage_ref, app_data, remux_rx, shutdown).await;
     });
 
     let moov2 = Arc::clone(&moov);
     let file_sink_subscirber = tx2.subscribe();
     let storage_ref = Arc::clone(&storage);
-    tokio::spawn(async move {
-        file_sink::file_saver(file_sink_subscirber, moov2, &config.app_data, storage_ref).await;
+    let shutdown = shutdown_rx.clone();
+    let app_data = config.app_data.clone();
+    let file_saver = tokio::spawn(async move {
+        file_sink::file_saver(file_sink_subscirber, moov2, &app_data, storage_ref, remux_tx, shutdown).await;
     });
 
 
@@ -280,18 +528,33 @@ async fn main() -> Result<(), Box<dyn std::error::Error>> {
             get(ws)
             .data(tx2)
             .data(moov)
+            .data(shutdown_rx)
         )
         .nest("/api", api_service)
             .data(Arc::clone(&storage))
+            .data(viewers)
+            .data(exports)
+            .data(timelapses)
             .with(CookieSession::new(CookieConfig::signed(CookieKey::generate())))
             .with(cors);
 
     info!("Listening on: {}", config.bind);
     if let Err(e) = Server::new(TcpListener::bind(&config.bind))
-        .run(app)
+        .run_with_graceful_shutdown(app, shutdown::wait_for_signal(&shutdown_tx), Some(shutdown::SHUTDOWN_TIMEOUT))
         .await {
             error!("Error starting server: {e:?}")
     }
 
+    // Server no longer accepts connections, wait for the pipeline and recordings to close
+    let _ = shutdown_tx.send(true);
+    let finished = tokio::time::timeout(shutdown::SHUTDOWN_TIMEOUT, async {
+        let _ = watchdog.await;
+        let _ = file_saver.await;
+    }).await;
+    if finished.is_err() {
+        warn!("Timed out waiting for the pipeline and recordings to finish");
+    }
+
+    info!("Shutdown complete");
     Ok(())
 }
\ No newline at end of file
@@ -1,10 +1,34 @@
 
 pub mod users;
 pub mod pipeline_config;
+pub mod pipeline_status;
 pub mod devices;
+pub mod device_info;
 pub mod file_sink_config;
+pub mod camera_control;
+pub mod camera_profile;
+pub mod viewer_stats;
+pub mod recording;
+pub mod playback;
+pub mod retention;
+pub mod recording_schedule;
+pub mod upload;
+pub mod export;
+pub mod timelapse;
 
 pub use users::User;
-pub use pipeline_config::PipelineConfig;
+pub use pipeline_config::{LatencyMode, PipelineConfig, PipelinePreview};
+pub use pipeline_status::{PipelineState, PipelineStatus};
 pub use devices::Device;
-pub use file_sink_config::FileSinkConfig;
\ No newline at end of file
+pub use device_info::DeviceInfo;
+pub use file_sink_config::FileSinkConfig;
+pub use camera_control::{CameraControl, ControlMenuItem, ControlValue};
+pub use camera_profile::{CameraProfile, ProfileSchedule, ScheduleMode, WeeklyProfileSwitch};
+pub use viewer_stats::ViewerStats;
+pub use recording::{Recording, RecordingPage, RecordingQuery, RecordingSort, SortOrder};
+pub use playback::PlaybackSegment;
+pub use retention::{RetentionEvent, RetentionRule};
+pub use recording_schedule::{RecordingSchedule, RecordingScheduleState, RecordingWindow, ScheduleException};
+pub use upload::{LocalCopyPolicy, S3Config, SftpConfig, UploadBackend, UploadConfig, UploadJob, UploadStatus, WebDavConfig};
+pub use export::{ExportJob, ExportState};
+pub use timelapse::{Timelapse, TimelapseConfig, TimelapseRequest, TimelapseSource, TimelapseState};
\ No newline at end of file
@@ -0,0 +1,28 @@
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct CameraControl {
+    pub id: u32,
+    pub name: String,
+    pub control_type: String,
+    pub minimum: i64,
+    pub maximum: i64,
+    pub step: u64,
+    pub default: i64,
+    pub value: Option<i64>,
+    pub menu: Vec<ControlMenuItem>
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct ControlMenuItem {
+    pub index: u32,
+    pub name: String
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct ControlValue {
+    pub id: u32,
+    pub value: i64
+}
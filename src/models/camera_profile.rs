@@ -0,0 +1,38 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use super::{ControlValue, PipelineConfig};
+
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct CameraProfile {
+    pub name: String,
+    pub pipeline: PipelineConfig,
+    pub controls: Vec<ControlValue>
+}
+
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ScheduleMode {
+    #[default]
+    Disabled,
+    Weekly,
+    Sun
+}
+
+/// Switch to `profile` every week on `weekday` (0 is Monday) at `time` (HH:MM local time)
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct WeeklyProfileSwitch {
+    pub weekday: u32,
+    pub time: String,
+    pub profile: String
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ProfileSchedule {
+    pub mode: ScheduleMode,
+    pub weekly: Vec<WeeklyProfileSwitch>,
+    pub latitude: f64,
+    pub longitude: f64,
+    pub day_profile: Option<String>,
+    pub night_profile: Option<String>
+}
@@ -0,0 +1,149 @@
+use poem_openapi::{Object, Union};
+use v4l::frameinterval::FrameIntervalEnum;
+use v4l::framesize::FrameSizeEnum;
+
+use super::devices::{max_frame_size, Capabilties, Device};
+
+
+#[derive(Object, Debug, Clone)]
+pub struct DeviceInfo {
+    pub id: String,
+    pub path: String,
+    pub card: String,
+    pub bus: String,
+    pub by_id: Vec<String>,
+    pub by_path: Vec<String>,
+    pub formats: Vec<FormatInfo>
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct FormatInfo {
+    pub format: String,
+    pub builtin_encoder: bool,
+    pub frame_sizes: Vec<FrameSizeInfo>
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct FrameSizeInfo {
+    pub size: FrameSize,
+    pub frame_intervals: Vec<FrameInterval>
+}
+
+#[derive(Union, Debug, Clone)]
+#[oai(discriminator_name = "type")]
+pub enum FrameSize {
+    Discrete(DiscreteFrameSize),
+    Stepwise(StepwiseFrameSize)
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct DiscreteFrameSize {
+    pub width: u32,
+    pub height: u32
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct StepwiseFrameSize {
+    pub min_width: u32,
+    pub max_width: u32,
+    pub step_width: u32,
+    pub min_height: u32,
+    pub max_height: u32,
+    pub step_height: u32
+}
+
+#[derive(Union, Debug, Clone)]
+#[oai(discriminator_name = "type")]
+pub enum FrameInterval {
+    Discrete(Fraction),
+    Stepwise(StepwiseFrameInterval)
+}
+
+/// Time between frames in seconds, `1/30` is 30 fps
+#[derive(Object, Debug, Clone)]
+pub struct Fraction {
+    pub numerator: u32,
+    pub denominator: u32
+}
+
+#[derive(Object, Debug, Clone)]
+pub struct StepwiseFrameInterval {
+    pub min: Fraction,
+    pub max: Fraction,
+    pub step: Fraction
+}
+
+
+impl From<&Device> for DeviceInfo {
+    fn from(device: &Device) -> Self {
+        Self {
+            id: device.id.clone(),
+            path: device.path.clone(),
+            card: device.card.clone(),
+            bus: device.bus.clone(),
+            by_id: device.by_id.clone(),
+            by_path: device.by_path.clone(),
+            formats: device.capabilities.iter().map(FormatInfo::from).collect()
+        }
+    }
+}
+
+impl From<&Capabilties> for FormatInfo {
+    fn from(cap: &Capabilties) -> Self {
+        let frame_sizes = cap.resolution.iter()
+            .map(|size| FrameSizeInfo {
+                size: FrameSize::from(size),
+                frame_intervals: cap.frame_intervals.get(&max_frame_size(size))
+                    .map(|intervals| intervals.iter().map(FrameInterval::from).collect())
+                    .unwrap_or_default()
+            })
+            .collect();
+
+        Self {
+            format: cap.format.clone(),
+            builtin_encoder: crate::video::format_supports_builtin_encoder(&cap.format),
+            frame_sizes
+        }
+    }
+}
+
+impl From<&FrameSizeEnum> for FrameSize {
+    fn from(size: &FrameSizeEnum) -> Self {
+        match size {
+            FrameSizeEnum::Discrete(d) => FrameSize::Discrete(DiscreteFrameSize {
+                width: d.width,
+                height: d.height
+            }),
+            FrameSizeEnum::Stepwise(s) => FrameSize::Stepwise(StepwiseFrameSize {
+                min_width: s.min_width,
+                max_width: s.max_width,
+                step_width: s.step_width,
+                min_height: s.min_height,
+                max_height: s.max_height,
+                step_height: s.step_height
+            })
+        }
+    }
+}
+
+impl From<&v4l::Fraction> for Fraction {
+    fn from(fraction: &v4l::Fraction) -> Self {
+        Self {
+            numerator: fraction.numerator,
+            denominator: fraction.denominator
+        }
+    }
+}
+
+impl From<&FrameIntervalEnum> for FrameInterval {
+    fn from(interval: &FrameIntervalEnum) -> Self {
+        match interval {
+            FrameIntervalEnum::Discrete(f) => FrameInterval::Discrete(Fraction::from(f)),
+            FrameIntervalEnum::Stepwise(s) => FrameInterval::Stepwise(StepwiseFrameInterval {
+                min: Fraction::from(&s.min),
+                max: Fraction::from(&s.max),
+                step: Fraction::from(&s.step)
+            })
+        }
+    }
+}
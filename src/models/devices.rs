@@ -1,12 +1,25 @@
 use std::collections::HashMap;
+use std::path::Path;
 
+use log::*;
+use v4l::control::{Control, MenuItem, Type, Value};
+use v4l::frameinterval::FrameIntervalEnum;
 use v4l::{framesize::FrameSizeEnum, video::Capture};
 
+use super::{CameraControl, ControlMenuItem, ControlValue};
 
+const V4L_BY_ID: &str = "/dev/v4l/by-id";
+const V4L_BY_PATH: &str = "/dev/v4l/by-path";
 
 #[derive(Debug)]
 pub struct Device {
+    /// Stable identifier that survives reboots and USB re-enumeration
+    pub id: String,
     pub path: String,
+    pub card: String,
+    pub bus: String,
+    pub by_id: Vec<String>,
+    pub by_path: Vec<String>,
     pub capabilities: Vec<Capabilties>
 }
 
@@ -14,7 +27,9 @@ pub struct Device {
 #[derive(Debug)]
 pub struct Capabilties {
     pub format: String,
-    pub resolution: Vec<FrameSizeEnum>
+    pub resolution: Vec<FrameSizeEnum>,
+    /// Frame intervals keyed by frame size, stepwise sizes use their maximum
+    pub frame_intervals: HashMap<(u32, u32), Vec<FrameIntervalEnum>>
 }
 
 
@@ -32,17 +47,41 @@ impl  Device {
                 let mut caps = Vec::new();
                 for fmt in device.enum_formats().unwrap_or_default() {
                     if let Ok(resolution) = device.enum_framesizes(fmt.fourcc) {
+                        let resolution: Vec<FrameSizeEnum> = resolution.into_iter().map(|f| f.size).collect();
+                        let frame_intervals = resolution.iter()
+                            .map(max_frame_size)
+                            .map(|(width, height)| {
+                                let intervals = device.enum_frameintervals(fmt.fourcc, width, height)
+                                    .unwrap_or_default()
+                                    .into_iter()
+                                    .map(|i| i.interval)
+                                    .collect();
+                                ((width, height), intervals)
+                            })
+                            .collect();
                         caps.push(Capabilties {
                             format: fmt.fourcc.str().map(|s| s.to_string()).unwrap(),
-                            resolution: resolution.into_iter().map(|f| f.size).collect()
+                            resolution,
+                            frame_intervals
                         })
                     }
 
                 }
                 if !caps.is_empty() {
+                    let (card, bus) = device.query_caps()
+                        .map(|c| (c.card, c.bus))
+                        .unwrap_or_default();
+                    let by_id = symlinks_to(V4L_BY_ID, path);
+                    let by_path = symlinks_to(V4L_BY_PATH, path);
                     let path  = path.to_str().map(|s| s.to_string()).unwrap_or_default();
+                    let id = stable_id(&by_id, &by_path, &card, &bus, &path);
                     ret.insert( path.clone(), Device {
+                        id,
                         path,
+                        card,
+                        bus,
+                        by_id,
+                        by_path,
                         capabilities: caps
                     });
                 }
@@ -51,4 +90,129 @@ impl  Device {
 
         ret
     }
-}
\ No newline at end of file
+
+    /// Checks if `source` refers to this device, either by its stable id,
+    /// one of its `/dev/v4l` links or the current `/dev/videoN` node
+    pub fn matches(&self, source: &str) -> bool {
+        self.id == source
+            || self.path == source
+            || self.by_id.iter().chain(self.by_path.iter()).any(|l| l == source)
+    }
+
+    /// Lists the V4L2 controls of the device together with their current values
+    pub fn controls(&self) -> Result<Vec<CameraControl>, String> {
+        let device = v4l::Device::with_path(&self.path)
+            .map_err(|e| e.to_string())?;
+        let descriptions = device.query_controls()
+            .map_err(|e| e.to_string())?;
+
+        Ok(descriptions.into_iter()
+            .filter(|d| d.typ != Type::CtrlClass)
+            .map(|d| {
+                let value = match device.control(d.id).map(|c| c.value) {
+                    Ok(Value::Integer(v)) => Some(v),
+                    Ok(Value::Boolean(v)) => Some(v as i64),
+                    _ => None
+                };
+                let menu = d.items.unwrap_or_default()
+                    .into_iter()
+                    .map(|(index, item)| ControlMenuItem {
+                        index,
+                        name: match item {
+                            MenuItem::Name(name) => name,
+                            MenuItem::Value(value) => value.to_string()
+                        }
+                    })
+                    .collect();
+
+                CameraControl {
+                    id: d.id,
+                    name: d.name,
+                    control_type: d.typ.to_string(),
+                    minimum: d.minimum,
+                    maximum: d.maximum,
+                    step: d.step,
+                    default: d.default,
+                    value,
+                    menu
+                }
+            })
+            .collect())
+    }
+
+    /// Applies control values, controls that fail are skipped and reported
+    pub fn set_controls(&self, controls: &[ControlValue]) -> Result<(), String> {
+        let device = v4l::Device::with_path(&self.path)
+            .map_err(|e| e.to_string())?;
+
+        let mut errors = Vec::new();
+        for control in controls {
+            // Controls from different classes can't be set in one call
+            if let Err(e) = device.set_control(Control {
+                id: control.id,
+                value: Value::Integer(control.value)
+            }) {
+                warn!("Failed to set control {} on {}: {e:?}", control.id, self.path);
+                errors.push(format!("{}: {e}", control.id));
+            }
+        }
+
+        if errors.is_empty() {
+            Ok(())
+        } else {
+            Err(format!("Failed to set controls {}", errors.join(", ")))
+        }
+    }
+
+    /// Resolves a configured source to the device currently behind it
+    pub fn find<'a>(devices: &'a HashMap<String, Device>, source: &str) -> Option<&'a Device> {
+        devices.get(source)
+            .or_else(|| devices.values().find(|d| d.matches(source)))
+    }
+}
+
+pub fn max_frame_size(size: &FrameSizeEnum) -> (u32, u32) {
+    match size {
+        FrameSizeEnum::Discrete(d) => (d.width, d.height),
+        FrameSizeEnum::Stepwise(s) => (s.max_width, s.max_height)
+    }
+}
+
+fn symlinks_to(dir: &str, target: &Path) -> Vec<String> {
+    let Ok(target) = std::fs::canonicalize(target) else {
+        return Vec::new();
+    };
+    let Ok(entries) = std::fs::read_dir(dir) else {
+        return Vec::new();
+    };
+
+    let mut links: Vec<String> = entries
+        .filter_map(|e| e.ok())
+        .map(|e| e.path())
+        .filter(|p| std::fs::canonicalize(p).map(|p| p == target).unwrap_or(false))
+        .filter_map(|p| p.to_str().map(|s| s.to_string()))
+        .collect();
+    links.sort();
+    links
+}
+
+fn stable_id(by_id: &[String], by_path: &[String], card: &str, bus: &str, path: &str) -> String {
+    let link_name = |l: &String| Path::new(l)
+        .file_name()
+        .and_then(|n| n.to_str())
+        .map(|n| n.to_string());
+
+    if let Some(id) = by_id.first().and_then(link_name) {
+        return id;
+    }
+    if let Some(id) = by_path.first().and_then(link_name) {
+        return id;
+    }
+    if !card.is_empty() || !bus.is_empty() {
+        return format!("{card}-{bus}")
+            .chars()
+            .map(|c| if c.is_ascii_alphanumeric() || c == '-' || c == '.' { c } else { '_' })
+            .collect();
+    }
+    path.to_string()
+}
@@ -0,0 +1,33 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum ExportState {
+    #[default]
+    Queued,
+    Running,
+    Done,
+    Failed
+}
+
+/// Clip of a recording cut into a standalone mp4 in the background
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ExportJob {
+    pub id: u64,
+    pub recording_id: i64,
+    /// Milliseconds from the start of the recording, once running lossless
+    /// exports widen the range to the enclosing keyframes
+    pub start: i64,
+    pub end: i64,
+    /// Re-encodes for frame accurate cuts instead of cutting at keyframes
+    pub precise: bool,
+    pub state: ExportState,
+    /// From 0 to 1
+    pub progress: f64,
+    pub error: Option<String>,
+    /// Api path of the finished clip
+    pub download: Option<String>,
+    /// Unix timestamp in milliseconds
+    pub created_at: i64
+}
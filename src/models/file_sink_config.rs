@@ -1,13 +1,44 @@
 use poem_openapi::Object;
 use serde::{Deserialize, Serialize};
 
+use super::RecordingSchedule;
 
 
+
+/// Used when `fsync_interval` is unset, 0 only syncs when a recording is closed
+pub const DEFAULT_FSYNC_INTERVAL: u64 = 10;
+/// Used when `file_name_template` is unset, safe on FAT/exFAT and in shell scripts
+pub const DEFAULT_FILE_NAME_TEMPLATE: &str = "%Y-%m-%d_%H-%M-%S";
+/// Replaced by the camera name in `file_name_template`
+pub const CAMERA_PLACEHOLDER: &str = "{camera}";
+
 #[derive(Object, Serialize, Deserialize, Debug, Clone)]
 pub struct FileSinkConfig {
     pub max_file_duration: Option<u64>,
     pub max_number_of_file: Option<u64>,
     pub max_system_usage: f64,
+    /// Seconds after which a recording is deleted
+    pub max_age: Option<u64>,
+    /// Upper bound of the combined size of all recordings
+    pub max_total_bytes: Option<u64>,
+    /// Space that has to stay free on the recordings filesystem
+    pub min_free_bytes: Option<u64>,
+    /// Combined size locked recordings may use, locking fails beyond it
+    pub max_locked_bytes: Option<u64>,
+    /// Record only within the schedule, always record when unset
+    pub schedule: Option<RecordingSchedule>,
+    /// Seconds between fsyncs of the open recording, bounds the data lost on power failure
+    pub fsync_interval: Option<u64>,
+    /// Seconds between preview frames, only the thumbnail is generated when unset
+    pub preview_interval: Option<u64>,
+    /// Remux closed recordings into progressive mp4s with the moov at the front
+    pub faststart: Option<bool>,
+    /// strftime format of new recording names in local time, `{camera}` is replaced by the camera name
+    pub file_name_template: Option<String>,
+    /// Used for `{camera}`, defaults to the name of the capture device
+    pub camera_name: Option<String>,
+    /// Store new recordings in YYYY/MM/DD directories
+    pub date_directories: Option<bool>,
 }
 
 impl Default for FileSinkConfig {
@@ -17,7 +48,18 @@ impl Default for FileSinkConfig {
         Self {
             max_file_duration: Some(CREATE_NEW_FILE_THRESHOLD),
             max_number_of_file: None,
-            max_system_usage: 0.9
+            max_system_usage: 0.9,
+            max_age: None,
+            max_total_bytes: None,
+            min_free_bytes: None,
+            max_locked_bytes: None,
+            schedule: None,
+            fsync_interval: Some(DEFAULT_FSYNC_INTERVAL),
+            preview_interval: None,
+            faststart: None,
+            file_name_template: None,
+            camera_name: None,
+            date_directories: None
         }    
     }
 }
\ No newline at end of file
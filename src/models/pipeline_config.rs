@@ -1,11 +1,39 @@
-use poem_openapi::Object;
+use poem_openapi::{Enum, Object};
 use serde::{Deserialize, Serialize};
 use sqlx::{prelude::FromRow, Decode, Encode};
 
-#[derive(Object, Debug, Clone, Encode, Decode, Default, FromRow, Serialize, Deserialize)]
+#[derive(Object, Debug, Clone, PartialEq, Encode, Decode, Default, FromRow, Serialize, Deserialize)]
 pub struct PipelineConfig {
+    /// Stable device id, `/dev/v4l/by-*` link or `/dev/videoN` path
     pub source: Option<String>,
     pub use_cam_builtin_encoder: Option<bool>,
     pub width: Option<u32>,
-    pub height: Option<u32>
+    pub height: Option<u32>,
+    /// `gst-launch` fragment replacing the source/convert/encode segment, must output H.264
+    pub pipeline_override: Option<String>,
+    pub latency_mode: Option<LatencyMode>,
+    /// Duration of low latency CMAF chunks in milliseconds
+    pub chunk_duration: Option<u32>
+}
+
+#[derive(Enum, Debug, Clone, Copy, PartialEq, Default, Serialize, Deserialize)]
+pub enum LatencyMode {
+    /// A fragment per frame from mp4mux
+    #[default]
+    Standard,
+    /// Keyframe aligned fragments split into sub-second CMAF chunks
+    LowLatency
+}
+
+/// What a `PipelineConfig` resolves to on the current devices
+#[derive(Object, Debug, Clone, Serialize, Deserialize)]
+pub struct PipelinePreview {
+    pub source: String,
+    pub device_id: Option<String>,
+    pub use_cam_builtin_encoder: bool,
+    pub custom_pipeline: bool,
+    pub width: u32,
+    pub height: u32,
+    pub fps: u32,
+    pub unsatisfied: Vec<String>
 }
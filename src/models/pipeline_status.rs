@@ -0,0 +1,23 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use super::PipelineConfig;
+
+
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum PipelineState {
+    #[default]
+    Starting,
+    Running,
+    RolledBack,
+    Failed
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct PipelineStatus {
+    pub state: PipelineState,
+    pub error: Option<String>,
+    pub failed_config: Option<PipelineConfig>,
+    /// Last config that reached Playing and produced fragments, used for rollback
+    pub last_known_good: Option<PipelineConfig>
+}
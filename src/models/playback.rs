@@ -0,0 +1,14 @@
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+
+/// Continuous part of a time range that can be played with a single init segment
+#[derive(Object, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct PlaybackSegment {
+    /// Unix timestamp in milliseconds of the first keyframe
+    pub from: i64,
+    pub to: i64,
+    pub codec: Option<String>,
+    pub width: Option<u32>,
+    pub height: Option<u32>
+}
@@ -0,0 +1,64 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+use super::UploadStatus;
+
+
+/// Recording index entry, times are unix timestamps in milliseconds
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Recording {
+    pub id: i64,
+    /// Path relative to the app data directory
+    pub file_name: String,
+    pub start_time: i64,
+    pub end_time: Option<i64>,
+    pub duration: Option<i64>,
+    pub size: u64,
+    pub width: Option<u32>,
+    pub height: Option<u32>,
+    pub camera: Option<String>,
+    pub closed: bool,
+    /// Locked recordings are skipped by retention
+    pub locked: bool,
+    /// Off-site copy, none when only stored locally
+    pub upload_status: Option<UploadStatus>,
+    /// Remuxed into a progressive mp4, otherwise fragmented as recorded
+    pub faststart: bool,
+    /// Waiting for the faststart remux, its upload starts once that is done
+    pub remux_pending: bool
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct RecordingPage {
+    pub total: u64,
+    pub items: Vec<Recording>
+}
+
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum RecordingSort {
+    #[default]
+    StartTime,
+    Duration,
+    Size
+}
+
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum SortOrder {
+    Asc,
+    #[default]
+    Desc
+}
+
+#[derive(Debug, Clone, Default)]
+pub struct RecordingQuery {
+    /// Recordings that end after this time
+    pub from: Option<i64>,
+    /// Recordings that start before this time
+    pub to: Option<i64>,
+    pub camera: Option<String>,
+    pub locked: Option<bool>,
+    pub sort: RecordingSort,
+    pub order: SortOrder,
+    pub offset: u32,
+    pub limit: u32
+}
@@ -0,0 +1,36 @@
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+
+/// Record every week on `weekday` (0 is Monday) from `start` to `end` (HH:MM),
+/// an `end` before `start` runs past midnight into the next day
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct RecordingWindow {
+    pub weekday: u32,
+    pub start: String,
+    pub end: String
+}
+
+/// One-off override of the weekly windows, times are unix timestamps in milliseconds
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct ScheduleException {
+    pub from: i64,
+    pub to: i64,
+    pub record: bool
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct RecordingSchedule {
+    /// IANA timezone of the windows, e.g. Europe/Berlin, local time when unset
+    pub timezone: Option<String>,
+    pub windows: Vec<RecordingWindow>,
+    /// Later exceptions take precedence over earlier ones
+    pub exceptions: Vec<ScheduleException>
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, PartialEq)]
+pub struct RecordingScheduleState {
+    pub recording: bool,
+    /// Unix timestamp in milliseconds of the next change, none if it never changes
+    pub next_transition: Option<i64>
+}
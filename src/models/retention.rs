@@ -0,0 +1,21 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+
+/// Retention rule that caused a recording to be deleted
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq)]
+pub enum RetentionRule {
+    MaxSystemUsage,
+    MinFreeBytes,
+    MaxNumberOfFiles,
+    MaxTotalBytes,
+    MaxAge
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct RetentionEvent {
+    pub file_name: String,
+    pub rule: RetentionRule,
+    /// Unix timestamp in milliseconds
+    pub deleted_at: i64
+}
@@ -0,0 +1,80 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+
+pub const DEFAULT_TIMELAPSE_FPS: u32 = 30;
+
+#[derive(Enum, Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Default)]
+#[sqlx(rename_all = "snake_case")]
+pub enum TimelapseSource {
+    /// Frames are taken from recordings already on disk
+    #[default]
+    Recordings,
+    /// Frames are taken from the live stream while the job runs
+    Live
+}
+
+#[derive(Enum, Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq, Default)]
+#[sqlx(rename_all = "snake_case")]
+pub enum TimelapseState {
+    #[default]
+    Queued,
+    Running,
+    Done,
+    Failed
+}
+
+/// Times are unix timestamps in milliseconds
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct TimelapseRequest {
+    pub source: TimelapseSource,
+    /// Defaults to now for live timelapses
+    pub from: Option<i64>,
+    pub to: i64,
+    /// Seconds between sampled frames
+    pub interval: u64,
+    /// Output frame rate, `TimelapseConfig::fps` when not set
+    pub fps: Option<u32>
+}
+
+/// Timelapse library entry, times are unix timestamps in milliseconds
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct Timelapse {
+    pub id: i64,
+    pub source: TimelapseSource,
+    pub from: i64,
+    pub to: i64,
+    pub interval: u64,
+    pub fps: u32,
+    pub state: TimelapseState,
+    /// From 0 to 1, sampling and encoding combined
+    pub progress: f64,
+    pub frames: u32,
+    pub size: u64,
+    pub error: Option<String>,
+    pub created_at: i64
+}
+
+/// Defaults and retention of the timelapse library, separate from recordings
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct TimelapseConfig {
+    pub fps: u32,
+    /// Frames are scaled to this width, keeps the size constant across resolution changes
+    pub width: Option<u32>,
+    /// Seconds
+    pub max_age: Option<u64>,
+    pub max_count: Option<u32>,
+    pub max_total_bytes: Option<u64>
+}
+
+impl Default for TimelapseConfig {
+    fn default() -> Self {
+        Self {
+            fps: DEFAULT_TIMELAPSE_FPS,
+            width: None,
+            max_age: None,
+            max_count: None,
+            max_total_bytes: None
+        }
+    }
+}
@@ -0,0 +1,92 @@
+use poem_openapi::{Enum, Object};
+use serde::{Deserialize, Serialize};
+
+
+/// Where finished recordings are stored besides app_data
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum UploadBackend {
+    #[default]
+    Local,
+    S3,
+    WebDav,
+    Sftp
+}
+
+/// What happens to the local copy once a recording has been uploaded
+#[derive(Enum, Serialize, Deserialize, Debug, Clone, Copy, PartialEq, Default)]
+pub enum LocalCopyPolicy {
+    #[default]
+    Keep,
+    /// Locked recordings are always kept
+    DeleteAfterUpload
+}
+
+/// S3 compatible object storage, e.g. AWS S3 or MinIO
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct S3Config {
+    /// e.g. http://localhost:9000 for a local MinIO
+    pub endpoint: String,
+    pub region: String,
+    pub bucket: String,
+    pub access_key: String,
+    /// Never returned by the API, an empty value keeps the stored one
+    pub secret_key: String,
+    /// Key prefix of uploaded recordings
+    pub prefix: Option<String>,
+    /// Bucket in the path instead of the host name, needed for MinIO
+    pub path_style: bool
+}
+
+/// WebDAV collection recordings are mirrored into, with a JSON metadata file next to each
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct WebDavConfig {
+    /// Existing collection, e.g. http://localhost:8080/picam
+    pub url: String,
+    pub username: Option<String>,
+    /// Never returned by the API, an empty value keeps the stored one
+    pub password: Option<String>
+}
+
+/// SFTP directory recordings are mirrored into, with a JSON metadata file next to each
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct SftpConfig {
+    pub host: String,
+    pub port: Option<u16>,
+    pub username: String,
+    /// Password, or passphrase of `private_key`, never returned by the API
+    pub password: Option<String>,
+    /// Path of a private key file on the camera
+    pub private_key: Option<String>,
+    /// SHA256 fingerprint of the server host key as printed by `ssh-keygen -lf`, e.g. `SHA256:nThbg6kX...`
+    pub host_key_fingerprint: Option<String>,
+    pub directory: String
+}
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct UploadConfig {
+    pub backend: UploadBackend,
+    pub s3: Option<S3Config>,
+    pub webdav: Option<WebDavConfig>,
+    pub sftp: Option<SftpConfig>,
+    pub local_copy: LocalCopyPolicy,
+    /// Upload bandwidth limit of the remote backends
+    pub max_bytes_per_second: Option<u64>
+}
+
+#[derive(Enum, Serialize, Deserialize, sqlx::Type, Debug, Clone, Copy, PartialEq)]
+#[sqlx(rename_all = "snake_case")]
+pub enum UploadStatus {
+    Pending,
+    Uploaded,
+    /// Failed at least once and waiting for a retry
+    Failed
+}
+
+/// Recording waiting to be uploaded, times are unix timestamps in milliseconds
+#[derive(Object, Serialize, Deserialize, Debug, Clone)]
+pub struct UploadJob {
+    pub file_name: String,
+    pub attempts: u32,
+    pub next_attempt: i64,
+    pub last_error: Option<String>
+}
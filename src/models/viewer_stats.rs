@@ -0,0 +1,17 @@
+use poem_openapi::Object;
+use serde::{Deserialize, Serialize};
+
+
+#[derive(Object, Serialize, Deserialize, Debug, Clone, Default)]
+pub struct ViewerStats {
+    pub id: u64,
+    pub address: String,
+    pub connected_at: String,
+    /// Messages skipped because the viewer fell behind the live stream
+    pub lagged: u64,
+    /// Fragments dropped because the send queue was over budget
+    pub dropped: u64,
+    /// Times the viewer was resynced on a keyframe
+    pub resyncs: u64,
+    pub queued_bytes: u64
+}
@@ -0,0 +1,882 @@
+use std::{ops::Range, path::Path};
+use tokio::{fs::File, io::{AsyncReadExt, AsyncSeekExt, SeekFrom}};
+
+/// Sample flag marking a sample that is not a sync sample (keyframe)
+const SAMPLE_IS_NON_SYNC: u32 = 0x0001_0000;
+/// Sample flags of generated fragments: keyframes depend on nothing, other frames on earlier ones
+const SYNC_SAMPLE_FLAGS: u32 = 0x0200_0000;
+const NON_SYNC_SAMPLE_FLAGS: u32 = 0x0101_0000;
+/// tfhd flag making trun data offsets relative to the moof
+const TFHD_DEFAULT_BASE_IS_MOOF: u32 = 0x0002_0000;
+/// trun with data offset and per sample duration, size, flags and composition offset
+const TRUN_FLAGS: u32 = 0x0000_0f01;
+/// Guards allocations against corrupt sample tables
+const MAX_SAMPLES: usize = 10_000_000;
+
+/// Box inside a buffer, offsets are relative to the buffer
+#[derive(Debug, Clone, Copy)]
+pub struct BoxRef {
+    pub kind: [u8; 4],
+    pub start: usize,
+    pub content: usize,
+    pub end: usize,
+}
+
+/// Iterates over the boxes directly contained in `data`
+pub fn boxes(data: &[u8]) -> impl Iterator<Item = BoxRef> + '_ {
+    let mut offset = 0;
+    std::iter::from_fn(move || {
+        let header = data.get(offset..offset + 8)?;
+        let size = u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as usize;
+        let kind = [header[4], header[5], header[6], header[7]];
+        let (content, end) = match size {
+            0 => (offset + 8, data.len()),
+            1 => {
+                let large = data.get(offset + 8..offset + 16)?;
+                let size = u64::from_be_bytes(large.try_into().ok()?) as usize;
+                (offset + 16, offset.checked_add(size)?)
+            },
+            size => (offset + 8, offset.checked_add(size)?),
+        };
+        if end > data.len() || end < content {
+            return None;
+        }
+        let found = BoxRef { kind, start: offset, content, end };
+        offset = end;
+        Some(found)
+    })
+}
+
+/// Finds a box by path, e.g. `[b"moov", b"trak", b"mdia"]`, offsets are relative to `data`
+pub fn find_path(data: &[u8], path: &[&[u8; 4]]) -> Option<BoxRef> {
+    let (first, rest) = path.split_first()?;
+    let found = boxes(data).find(|b| &b.kind == *first)?;
+    if rest.is_empty() {
+        return Some(found);
+    }
+    let child = find_path(&data[found.content..found.end], rest)?;
+    Some(BoxRef {
+        kind: child.kind,
+        start: child.start + found.content,
+        content: child.content + found.content,
+        end: child.end + found.content,
+    })
+}
+
+fn write_box(kind: &[u8; 4], content: &[u8]) -> Vec<u8> {
+    let mut data = Vec::with_capacity(content.len() + 8);
+    data.extend(((content.len() + 8) as u32).to_be_bytes());
+    data.extend(kind);
+    data.extend(content);
+    data
+}
+
+fn read_u32(data: &[u8], offset: usize) -> Option<u32> {
+    Some(u32::from_be_bytes(data.get(offset..offset + 4)?.try_into().ok()?))
+}
+
+fn read_u64(data: &[u8], offset: usize) -> Option<u64> {
+    Some(u64::from_be_bytes(data.get(offset..offset + 8)?.try_into().ok()?))
+}
+
+/// Track defaults needed to interpret fragments
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TrackInfo {
+    pub timescale: u32,
+    pub default_sample_duration: u32,
+    pub default_sample_flags: u32,
+}
+
+/// Reads the track info of the first track from an init segment (ftyp + moov)
+pub fn parse_init(init: &[u8]) -> Option<TrackInfo> {
+    let mdhd = find_path(init, &[b"moov", b"trak", b"mdia", b"mdhd"])?;
+    let timescale = match init.get(mdhd.content)? {
+        1 => read_u32(init, mdhd.content + 20)?,
+        _ => read_u32(init, mdhd.content + 12)?,
+    };
+
+    // trex: version/flags, track id, sample description index, duration, size, flags
+    let trex = find_path(init, &[b"moov", b"mvex", b"trex"]);
+    let default_sample_duration = trex.and_then(|t| read_u32(init, t.content + 12)).unwrap_or_default();
+    let default_sample_flags = trex.and_then(|t| read_u32(init, t.content + 20)).unwrap_or_default();
+
+    Some(TrackInfo {
+        timescale,
+        default_sample_duration,
+        default_sample_flags,
+    })
+}
+
+/// Sample description (stsd) of the first track, holds the codec configuration like avcC with SPS and PPS.
+/// Fragments can only be played behind an init segment with the same one.
+pub fn sample_description(init: &[u8]) -> Option<&[u8]> {
+    let stsd = find_path(init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsd"])?;
+    init.get(stsd.content..stsd.end)
+}
+
+/// Timing of a single moof + mdat pair, in track timescale
+#[derive(Debug, Clone, Copy)]
+pub struct FragmentInfo {
+    pub decode_time: u64,
+    pub duration: u64,
+    /// First sample is a keyframe
+    pub sync: bool,
+}
+
+/// Parses the first traf of a complete moof box
+pub fn parse_moof(moof: &[u8], track: &TrackInfo) -> Option<FragmentInfo> {
+    let tfhd = find_path(moof, &[b"moof", b"traf", b"tfhd"])?;
+    let tfhd_flags = read_u32(moof, tfhd.content)? & 0x00ff_ffff;
+    // Optional fields follow version/flags and track id
+    let mut offset = tfhd.content + 8;
+    if tfhd_flags & 0x01 != 0 {
+        offset += 8;
+    }
+    if tfhd_flags & 0x02 != 0 {
+        offset += 4;
+    }
+    let mut default_duration = track.default_sample_duration;
+    if tfhd_flags & 0x08 != 0 {
+        default_duration = read_u32(moof, offset)?;
+        offset += 4;
+    }
+    if tfhd_flags & 0x10 != 0 {
+        offset += 4;
+    }
+    let mut default_flags = track.default_sample_flags;
+    if tfhd_flags & 0x20 != 0 {
+        default_flags = read_u32(moof, offset)?;
+    }
+
+    let tfdt = find_path(moof, &[b"moof", b"traf", b"tfdt"])?;
+    let decode_time = match moof.get(tfdt.content)? {
+        1 => read_u64(moof, tfdt.content + 4)?,
+        _ => read_u32(moof, tfdt.content + 4)? as u64,
+    };
+
+    let trun = find_path(moof, &[b"moof", b"traf", b"trun"])?;
+    let trun_flags = read_u32(moof, trun.content)? & 0x00ff_ffff;
+    let sample_count = read_u32(moof, trun.content + 4)?;
+    let mut offset = trun.content + 8;
+    if trun_flags & 0x01 != 0 {
+        offset += 4;
+    }
+    let mut first_flags = None;
+    if trun_flags & 0x04 != 0 {
+        first_flags = Some(read_u32(moof, offset)?);
+        offset += 4;
+    }
+
+    let mut duration = 0u64;
+    let mut sample_flags = None;
+    for sample in 0..sample_count {
+        if trun_flags & 0x100 != 0 {
+            duration += read_u32(moof, offset)? as u64;
+            offset += 4;
+        } else {
+            duration += default_duration as u64;
+        }
+        if trun_flags & 0x200 != 0 {
+            offset += 4;
+        }
+        if trun_flags & 0x400 != 0 {
+            if sample == 0 {
+                sample_flags = Some(read_u32(moof, offset)?);
+            }
+            offset += 4;
+        }
+        if trun_flags & 0x800 != 0 {
+            offset += 4;
+        }
+    }
+
+    let flags = first_flags.or(sample_flags).unwrap_or(default_flags);
+    Some(FragmentInfo {
+        decode_time,
+        duration,
+        sync: flags & SAMPLE_IS_NON_SYNC == 0,
+    })
+}
+
+/// Rewrites tfdt, a version 0 box is widened to version 1 when the value needs 64 bits.
+/// Returns false if the moof can't hold the value.
+pub fn set_decode_time(moof: &mut Vec<u8>, decode_time: u64) -> bool {
+    let Some(tfdt) = find_path(moof, &[b"moof", b"traf", b"tfdt"]) else {
+        return false;
+    };
+    match moof.get(tfdt.content) {
+        Some(1) if tfdt.end >= tfdt.content + 12 => {
+            moof[tfdt.content + 4..tfdt.content + 12].copy_from_slice(&decode_time.to_be_bytes());
+            true
+        },
+        Some(0) if tfdt.end >= tfdt.content + 8 => match u32::try_from(decode_time) {
+            Ok(time) => {
+                moof[tfdt.content + 4..tfdt.content + 8].copy_from_slice(&time.to_be_bytes());
+                true
+            },
+            Err(_) => widen_tfdt(moof, tfdt, decode_time),
+        },
+        _ => false,
+    }
+}
+
+/// Turns a version 0 tfdt into version 1, the boxes around it and trun data offsets grow by 4 bytes
+fn widen_tfdt(moof: &mut Vec<u8>, tfdt: BoxRef, decode_time: u64) -> bool {
+    let (Some(outer), Some(traf)) = (find_path(moof, &[b"moof"]), find_path(moof, &[b"moof", b"traf"])) else {
+        return false;
+    };
+    // Only boxes with a 32 bit size are grown
+    let growable = |b: &BoxRef| b.content == b.start + 8
+        && read_u32(moof, b.start).map(|size| size as usize) == Some(b.end - b.start);
+    if ![outer, traf, tfdt].iter().all(growable) || outer.end - outer.start + 4 > u32::MAX as usize {
+        return false;
+    }
+
+    // Data offsets are relative to the moof unless tfhd sets an explicit base offset
+    let traf_content = traf.content..traf.end;
+    let explicit_base = find_path(&moof[traf_content.clone()], &[b"tfhd"])
+        .and_then(|tfhd| read_u32(moof, traf.content + tfhd.content))
+        .map(|flags| flags & 0x01 != 0)
+        .unwrap_or(false);
+    if !explicit_base {
+        let truns: Vec<BoxRef> = boxes(&moof[traf_content]).filter(|b| &b.kind == b"trun").collect();
+        for trun in truns {
+            let flags = traf.content + trun.content;
+            if read_u32(moof, flags).map(|f| f & 0x01 != 0) == Some(true) {
+                let Some(offset) = read_u32(moof, flags + 8) else {
+                    return false;
+                };
+                moof[flags + 8..flags + 12].copy_from_slice(&offset.wrapping_add(4).to_be_bytes());
+            }
+        }
+    }
+
+    for grown in [outer, traf, tfdt] {
+        let size = (grown.end - grown.start + 4) as u32;
+        moof[grown.start..grown.start + 4].copy_from_slice(&size.to_be_bytes());
+    }
+    moof[tfdt.content] = 1;
+    moof.splice(tfdt.content + 4..tfdt.content + 8, decode_time.to_be_bytes());
+    true
+}
+
+/// Rewrites the mfhd sequence number in place
+pub fn set_sequence_number(moof: &mut [u8], sequence: u32) {
+    if let Some(mfhd) = find_path(moof, &[b"moof", b"mfhd"]) {
+        if mfhd.end >= mfhd.content + 8 {
+            moof[mfhd.content + 4..mfhd.content + 8].copy_from_slice(&sequence.to_be_bytes());
+        }
+    }
+}
+
+/// Where the moof of a fragment comes from
+#[derive(Debug, Clone)]
+pub enum Moof {
+    /// moof box stored in the file
+    File(Range<u64>),
+    /// Generated for a progressive file, followed by the mdat header
+    Generated(Vec<u8>),
+}
+
+/// Fragment of a recording file, ranges are byte offsets in the file
+#[derive(Debug, Clone)]
+pub struct Fragment {
+    pub moof: Moof,
+    /// Whole mdat box, or only the sample data for generated moofs
+    pub mdat: Range<u64>,
+    pub info: FragmentInfo,
+}
+
+impl Fragment {
+    /// Length of the fragment when streamed
+    pub fn stream_len(&self) -> u64 {
+        let moof = match &self.moof {
+            Moof::File(range) => range.end - range.start,
+            Moof::Generated(data) => data.len() as u64,
+        };
+        moof + self.mdat.end - self.mdat.start
+    }
+}
+
+/// Layout of a fragmented mp4 recording
+#[derive(Debug, Clone)]
+pub struct RecordingFile {
+    /// ftyp and moov boxes
+    pub init: Vec<u8>,
+    pub track: TrackInfo,
+    pub fragments: Vec<Fragment>,
+}
+
+impl RecordingFile {
+    /// Length up to the end of the last complete moof + mdat pair
+    pub fn complete_length(&self) -> u64 {
+        self.fragments.last()
+            .map(|f| f.mdat.end)
+            .unwrap_or(self.init.len() as u64)
+    }
+
+    pub fn duration_millis(&self) -> i64 {
+        let (Some(first), Some(last)) = (self.fragments.first(), self.fragments.last()) else {
+            return 0;
+        };
+        if self.track.timescale == 0 {
+            return 0;
+        }
+        let duration = (last.info.decode_time + last.info.duration).saturating_sub(first.info.decode_time);
+        (duration as i128 * 1000 / self.track.timescale as i128) as i64
+    }
+}
+
+/// Reads the top level box header at `offset`, returns the kind and the box range
+async fn read_box_header(file: &mut File, offset: u64, len: u64) -> std::io::Result<Option<([u8; 4], Range<u64>)>> {
+    if offset + 8 > len {
+        return Ok(None);
+    }
+    let mut header = [0u8; 16];
+    file.seek(SeekFrom::Start(offset)).await?;
+    file.read_exact(&mut header[..8]).await?;
+    let kind = [header[4], header[5], header[6], header[7]];
+    let size = match u32::from_be_bytes([header[0], header[1], header[2], header[3]]) as u64 {
+        0 => len - offset,
+        1 => {
+            if offset + 16 > len {
+                return Ok(None);
+            }
+            file.read_exact(&mut header[8..16]).await?;
+            u64::from_be_bytes([header[8], header[9], header[10], header[11], header[12], header[13], header[14], header[15]])
+        },
+        size => size,
+    };
+    if size < 8 || offset + size > len {
+        // Box cut short, e.g. by a crash while writing
+        return Ok(None);
+    }
+    Ok(Some((kind, offset..offset + size)))
+}
+
+async fn read_range(file: &mut File, range: &Range<u64>) -> std::io::Result<Vec<u8>> {
+    let mut data = vec![0u8; (range.end - range.start) as usize];
+    file.seek(SeekFrom::Start(range.start)).await?;
+    file.read_exact(&mut data).await?;
+    Ok(data)
+}
+
+/// Reads the init segment and fragment layout of a recording without loading media data
+pub async fn scan(path: &Path) -> std::io::Result<RecordingFile> {
+    let mut file = File::open(path).await?;
+    let len = file.metadata().await?.len();
+
+    let mut init = Vec::new();
+    let mut track = None;
+    let mut fragments = Vec::new();
+    let mut pending_moof: Option<(Range<u64>, FragmentInfo)> = None;
+    let mut offset = 0;
+
+    while let Some((kind, range)) = read_box_header(&mut file, offset, len).await? {
+        offset = range.end;
+        match &kind {
+            b"ftyp" | b"moov" if track.is_none() => {
+                init.extend(read_range(&mut file, &range).await?);
+                if &kind == b"moov" {
+                    track = parse_init(&init);
+                }
+            },
+            b"moof" => {
+                let Some(track) = track.as_ref() else {
+                    continue;
+                };
+                let moof = read_range(&mut file, &range).await?;
+                pending_moof = parse_moof(&moof, track).map(|info| (range, info));
+            },
+            b"mdat" => {
+                if let Some((moof, info)) = pending_moof.take() {
+                    fragments.push(Fragment { moof: Moof::File(moof), mdat: range, info });
+                }
+            },
+            _ => {}
+        }
+    }
+
+    let track = track.ok_or_else(|| std::io::Error::new(std::io::ErrorKind::InvalidData, "recording has no init segment"))?;
+    // Remuxed recordings carry their samples in the moov instead of fragments
+    if fragments.is_empty() {
+        if let Some(progressive) = progressive_layout(&init) {
+            return Ok(progressive);
+        }
+    }
+    Ok(RecordingFile { init, track, fragments })
+}
+
+/// Reads the moof box of a fragment, generated moofs include the mdat header
+pub async fn read_moof(file: &mut File, fragment: &Fragment) -> std::io::Result<Vec<u8>> {
+    match &fragment.moof {
+        Moof::File(range) => read_range(file, range).await,
+        Moof::Generated(data) => Ok(data.clone()),
+    }
+}
+
+/// Sample of a progressive mp4
+#[derive(Debug, Clone, Copy)]
+struct Sample {
+    offset: u64,
+    size: u32,
+    duration: u32,
+    composition_offset: u32,
+    sync: bool,
+}
+
+fn table<'a>(stbl: &'a [u8], kind: &[u8; 4]) -> Option<&'a [u8]> {
+    let found = find_path(stbl, &[kind])?;
+    Some(&stbl[found.content..found.end])
+}
+
+/// Expands run length encoded (count, value) entries to one value per sample
+fn expand_runs(table: &[u8], count: usize) -> Option<Vec<u32>> {
+    let mut values = Vec::with_capacity(count);
+    for entry in 0..read_u32(table, 4)? as usize {
+        let run = read_u32(table, 8 + entry * 8)? as usize;
+        let value = read_u32(table, 12 + entry * 8)?;
+        values.extend(std::iter::repeat_n(value, run.min(count - values.len())));
+    }
+    Some(values)
+}
+
+/// Resolves the sample table of a progressive mp4's first track
+fn parse_samples(stbl: &[u8]) -> Option<Vec<Sample>> {
+    let stsz = table(stbl, b"stsz")?;
+    let fixed_size = read_u32(stsz, 4)?;
+    let count = read_u32(stsz, 8)? as usize;
+    if count > MAX_SAMPLES {
+        return None;
+    }
+    let sizes: Vec<u32> = match fixed_size {
+        0 => (0..count).map(|i| read_u32(stsz, 12 + i * 4)).collect::<Option<_>>()?,
+        size => vec![size; count],
+    };
+
+    let durations = expand_runs(table(stbl, b"stts")?, count)?;
+    let composition_offsets = match table(stbl, b"ctts") {
+        Some(ctts) => expand_runs(ctts, count)?,
+        None => vec![0; count],
+    };
+    let mut sync = vec![false; count];
+    match table(stbl, b"stss") {
+        Some(stss) => {
+            for entry in 0..read_u32(stss, 4)? as usize {
+                let sample = read_u32(stss, 8 + entry * 4)? as usize;
+                if let Some(flag) = sample.checked_sub(1).and_then(|s| sync.get_mut(s)) {
+                    *flag = true;
+                }
+            }
+        },
+        // Every sample is a sync sample without stss
+        None => sync.fill(true),
+    }
+
+    let chunks: Vec<u64> = match (table(stbl, b"stco"), table(stbl, b"co64")) {
+        (Some(stco), _) => (0..read_u32(stco, 4)? as usize).map(|i| read_u32(stco, 8 + i * 4).map(u64::from)).collect::<Option<_>>()?,
+        (None, Some(co64)) => (0..read_u32(co64, 4)? as usize).map(|i| read_u64(co64, 8 + i * 8)).collect::<Option<_>>()?,
+        (None, None) => return None,
+    };
+    let stsc = table(stbl, b"stsc")?;
+    let sample_to_chunk: Vec<(u32, u32)> = (0..read_u32(stsc, 4)? as usize)
+        .map(|i| Some((read_u32(stsc, 8 + i * 12)?, read_u32(stsc, 12 + i * 12)?)))
+        .collect::<Option<_>>()?;
+
+    let mut offsets = Vec::with_capacity(count);
+    for (index, chunk_offset) in chunks.iter().enumerate() {
+        let chunk = index as u32 + 1;
+        let per_chunk = sample_to_chunk.iter()
+            .rev()
+            .find(|(first_chunk, _)| *first_chunk <= chunk)
+            .map(|(_, samples)| *samples)
+            .unwrap_or_default();
+        let mut offset = *chunk_offset;
+        for _ in 0..per_chunk {
+            let Some(size) = sizes.get(offsets.len()) else {
+                break;
+            };
+            offsets.push(offset);
+            offset += *size as u64;
+        }
+    }
+    if offsets.len() != count || durations.len() != count || composition_offsets.len() != count {
+        return None;
+    }
+
+    Some((0..count)
+        .map(|i| Sample {
+            offset: offsets[i],
+            size: sizes[i],
+            duration: durations[i],
+            composition_offset: composition_offsets[i],
+            sync: sync[i],
+        })
+        .collect())
+}
+
+/// Builds a moof for contiguous samples, followed by the header of the mdat holding them
+fn build_moof(samples: &[Sample], decode_time: u64, sequence: u32, track_id: u32) -> Vec<u8> {
+    let mfhd = write_box(b"mfhd", &[[0u8; 4], sequence.to_be_bytes()].concat());
+    let tfhd = write_box(b"tfhd", &[TFHD_DEFAULT_BASE_IS_MOOF.to_be_bytes(), track_id.to_be_bytes()].concat());
+    let tfdt = write_box(b"tfdt", &[&0x0100_0000u32.to_be_bytes()[..], &decode_time.to_be_bytes()].concat());
+
+    // Sizes are fixed, so the data offset is known before the trun is written
+    let trun_len = 20 + samples.len() * 16;
+    let moof_len = 8 + mfhd.len() + 8 + tfhd.len() + tfdt.len() + trun_len;
+    let mut trun = Vec::with_capacity(trun_len - 8);
+    trun.extend((0x0100_0000 | TRUN_FLAGS).to_be_bytes());
+    trun.extend((samples.len() as u32).to_be_bytes());
+    trun.extend(((moof_len + 8) as u32).to_be_bytes());
+    for sample in samples {
+        let flags = if sample.sync { SYNC_SAMPLE_FLAGS } else { NON_SYNC_SAMPLE_FLAGS };
+        trun.extend(sample.duration.to_be_bytes());
+        trun.extend(sample.size.to_be_bytes());
+        trun.extend(flags.to_be_bytes());
+        trun.extend(sample.composition_offset.to_be_bytes());
+    }
+
+    let traf = write_box(b"traf", &[tfhd, tfdt, write_box(b"trun", &trun)].concat());
+    let mut moof = write_box(b"moof", &[mfhd, traf].concat());
+    let data_len: u64 = samples.iter().map(|s| s.size as u64).sum();
+    moof.extend(((data_len + 8) as u32).to_be_bytes());
+    moof.extend(b"mdat");
+    moof
+}
+
+/// Copies boxes with the sample tables emptied, an init segment must not describe samples
+fn strip_sample_tables(data: &[u8]) -> Vec<u8> {
+    let mut stripped = Vec::with_capacity(data.len());
+    for found in boxes(data) {
+        let content = &data[found.content..found.end];
+        match &found.kind {
+            b"moov" | b"trak" | b"mdia" | b"minf" | b"stbl" => stripped.extend(write_box(&found.kind, &strip_sample_tables(content))),
+            b"stts" | b"stsc" | b"stco" | b"ctts" => stripped.extend(write_box(&found.kind, &[0u8; 8])),
+            b"co64" => stripped.extend(write_box(b"stco", &[0u8; 8])),
+            b"stsz" => stripped.extend(write_box(b"stsz", &[0u8; 12])),
+            b"stss" => {},
+            _ => stripped.extend(&data[found.start..found.end]),
+        }
+    }
+    stripped
+}
+
+/// Describes a progressive mp4 as fragments of one keyframe interval each, so it streams like a recording
+fn progressive_layout(file_init: &[u8]) -> Option<RecordingFile> {
+    let stbl = find_path(file_init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl"])?;
+    let samples = parse_samples(&file_init[stbl.content..stbl.end])?;
+    let tkhd = find_path(file_init, &[b"moov", b"trak", b"tkhd"])?;
+    let track_id = match file_init.get(tkhd.content)? {
+        1 => read_u32(file_init, tkhd.content + 20)?,
+        _ => read_u32(file_init, tkhd.content + 12)?,
+    };
+
+    let ftyp = find_path(file_init, &[b"ftyp"]);
+    let moov = find_path(file_init, &[b"moov"])?;
+    let mut init = ftyp.map(|f| file_init[f.start..f.end].to_vec()).unwrap_or_default();
+    // trex: version/flags, track id, sample description index and empty defaults
+    let trex = write_box(b"trex", &[[0u8; 4], track_id.to_be_bytes(), 1u32.to_be_bytes(), [0; 4], [0; 4], [0; 4]].concat());
+    let moov_content = [
+        strip_sample_tables(&file_init[moov.content..moov.end]),
+        write_box(b"mvex", &trex),
+    ].concat();
+    init.extend(write_box(b"moov", &moov_content));
+    let track = parse_init(&init)?;
+
+    // Files with only sync samples are grouped into fragments of about a second instead
+    let all_sync = samples.iter().all(|s| s.sync);
+    let mut fragments = Vec::new();
+    let mut decode_time = 0u64;
+    let mut start = 0;
+    for end in 1..=samples.len() {
+        let duration: u64 = samples[start..end].iter().map(|s| s.duration as u64).sum();
+        let last = &samples[end - 1];
+        // Fragments end before keyframes and wherever the sample data isn't contiguous
+        let split = match samples.get(end) {
+            Some(next) => next.offset != last.offset + last.size as u64
+                || (next.sync && (!all_sync || duration >= track.timescale as u64)),
+            None => true,
+        };
+        if !split {
+            continue;
+        }
+        let group = &samples[start..end];
+        fragments.push(Fragment {
+            moof: Moof::Generated(build_moof(group, decode_time, fragments.len() as u32 + 1, track_id)),
+            mdat: group[0].offset..last.offset + last.size as u64,
+            info: FragmentInfo {
+                decode_time,
+                duration,
+                sync: group[0].sync,
+            },
+        });
+        decode_time += duration;
+        start = end;
+    }
+
+    Some(RecordingFile { init, track, fragments })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TIMESCALE: u32 = 90_000;
+    const SAMPLE_DURATION: u32 = 3_000;
+    const TRACK_ID: u32 = 1;
+    const CHUNK_GAP: u32 = 4;
+
+    fn full_box(kind: &[u8; 4], version: u8, content: &[u8]) -> Vec<u8> {
+        write_box(kind, &[&[version, 0, 0, 0][..], content].concat())
+    }
+
+    fn u32s(values: &[u32]) -> Vec<u8> {
+        values.iter().flat_map(|v| v.to_be_bytes()).collect()
+    }
+
+    /// ftyp + moov with a single video track, `stbl` holds the sample tables and `mvex` is added for fragmented files
+    fn init_segment(stbl: &[u8], fragmented: bool) -> Vec<u8> {
+        let tkhd = full_box(b"tkhd", 0, &u32s(&[0, 0, TRACK_ID, 0, 0]));
+        let mdhd = full_box(b"mdhd", 0, &u32s(&[0, 0, TIMESCALE, 0, 0]));
+        let stsd = full_box(b"stsd", 0, &u32s(&[0]));
+        let stbl = write_box(b"stbl", &[stsd, stbl.to_vec()].concat());
+        let mdia = write_box(b"mdia", &[mdhd, write_box(b"minf", &stbl)].concat());
+        let trak = write_box(b"trak", &[tkhd, mdia].concat());
+        let mut moov = trak;
+        if fragmented {
+            let trex = full_box(b"trex", 0, &u32s(&[TRACK_ID, 1, SAMPLE_DURATION, 0, SAMPLE_IS_NON_SYNC]));
+            moov.extend(write_box(b"mvex", &trex));
+        }
+        [write_box(b"ftyp", b"isom\0\0\0\0"), write_box(b"moov", &moov)].concat()
+    }
+
+    /// moof with a version 0 tfdt and a trun of `samples` samples of 4 bytes, the first one a keyframe if `sync`
+    fn moof(sequence: u32, decode_time: u32, samples: u32, sync: bool) -> Vec<u8> {
+        let mfhd = full_box(b"mfhd", 0, &u32s(&[sequence]));
+        let tfhd = write_box(b"tfhd", &u32s(&[TFHD_DEFAULT_BASE_IS_MOOF, TRACK_ID]));
+        let tfdt = full_box(b"tfdt", 0, &u32s(&[decode_time]));
+        let first_flags = if sync { 0 } else { SAMPLE_IS_NON_SYNC };
+        // data offset, first sample flags and per sample sizes
+        let trun_len = 8 + 16 + samples as usize * 4;
+        let moof_len = 8 + mfhd.len() + 8 + tfhd.len() + tfdt.len() + trun_len;
+        let mut trun = u32s(&[0x0000_0205, samples, moof_len as u32 + 8, first_flags]);
+        trun.extend(u32s(&vec![4; samples as usize]));
+        let traf = write_box(b"traf", &[tfhd, tfdt, write_box(b"trun", &trun)].concat());
+        write_box(b"moof", &[mfhd, traf].concat())
+    }
+
+    fn fragment(sequence: u32, decode_time: u32, samples: u32, sync: bool) -> Vec<u8> {
+        let data: Vec<u8> = (0..samples * 4).map(|i| (sequence + i) as u8).collect();
+        [moof(sequence, decode_time, samples, sync), write_box(b"mdat", &data)].concat()
+    }
+
+    fn track() -> TrackInfo {
+        parse_init(&init_segment(&[], true)).unwrap()
+    }
+
+    struct TempFile(std::path::PathBuf);
+
+    impl TempFile {
+        fn new(name: &str, data: &[u8]) -> Self {
+            let path = std::env::temp_dir().join(format!("picam-mp4-{}-{name}.mp4", std::process::id()));
+            std::fs::write(&path, data).unwrap();
+            Self(path)
+        }
+    }
+
+    impl Drop for TempFile {
+        fn drop(&mut self) {
+            let _ = std::fs::remove_file(&self.0);
+        }
+    }
+
+    #[test]
+    fn parses_init_and_moof() {
+        let track = track();
+        assert_eq!(track.timescale, TIMESCALE);
+        assert_eq!(track.default_sample_duration, SAMPLE_DURATION);
+
+        let info = parse_moof(&moof(1, 6_000, 5, true), &track).unwrap();
+        assert_eq!(info.decode_time, 6_000);
+        assert_eq!(info.duration, 5 * SAMPLE_DURATION as u64);
+        assert!(info.sync);
+        assert!(!parse_moof(&moof(2, 0, 5, false), &track).unwrap().sync);
+    }
+
+    #[test]
+    fn boxes_stop_at_truncated_box() {
+        let data = fragment(1, 0, 3, true);
+        assert_eq!(boxes(&data).count(), 2);
+        assert_eq!(boxes(&data[..data.len() - 1]).count(), 1);
+        // Header cut in the middle
+        let moof_len = moof(1, 0, 3, true).len();
+        assert_eq!(boxes(&data[..moof_len + 4]).count(), 1);
+    }
+
+    #[test]
+    fn boxes_read_64_bit_sizes() {
+        let mut data = vec![0, 0, 0, 1];
+        data.extend(b"mdat");
+        data.extend(20u64.to_be_bytes());
+        data.extend([7u8; 4]);
+        let found: Vec<BoxRef> = boxes(&data).collect();
+        assert_eq!(found.len(), 1);
+        assert_eq!((found[0].content, found[0].end), (16, 20));
+        assert_eq!(boxes(&data[..19]).count(), 0);
+    }
+
+    #[tokio::test]
+    async fn scan_stops_at_partial_fragment() {
+        let complete = [init_segment(&[], true), fragment(1, 0, 3, true), fragment(2, 9_000, 3, false)].concat();
+        let partial = fragment(3, 18_000, 3, true);
+
+        for cut in [4, 8, partial.len() - 1] {
+            let file = TempFile::new(&format!("partial-{cut}"), &[&complete[..], &partial[..cut]].concat());
+            let scanned = scan(&file.0).await.unwrap();
+            assert_eq!(scanned.fragments.len(), 2, "cut after {cut} bytes");
+            assert_eq!(scanned.complete_length(), complete.len() as u64);
+            assert_eq!(scanned.duration_millis(), 6 * SAMPLE_DURATION as i64 * 1000 / TIMESCALE as i64);
+        }
+    }
+
+    #[tokio::test]
+    async fn scan_reads_64_bit_mdat() {
+        let moof = moof(1, 0, 2, true);
+        let mut mdat = vec![0, 0, 0, 1];
+        mdat.extend(b"mdat");
+        mdat.extend(24u64.to_be_bytes());
+        mdat.extend([1u8; 8]);
+        let data = [init_segment(&[], true), moof.clone(), mdat.clone()].concat();
+
+        let file = TempFile::new("large-mdat", &data);
+        let scanned = scan(&file.0).await.unwrap();
+        assert_eq!(scanned.fragments.len(), 1);
+        assert_eq!(scanned.fragments[0].mdat.end, data.len() as u64);
+        assert_eq!(scanned.fragments[0].stream_len(), (moof.len() + mdat.len()) as u64);
+
+        // A 64 bit size pointing past the end of the file is a truncated box
+        let file = TempFile::new("large-mdat-truncated", &data[..data.len() - 1]);
+        assert!(scan(&file.0).await.unwrap().fragments.is_empty());
+    }
+
+    #[tokio::test]
+    async fn scan_without_init_fails() {
+        let file = TempFile::new("no-init", &fragment(1, 0, 3, true));
+        assert!(scan(&file.0).await.is_err());
+    }
+
+    #[test]
+    fn rewrites_version_0_decode_time() {
+        let mut moof = moof(1, 0, 3, true);
+        let len = moof.len();
+        assert!(set_decode_time(&mut moof, 123_456));
+        assert_eq!(moof.len(), len);
+        assert_eq!(parse_moof(&moof, &track()).unwrap().decode_time, 123_456);
+    }
+
+    #[test]
+    fn widens_version_0_decode_time() {
+        let mut data = fragment(1, 0, 3, true);
+        let moof_len = moof(1, 0, 3, true).len();
+        let decode_time = u32::MAX as u64 + 10;
+        let mut moof = data[..moof_len].to_vec();
+        assert!(set_decode_time(&mut moof, decode_time));
+        assert_eq!(moof.len(), moof_len + 4);
+
+        let tfdt = find_path(&moof, &[b"moof", b"traf", b"tfdt"]).unwrap();
+        assert_eq!(moof[tfdt.content], 1);
+        assert_eq!(boxes(&moof).next().unwrap().end, moof.len());
+        let info = parse_moof(&moof, &track()).unwrap();
+        assert_eq!(info.decode_time, decode_time);
+        assert_eq!(info.duration, 3 * SAMPLE_DURATION as u64);
+
+        // The data offset still points at the first sample behind the mdat header
+        let trun = find_path(&moof, &[b"moof", b"traf", b"trun"]).unwrap();
+        assert_eq!(read_u32(&moof, trun.content + 8), Some(moof.len() as u32 + 8));
+
+        data.splice(..moof_len, moof);
+        assert_eq!(boxes(&data).count(), 2);
+    }
+
+    #[test]
+    fn sets_sequence_number() {
+        let mut moof = moof(1, 0, 3, true);
+        set_sequence_number(&mut moof, 42);
+        let mfhd = find_path(&moof, &[b"moof", b"mfhd"]).unwrap();
+        assert_eq!(read_u32(&moof, mfhd.content + 4), Some(42));
+    }
+
+    /// Progressive mp4 with `samples` samples of increasing size, keyframes every `gop` samples and two chunks with a gap between them
+    fn progressive(samples: u32, gop: u32) -> (Vec<u8>, Vec<Vec<u8>>) {
+        let sizes: Vec<u32> = (0..samples).map(|i| 10 + i).collect();
+        let data: Vec<Vec<u8>> = sizes.iter().enumerate().map(|(i, s)| vec![i as u8; *s as usize]).collect();
+        let first_chunk = samples / 2;
+
+        let build = |mdat_offset: u32| {
+            let stts = full_box(b"stts", 0, &u32s(&[1, samples, SAMPLE_DURATION]));
+            let keyframes: Vec<u32> = (0..samples).step_by(gop as usize).map(|i| i + 1).collect();
+            let stss = full_box(b"stss", 0, &[u32s(&[keyframes.len() as u32]), u32s(&keyframes)].concat());
+            let stsz = full_box(b"stsz", 0, &[u32s(&[0, samples]), u32s(&sizes)].concat());
+            let stsc = full_box(b"stsc", 0, &u32s(&[2, 1, first_chunk, 1, 2, samples - first_chunk, 1]));
+            let second_chunk = mdat_offset + 8 + sizes[..first_chunk as usize].iter().sum::<u32>() + CHUNK_GAP;
+            let stco = full_box(b"stco", 0, &u32s(&[2, mdat_offset + 8, second_chunk]));
+            init_segment(&[stts, stss, stsz, stsc, stco].concat(), false)
+        };
+        // The moov size doesn't depend on the offsets, build once to learn where the mdat starts
+        let init = build(build(0).len() as u32);
+        let mdat = [data[..first_chunk as usize].concat(), vec![0xff; CHUNK_GAP as usize], data[first_chunk as usize..].concat()].concat();
+        let file = [init, write_box(b"mdat", &mdat)].concat();
+        (file, data)
+    }
+
+    #[tokio::test]
+    async fn progressive_layout_round_trips() {
+        let (file_data, samples) = progressive(10, 4);
+        let file = TempFile::new("progressive", &file_data);
+        let scanned = scan(&file.0).await.unwrap();
+
+        // The init segment describes no samples and can be played with fragments
+        assert!(find_path(&scanned.init, &[b"moov", b"mvex", b"trex"]).is_some());
+        assert!(find_path(&scanned.init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stss"]).is_none());
+        let stsz = find_path(&scanned.init, &[b"moov", b"trak", b"mdia", b"minf", b"stbl", b"stsz"]).unwrap();
+        assert_eq!(read_u32(&scanned.init, stsz.content + 8), Some(0));
+        assert_eq!(scanned.track.timescale, TIMESCALE);
+
+        // Fragments start at the keyframes 0, 4 and 8, the gap between the chunks after sample 4 splits as well
+        let sizes: Vec<usize> = scanned.fragments.iter()
+            .map(|f| (f.info.duration / SAMPLE_DURATION as u64) as usize)
+            .collect();
+        assert_eq!(sizes, vec![4, 1, 3, 2]);
+        let sync: Vec<bool> = scanned.fragments.iter().map(|f| f.info.sync).collect();
+        assert_eq!(sync, vec![true, true, false, true]);
+        assert_eq!(scanned.duration_millis(), 10 * SAMPLE_DURATION as i64 * 1000 / TIMESCALE as i64);
+
+        // Streamed like playback does, the result is a fragmented mp4 holding the same samples
+        let mut stream = scanned.init.clone();
+        let mut source = File::open(&file.0).await.unwrap();
+        for fragment in &scanned.fragments {
+            let moof = read_moof(&mut source, fragment).await.unwrap();
+            let info = parse_moof(&moof, &scanned.track).unwrap();
+            assert_eq!((info.decode_time, info.duration, info.sync), (fragment.info.decode_time, fragment.info.duration, fragment.info.sync));
+            stream.extend(moof);
+            stream.extend(&file_data[fragment.mdat.start as usize..fragment.mdat.end as usize]);
+        }
+        assert_eq!(stream.len() as u64, scanned.init.len() as u64 + scanned.fragments.iter().map(|f| f.stream_len()).sum::<u64>());
+
+        let streamed = TempFile::new("progressive-streamed", &stream);
+        let fragmented = scan(&streamed.0).await.unwrap();
+        assert_eq!(fragmented.fragments.len(), scanned.fragments.len());
+        let mut data: Vec<u8> = Vec::new();
+        for fragment in &fragmented.fragments {
+            let Moof::File(range) = &fragment.moof else {
+                panic!("Streamed file should hold its moofs");
+            };
+            let moof = &stream[range.start as usize..range.end as usize];
+            // trun data offsets point behind the mdat header of each fragment
+            let trun = find_path(moof, &[b"moof", b"traf", b"trun"]).unwrap();
+            assert_eq!(read_u32(moof, trun.content + 8), Some(moof.len() as u32 + 8));
+            data.extend(&stream[fragment.mdat.start as usize + 8..fragment.mdat.end as usize]);
+        }
+        assert_eq!(data, samples.concat());
+    }
+}
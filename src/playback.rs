@@ -0,0 +1,200 @@
+use std::{ops::Range, path::PathBuf};
+use log::*;
+use poem::Body;
+use tokio::{
+    fs::File,
+    io::{AsyncReadExt, AsyncSeekExt, AsyncWrite, AsyncWriteExt, SeekFrom},
+};
+use crate::{models::*, mp4::{self, RecordingFile, TrackInfo}, storage::Storage, video::InitSegment};
+
+/// Every recording of a playback keeps its parsed fragment index in memory while it streams
+const MAX_PLAYBACK_RECORDINGS: u32 = 10_000;
+const STREAM_BUFFER: usize = 64 * 1024;
+
+/// Fragments of a single recording used by a playback
+struct PlaybackPart {
+    path: PathBuf,
+    file: RecordingFile,
+    fragments: Range<usize>,
+}
+
+/// Recordings that can be stitched behind one init segment
+pub struct Playback {
+    pub segment: PlaybackSegment,
+    init: Vec<u8>,
+    /// Fragments rely on the trex defaults of the init segment they are played with
+    track: TrackInfo,
+    parts: Vec<PlaybackPart>,
+}
+
+/// Wall clock time of a decode time, relative to the start of the recording
+fn wall_clock(recording: &Recording, file: &RecordingFile, decode_time: u64) -> i64 {
+    let first = file.fragments.first().map(|f| f.info.decode_time).unwrap_or_default();
+    let elapsed = decode_time.saturating_sub(first) as i128 * 1000 / file.track.timescale as i128;
+    recording.start_time + elapsed as i64
+}
+
+/// Fragments of a single recording overlapping `from..to` (unix ms), cut at keyframes
+pub async fn plan_recording(app_data: &str, recording: &Recording, from: i64, to: i64) -> Option<Playback> {
+    let (segment, part) = plan_part(app_data, recording, from, to).await?;
+    Some(Playback {
+        segment,
+        init: part.file.init.clone(),
+        track: part.file.track,
+        parts: vec![part],
+    })
+}
+
+/// Starts at the keyframe at or before `from` and ends with the fragment covering `to`
+async fn plan_part(app_data: &str, recording: &Recording, from: i64, to: i64) -> Option<(PlaybackSegment, PlaybackPart)> {
+    let path = crate::file_sink::recording_path(app_data, recording);
+    let file = match mp4::scan(&path).await {
+        Ok(file) if file.track.timescale > 0 => file,
+        Ok(_) => {
+            warn!("Recording {} has no timescale, skipping it", recording.file_name);
+            return None;
+        },
+        Err(e) => {
+            warn!("Failed to read recording {} {e:?}", recording.file_name);
+            return None;
+        }
+    };
+
+    let times: Vec<(i64, i64)> = file.fragments.iter()
+        .map(|f| (
+            wall_clock(recording, &file, f.info.decode_time),
+            wall_clock(recording, &file, f.info.decode_time + f.info.duration),
+        ))
+        .collect();
+
+    let mut first = times.iter().position(|(_, end)| *end > from)?;
+    let last = times.iter().rposition(|(start, _)| *start < to).map(|l| l + 1).unwrap_or_default();
+
+    // Start at the keyframe nearest to `from`, the decoder can't start on a delta frame
+    while first > 0 && !file.fragments[first].info.sync {
+        first -= 1;
+    }
+    while first < last && !file.fragments[first].info.sync {
+        first += 1;
+    }
+    if first >= last {
+        return None;
+    }
+
+    let init = InitSegment::from_packets(vec![file.init.clone()]);
+    let segment = PlaybackSegment {
+        from: times[first].0,
+        to: times[last - 1].1,
+        codec: init.codec,
+        width: init.width.map(u32::from),
+        height: init.height.map(u32::from),
+    };
+    Some((segment, PlaybackPart {
+        path,
+        file,
+        fragments: first..last,
+    }))
+}
+
+/// Finds the fragments overlapping `from..to`, split into segments wherever the init segment changes
+pub async fn plan(storage: &Storage, app_data: &str, from: i64, to: i64) -> Vec<Playback> {
+    let recordings = storage.recordings.list_recordings(&RecordingQuery {
+        from: Some(from),
+        to: Some(to),
+        sort: RecordingSort::StartTime,
+        order: SortOrder::Asc,
+        limit: MAX_PLAYBACK_RECORDINGS,
+        ..Default::default()
+    }).await.items;
+
+    let mut playbacks: Vec<Playback> = Vec::new();
+    for recording in recordings {
+        let Some((segment, part)) = plan_part(app_data, &recording, from, to).await else {
+            continue;
+        };
+        let track = part.file.track;
+
+        // Same codec string and resolution can still mean different SPS/PPS, the whole stsd has to match
+        match playbacks.last_mut() {
+            Some(playback) if playback.track == track
+                && mp4::sample_description(&playback.init).is_some()
+                && mp4::sample_description(&playback.init) == mp4::sample_description(&part.file.init) => {
+                playback.segment.to = segment.to;
+                playback.parts.push(part);
+            },
+            _ => {
+                playbacks.push(Playback {
+                    segment,
+                    init: part.file.init.clone(),
+                    track,
+                    parts: vec![part],
+                });
+            }
+        }
+    }
+
+    playbacks
+}
+
+impl Playback {
+    /// Length of the stitched mp4 in bytes
+    pub fn size(&self) -> u64 {
+        self.init.len() as u64 + self.parts.iter()
+            .flat_map(|p| &p.file.fragments[p.fragments.clone()])
+            .map(|f| f.stream_len())
+            .sum::<u64>()
+    }
+
+    /// Streams the stitched fragmented mp4
+    pub fn into_body(self) -> Body {
+        let (mut writer, reader) = tokio::io::duplex(STREAM_BUFFER);
+        tokio::spawn(async move {
+            if let Err(e) = self.write_to(&mut writer, |_| {}).await {
+                debug!("Playback stream stopped {e:?}");
+            }
+        });
+        Body::from_async_read(reader)
+    }
+
+    /// Writes the stitched mp4, `progress` is called with the bytes written so far
+    pub async fn write_to<W: AsyncWrite + Unpin>(&self, out: &mut W, mut progress: impl FnMut(u64)) -> std::io::Result<()> {
+        out.write_all(&self.init).await?;
+        let mut written = self.init.len() as u64;
+
+        // Decode times restart with every pipeline, rebase them so they keep increasing
+        let mut base = 0u64;
+        let mut sequence = 1u32;
+        for part in &self.parts {
+            let fragments = &part.file.fragments[part.fragments.clone()];
+            let Some(first) = fragments.first() else {
+                continue;
+            };
+            let offset = first.info.decode_time;
+
+            let mut file = File::open(&part.path).await?;
+            for fragment in fragments {
+                let mut moof = mp4::read_moof(&mut file, fragment).await?;
+                let decode_time = base + fragment.info.decode_time.saturating_sub(offset);
+                if !mp4::set_decode_time(&mut moof, decode_time) {
+                    warn!("Decode time {decode_time} does not fit fragment of {:?}", part.path);
+                }
+                mp4::set_sequence_number(&mut moof, sequence);
+                sequence = sequence.wrapping_add(1);
+                out.write_all(&moof).await?;
+
+                file.seek(SeekFrom::Start(fragment.mdat.start)).await?;
+                let mut mdat = (&mut file).take(fragment.mdat.end - fragment.mdat.start);
+                tokio::io::copy(&mut mdat, out).await?;
+
+                written += fragment.stream_len();
+                progress(written);
+            }
+
+            if let Some(last) = fragments.last() {
+                base += (last.info.decode_time + last.info.duration).saturating_sub(offset);
+            }
+        }
+
+        out.shutdown().await
+    }
+}
@@ -0,0 +1,362 @@
+use std::sync::Arc;
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, NaiveTime, TimeDelta, Timelike, Utc};
+use log::*;
+
+use crate::{models::*, storage::Storage, video};
+
+const MAX_SLEEP: Duration = Duration::from_secs(60 * 60);
+const MINUTES_IN_WEEK: i64 = 7 * 24 * 60;
+const J2000: f64 = 2451545.0;
+const UNIX_EPOCH_JULIAN: f64 = 2440587.5;
+
+#[derive(Debug, PartialEq)]
+pub struct ScheduleState {
+    pub profile: Option<String>,
+    pub next_transition: Option<DateTime<Utc>>
+}
+
+enum Daylight {
+    Rises(DateTime<Utc>, DateTime<Utc>),
+    AlwaysUp,
+    AlwaysDown
+}
+
+pub async fn profile_scheduler(storage: Arc<Storage>) {
+    let mut schedule_change = storage.profile_schedule.subscribe().await;
+    let mut profile_change = storage.profiles.subscribe().await;
+    // Applying restarts the pipeline, skip it when the stored config already is the scheduled profile
+    let mut active = stored_profile(&storage).await;
+
+    loop {
+        let schedule = storage.profile_schedule.get().await;
+        let state = schedule_state(&schedule, Utc::now());
+
+        if let Some(name) = state.profile {
+            let profile = storage.profiles.get().await.into_iter().find(|p| p.name == name);
+            match profile {
+                // Edits of the active profile differ from what was applied and are applied again
+                Some(profile) if active.as_ref() != Some(&profile) => {
+                    apply_profile(&storage, &profile).await;
+                    active = Some(profile);
+                },
+                Some(_) => {},
+                None => warn!("Scheduled camera profile {name} does not exist"),
+            }
+        }
+
+        let wait = state.next_transition
+            .map(|t| (t - Utc::now()).to_std().unwrap_or_default() + Duration::from_secs(1))
+            .unwrap_or(MAX_SLEEP)
+            .min(MAX_SLEEP);
+
+        tokio::select! {
+            _ = tokio::time::sleep(wait) => {}
+            _ = schedule_change.recv() => {}
+            _ = profile_change.recv() => {}
+        }
+    }
+}
+
+/// Scheduled profile if the stored pipeline config and controls already match it
+async fn stored_profile(storage: &Storage) -> Option<CameraProfile> {
+    let schedule = storage.profile_schedule.get().await;
+    let name = schedule_state(&schedule, Utc::now()).profile?;
+    let profile = storage.profiles.get().await.into_iter().find(|p| p.name == name)?;
+    if storage.camera_config.get().await != profile.pipeline {
+        return None;
+    }
+
+    if !profile.controls.is_empty() {
+        let devices = storage.devices.devices().await;
+        let config = video::Config::find_optimal_settings(devices, profile.pipeline.clone());
+        let device = Device::find(devices, config.source())?;
+        let stored = storage.controls.get_controls(&device.id).await;
+        if !profile.controls.iter().all(|c| stored.contains(c)) {
+            return None;
+        }
+    }
+    Some(profile)
+}
+
+async fn apply_profile(storage: &Storage, profile: &CameraProfile) {
+    info!("Switching to camera profile {}", profile.name);
+    if !profile.controls.is_empty() {
+        // Controls are stored per device and re-applied when the pipeline restarts
+        let devices = storage.devices.devices().await;
+        let config = video::Config::find_optimal_settings(devices, profile.pipeline.clone());
+        if let Some(device) = Device::find(devices, config.source()) {
+            storage.controls.set_controls(&device.id, &profile.controls).await;
+        }
+    }
+    storage.camera_config.set(&profile.pipeline).await;
+}
+
+/// Checks switch times, coordinates and that every referenced profile exists, returns the first problem found
+pub fn validate_schedule(schedule: &ProfileSchedule, profiles: &[CameraProfile]) -> Result<(), String> {
+    let known = |name: &str| profiles.iter().any(|p| p.name == name);
+    for switch in &schedule.weekly {
+        if switch.weekday > 6 {
+            return Err(format!("Weekday {} is out of range, 0 is Monday and 6 is Sunday", switch.weekday));
+        }
+        NaiveTime::parse_from_str(&switch.time, "%H:%M")
+            .map_err(|_| format!("Time {} is not in HH:MM format", switch.time))?;
+        if !known(&switch.profile) {
+            return Err(format!("Profile {} does not exist", switch.profile));
+        }
+    }
+    if !(-90.0..=90.0).contains(&schedule.latitude) {
+        return Err(format!("Latitude {} is out of range", schedule.latitude));
+    }
+    if !(-180.0..=180.0).contains(&schedule.longitude) {
+        return Err(format!("Longitude {} is out of range", schedule.longitude));
+    }
+    if let Some(name) = [&schedule.day_profile, &schedule.night_profile].into_iter().flatten().find(|n| !known(n)) {
+        return Err(format!("Profile {name} does not exist"));
+    }
+    Ok(())
+}
+
+pub fn schedule_state(schedule: &ProfileSchedule, now: DateTime<Utc>) -> ScheduleState {
+    match schedule.mode {
+        ScheduleMode::Disabled => ScheduleState {
+            profile: None,
+            next_transition: None
+        },
+        ScheduleMode::Weekly => weekly_state(&schedule.weekly, now),
+        ScheduleMode::Sun => sun_state(schedule, now)
+    }
+}
+
+fn minute_of_week(switch: &WeeklyProfileSwitch) -> Option<i64> {
+    if switch.weekday > 6 {
+        return None;
+    }
+    let time = NaiveTime::parse_from_str(&switch.time, "%H:%M").ok()?;
+    Some(switch.weekday as i64 * 24 * 60 + time.hour() as i64 * 60 + time.minute() as i64)
+}
+
+fn weekly_state(weekly: &[WeeklyProfileSwitch], now: DateTime<Utc>) -> ScheduleState {
+    let mut switches: Vec<(i64, &WeeklyProfileSwitch)> = weekly.iter()
+        .filter_map(|s| minute_of_week(s).map(|m| (m, s)))
+        .collect();
+    switches.sort_by_key(|(m, _)| *m);
+
+    let (Some(first), Some(last)) = (switches.first(), switches.last()) else {
+        return ScheduleState {
+            profile: None,
+            next_transition: None
+        };
+    };
+
+    let local = now.with_timezone(&Local);
+    let now_minute = local.weekday().num_days_from_monday() as i64 * 24 * 60
+        + local.hour() as i64 * 60
+        + local.minute() as i64;
+
+    // Before the first switch of the week the last one from previous week is still active
+    let active = switches.iter()
+        .rev()
+        .find(|(m, _)| *m <= now_minute)
+        .unwrap_or(last);
+    let next_minute = switches.iter()
+        .find(|(m, _)| *m > now_minute)
+        .map(|(m, _)| *m)
+        .unwrap_or(first.0 + MINUTES_IN_WEEK);
+
+    let next_transition = now
+        + TimeDelta::minutes(next_minute - now_minute)
+        - TimeDelta::seconds(local.second() as i64);
+
+    ScheduleState {
+        profile: Some(active.1.profile.clone()),
+        next_transition: Some(next_transition)
+    }
+}
+
+fn sun_state(schedule: &ProfileSchedule, now: DateTime<Utc>) -> ScheduleState {
+    let today = now.date_naive();
+
+    // Transitions from yesterday up to two days ahead always contain the previous and next one
+    let mut transitions = Vec::new();
+    for offset in -1..=2 {
+        let date = today + TimeDelta::days(offset);
+        if let Daylight::Rises(sunrise, sunset) = daylight(date, schedule.latitude, schedule.longitude) {
+            transitions.push((sunrise, true));
+            transitions.push((sunset, false));
+        }
+    }
+    transitions.sort_by_key(|(t, _)| *t);
+
+    let is_day = transitions.iter()
+        .rev()
+        .find(|(t, _)| *t <= now)
+        .map(|(_, day)| *day)
+        .unwrap_or_else(|| matches!(daylight(today, schedule.latitude, schedule.longitude), Daylight::AlwaysUp));
+    let next_transition = transitions.iter()
+        .find(|(t, _)| *t > now)
+        .map(|(t, _)| *t);
+
+    let profile = if is_day {
+        schedule.day_profile.clone()
+    } else {
+        schedule.night_profile.clone()
+    };
+
+    ScheduleState {
+        profile,
+        next_transition
+    }
+}
+
+/// Sunrise and sunset for `date` using the sunrise equation, accurate to a minute or two
+fn daylight(date: NaiveDate, latitude: f64, longitude: f64) -> Daylight {
+    let days = (date - NaiveDate::from_ymd_opt(2000, 1, 1).unwrap_or_default()).num_days() as f64;
+    let mean_solar_noon = days + 0.0009 - longitude / 360.0;
+
+    let anomaly = (357.5291 + 0.98560028 * mean_solar_noon).rem_euclid(360.0).to_radians();
+    let center = 1.9148 * anomaly.sin()
+        + 0.02 * (2.0 * anomaly).sin()
+        + 0.0003 * (3.0 * anomaly).sin();
+    let ecliptic_longitude = (anomaly.to_degrees() + center + 180.0 + 102.9372)
+        .rem_euclid(360.0)
+        .to_radians();
+    let transit = J2000 + mean_solar_noon + 0.0053 * anomaly.sin() - 0.0069 * (2.0 * ecliptic_longitude).sin();
+
+    let declination = (ecliptic_longitude.sin() * 23.4397f64.to_radians().sin()).asin();
+    let latitude = latitude.to_radians();
+    let cos_hour_angle = ((-0.833f64).to_radians().sin() - latitude.sin() * declination.sin())
+        / (latitude.cos() * declination.cos());
+
+    if cos_hour_angle < -1.0 {
+        return Daylight::AlwaysUp;
+    }
+    if cos_hour_angle > 1.0 {
+        return Daylight::AlwaysDown;
+    }
+
+    let hour_angle = cos_hour_angle.acos().to_degrees();
+    let sunrise = julian_to_utc(transit - hour_angle / 360.0);
+    let sunset = julian_to_utc(transit + hour_angle / 360.0);
+    match (sunrise, sunset) {
+        (Some(sunrise), Some(sunset)) => Daylight::Rises(sunrise, sunset),
+        _ => Daylight::AlwaysDown
+    }
+}
+
+fn julian_to_utc(julian: f64) -> Option<DateTime<Utc>> {
+    DateTime::from_timestamp(((julian - UNIX_EPOCH_JULIAN) * 86400.0) as i64, 0)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// The sunrise equation is good to a couple of minutes against published tables
+    const TOLERANCE: TimeDelta = TimeDelta::minutes(3);
+
+    fn utc(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).unwrap().with_timezone(&Utc)
+    }
+
+    fn assert_near(actual: DateTime<Utc>, expected: &str) {
+        let expected = utc(expected);
+        assert!((actual - expected).abs() <= TOLERANCE, "{actual} is not within {TOLERANCE} of {expected}");
+    }
+
+    fn sun_times(date: (i32, u32, u32), latitude: f64, longitude: f64) -> (DateTime<Utc>, DateTime<Utc>) {
+        let date = NaiveDate::from_ymd_opt(date.0, date.1, date.2).unwrap();
+        match daylight(date, latitude, longitude) {
+            Daylight::Rises(sunrise, sunset) => (sunrise, sunset),
+            Daylight::AlwaysUp => panic!("Sun never sets on {date}"),
+            Daylight::AlwaysDown => panic!("Sun never rises on {date}"),
+        }
+    }
+
+    #[test]
+    fn london_summer_solstice() {
+        let (sunrise, sunset) = sun_times((2024, 6, 21), 51.5074, -0.1278);
+        assert_near(sunrise, "2024-06-21T03:43:00Z");
+        assert_near(sunset, "2024-06-21T20:21:00Z");
+    }
+
+    #[test]
+    fn london_winter_solstice() {
+        let (sunrise, sunset) = sun_times((2024, 12, 21), 51.5074, -0.1278);
+        assert_near(sunrise, "2024-12-21T08:04:00Z");
+        assert_near(sunset, "2024-12-21T15:54:00Z");
+    }
+
+    #[test]
+    fn southern_hemisphere_east_of_greenwich() {
+        // Sydney sunrise is on the previous UTC day
+        let (sunrise, sunset) = sun_times((2024, 6, 21), -33.8688, 151.2093);
+        assert_near(sunrise, "2024-06-20T21:00:00Z");
+        assert_near(sunset, "2024-06-21T06:54:00Z");
+    }
+
+    #[test]
+    fn polar_day_and_night() {
+        let summer = NaiveDate::from_ymd_opt(2024, 6, 21).unwrap();
+        let winter = NaiveDate::from_ymd_opt(2024, 12, 21).unwrap();
+        assert!(matches!(daylight(summer, 69.65, 18.96), Daylight::AlwaysUp));
+        assert!(matches!(daylight(winter, 69.65, 18.96), Daylight::AlwaysDown));
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let profiles = vec![CameraProfile {
+            name: "day".to_string(),
+            pipeline: Default::default(),
+            controls: Vec::new(),
+        }];
+        let switch = |weekday: u32, time: &str, profile: &str| WeeklyProfileSwitch {
+            weekday,
+            time: time.to_string(),
+            profile: profile.to_string(),
+        };
+        let valid = ProfileSchedule {
+            mode: ScheduleMode::Weekly,
+            weekly: vec![switch(0, "07:30", "day")],
+            latitude: 51.5,
+            longitude: -0.1,
+            day_profile: Some("day".to_string()),
+            ..Default::default()
+        };
+        assert_eq!(validate_schedule(&valid, &profiles), Ok(()));
+
+        let invalid = [
+            ProfileSchedule { weekly: vec![switch(7, "07:30", "day")], ..valid.clone() },
+            ProfileSchedule { weekly: vec![switch(0, "7.30", "day")], ..valid.clone() },
+            ProfileSchedule { weekly: vec![switch(0, "07:30", "night")], ..valid.clone() },
+            ProfileSchedule { latitude: 90.5, ..valid.clone() },
+            ProfileSchedule { latitude: f64::NAN, ..valid.clone() },
+            ProfileSchedule { longitude: -180.5, ..valid.clone() },
+            ProfileSchedule { night_profile: Some("night".to_string()), ..valid.clone() },
+        ];
+        for schedule in invalid {
+            assert!(validate_schedule(&schedule, &profiles).is_err(), "{schedule:?}");
+        }
+    }
+
+    #[test]
+    fn sun_schedule_switches_at_sunset() {
+        let schedule = ProfileSchedule {
+            mode: ScheduleMode::Sun,
+            latitude: 51.5074,
+            longitude: -0.1278,
+            day_profile: Some("day".to_string()),
+            night_profile: Some("night".to_string()),
+            ..Default::default()
+        };
+
+        let noon = schedule_state(&schedule, utc("2024-06-21T12:00:00Z"));
+        assert_eq!(noon.profile.as_deref(), Some("day"));
+        assert_near(noon.next_transition.unwrap(), "2024-06-21T20:21:00Z");
+
+        let night = schedule_state(&schedule, utc("2024-06-21T23:00:00Z"));
+        assert_eq!(night.profile.as_deref(), Some("night"));
+        assert_near(night.next_transition.unwrap(), "2024-06-22T03:43:00Z");
+    }
+}
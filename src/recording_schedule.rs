@@ -0,0 +1,223 @@
+use std::time::Duration;
+
+use chrono::{DateTime, Datelike, Local, NaiveTime, TimeDelta, TimeZone, Utc};
+
+use crate::models::*;
+
+const MAX_SLEEP: Duration = Duration::from_secs(60 * 60);
+/// Weekly windows are expanded this many days around now, enough to find the next transition
+const EXPANDED_DAYS: i64 = 8;
+
+/// Checks the timezone and window times, returns the first problem found
+pub fn validate_schedule(schedule: &RecordingSchedule) -> Result<(), String> {
+    if let Some(ref timezone) = schedule.timezone {
+        timezone.parse::<chrono_tz::Tz>()
+            .map_err(|_| format!("Unknown timezone {timezone}"))?;
+    }
+    for window in &schedule.windows {
+        if window.weekday > 6 {
+            return Err(format!("Weekday {} is out of range, 0 is Monday and 6 is Sunday", window.weekday));
+        }
+        for time in [&window.start, &window.end] {
+            NaiveTime::parse_from_str(time, "%H:%M")
+                .map_err(|_| format!("Time {time} is not in HH:MM format"))?;
+        }
+    }
+    if let Some(exception) = schedule.exceptions.iter().find(|e| e.from >= e.to) {
+        return Err(format!("Exception from {} has to be before {}", exception.from, exception.to));
+    }
+    Ok(())
+}
+
+pub fn schedule_state(schedule: &Option<RecordingSchedule>, now: DateTime<Utc>) -> RecordingScheduleState {
+    let Some(schedule) = schedule else {
+        return RecordingScheduleState {
+            recording: true,
+            next_transition: None
+        };
+    };
+
+    let windows = match schedule.timezone.as_ref().and_then(|t| t.parse::<chrono_tz::Tz>().ok()) {
+        Some(timezone) => weekly_intervals(&schedule.windows, &timezone, now),
+        None => weekly_intervals(&schedule.windows, &Local, now),
+    };
+    let recording_at = |time: DateTime<Utc>| {
+        let millis = time.timestamp_millis();
+        schedule.exceptions.iter()
+            .rev()
+            .find(|e| e.from <= millis && millis < e.to)
+            .map(|e| e.record)
+            .unwrap_or_else(|| windows.iter().any(|(start, end)| *start <= time && time < *end))
+    };
+
+    let recording = recording_at(now);
+    let mut boundaries: Vec<DateTime<Utc>> = windows.iter()
+        .flat_map(|(start, end)| [*start, *end])
+        .chain(schedule.exceptions.iter()
+            .flat_map(|e| [e.from, e.to])
+            .filter_map(DateTime::from_timestamp_millis))
+        .filter(|t| *t > now)
+        .collect();
+    boundaries.sort();
+
+    // Adjacent windows and exceptions can share a boundary without changing the state
+    let next_transition = boundaries.into_iter()
+        .find(|t| recording_at(*t) != recording)
+        .map(|t| t.timestamp_millis());
+
+    RecordingScheduleState {
+        recording,
+        next_transition
+    }
+}
+
+/// Time to sleep until the state has to be evaluated again
+pub fn time_until_transition(state: &RecordingScheduleState) -> Duration {
+    state.next_transition
+        .and_then(DateTime::from_timestamp_millis)
+        .map(|t| (t - Utc::now()).to_std().unwrap_or_default())
+        .unwrap_or(MAX_SLEEP)
+        .min(MAX_SLEEP)
+}
+
+fn weekly_intervals<Tz: TimeZone>(windows: &[RecordingWindow], timezone: &Tz, now: DateTime<Utc>) -> Vec<(DateTime<Utc>, DateTime<Utc>)> {
+    let today = now.with_timezone(timezone).date_naive();
+    let mut intervals = Vec::new();
+
+    for offset in -EXPANDED_DAYS..=EXPANDED_DAYS {
+        let date = today + TimeDelta::days(offset);
+        for window in windows.iter().filter(|w| w.weekday == date.weekday().num_days_from_monday()) {
+            let (Ok(start), Ok(end)) = (
+                NaiveTime::parse_from_str(&window.start, "%H:%M"),
+                NaiveTime::parse_from_str(&window.end, "%H:%M"),
+            ) else {
+                continue;
+            };
+            let end_date = if end <= start { date + TimeDelta::days(1) } else { date };
+
+            // Times skipped by a DST change resolve to the first valid time after them
+            let to_utc = |local: chrono::NaiveDateTime| {
+                timezone.from_local_datetime(&local).earliest()
+                    .or_else(|| timezone.from_local_datetime(&(local + TimeDelta::hours(1))).earliest())
+                    .map(|t| t.with_timezone(&Utc))
+            };
+            if let (Some(start), Some(end)) = (to_utc(date.and_time(start)), to_utc(end_date.and_time(end))) {
+                intervals.push((start, end));
+            }
+        }
+    }
+    intervals
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn utc(date: &str) -> DateTime<Utc> {
+        DateTime::parse_from_rfc3339(date).unwrap().with_timezone(&Utc)
+    }
+
+    fn millis(date: &str) -> i64 {
+        utc(date).timestamp_millis()
+    }
+
+    fn window(weekday: u32, start: &str, end: &str) -> RecordingWindow {
+        RecordingWindow {
+            weekday,
+            start: start.to_string(),
+            end: end.to_string(),
+        }
+    }
+
+    fn exception(from: &str, to: &str, record: bool) -> ScheduleException {
+        ScheduleException {
+            from: millis(from),
+            to: millis(to),
+            record,
+        }
+    }
+
+    /// Berlin is UTC+1 in winter and UTC+2 in summer
+    fn berlin(windows: Vec<RecordingWindow>, exceptions: Vec<ScheduleException>) -> Option<RecordingSchedule> {
+        Some(RecordingSchedule {
+            timezone: Some("Europe/Berlin".to_string()),
+            windows,
+            exceptions,
+        })
+    }
+
+    fn assert_state(schedule: &Option<RecordingSchedule>, now: &str, recording: bool, next_transition: Option<&str>) {
+        assert_eq!(schedule_state(schedule, utc(now)), RecordingScheduleState {
+            recording,
+            next_transition: next_transition.map(millis),
+        }, "State at {now}");
+    }
+
+    #[test]
+    fn rejects_invalid_schedules() {
+        let valid = RecordingSchedule {
+            timezone: Some("Europe/Berlin".to_string()),
+            windows: vec![window(0, "08:00", "18:00")],
+            exceptions: vec![exception("2024-06-03T10:00:00Z", "2024-06-03T12:00:00Z", false)],
+        };
+        assert_eq!(validate_schedule(&valid), Ok(()));
+
+        let invalid = [
+            RecordingSchedule { timezone: Some("Mars/Olympus".to_string()), ..valid.clone() },
+            RecordingSchedule { windows: vec![window(7, "08:00", "18:00")], ..valid.clone() },
+            RecordingSchedule { windows: vec![window(0, "24:00", "18:00")], ..valid.clone() },
+            RecordingSchedule { windows: vec![window(0, "08:00", "6pm")], ..valid.clone() },
+            RecordingSchedule {
+                exceptions: vec![exception("2024-06-03T12:00:00Z", "2024-06-03T12:00:00Z", true)],
+                ..valid.clone()
+            },
+        ];
+        for schedule in invalid {
+            assert!(validate_schedule(&schedule).is_err(), "{schedule:?} was accepted");
+        }
+    }
+
+    #[test]
+    fn records_without_schedule() {
+        assert_state(&None, "2024-06-03T12:00:00Z", true, None);
+    }
+
+    #[test]
+    fn later_exceptions_take_precedence() {
+        // Monday 2024-06-03 from 06:00 to 16:00 UTC
+        let schedule = berlin(vec![window(0, "08:00", "18:00")], vec![
+            exception("2024-06-03T10:00:00Z", "2024-06-03T14:00:00Z", false),
+            exception("2024-06-03T12:00:00Z", "2024-06-03T13:00:00Z", true),
+            exception("2024-06-03T15:00:00Z", "2024-06-03T17:00:00Z", true),
+        ]);
+        assert_state(&schedule, "2024-06-03T09:00:00Z", true, Some("2024-06-03T10:00:00Z"));
+        assert_state(&schedule, "2024-06-03T11:00:00Z", false, Some("2024-06-03T12:00:00Z"));
+        assert_state(&schedule, "2024-06-03T12:30:00Z", true, Some("2024-06-03T13:00:00Z"));
+        assert_state(&schedule, "2024-06-03T13:30:00Z", false, Some("2024-06-03T14:00:00Z"));
+        // The window ends inside the last exception, which keeps recording
+        assert_state(&schedule, "2024-06-03T14:30:00Z", true, Some("2024-06-03T17:00:00Z"));
+        assert_state(&schedule, "2024-06-03T17:30:00Z", false, Some("2024-06-10T06:00:00Z"));
+    }
+
+    #[test]
+    fn windows_wrap_around_the_week() {
+        // Sunday 22:00 to Monday 02:00, 20:00 to 00:00 UTC in summer
+        let schedule = berlin(vec![window(6, "22:00", "02:00")], Vec::new());
+        assert_state(&schedule, "2024-06-02T23:00:00Z", true, Some("2024-06-03T00:00:00Z"));
+        assert_state(&schedule, "2024-06-03T12:00:00Z", false, Some("2024-06-09T20:00:00Z"));
+        assert_state(&schedule, "2024-06-09T19:00:00Z", false, Some("2024-06-09T20:00:00Z"));
+    }
+
+    #[test]
+    fn dst_transition_days() {
+        // 2024-03-31 skips from 02:00 to 03:00, the window starts at the first valid time after 02:30
+        let schedule = berlin(vec![window(6, "02:30", "04:00")], Vec::new());
+        assert_state(&schedule, "2024-03-31T00:30:00Z", false, Some("2024-03-31T01:30:00Z"));
+        assert_state(&schedule, "2024-03-31T01:45:00Z", true, Some("2024-03-31T02:00:00Z"));
+
+        // 2024-10-27 repeats 02:00 to 03:00, the window runs an hour longer
+        let schedule = berlin(vec![window(6, "01:00", "05:00")], Vec::new());
+        assert_state(&schedule, "2024-10-26T22:30:00Z", false, Some("2024-10-26T23:00:00Z"));
+        assert_state(&schedule, "2024-10-27T03:30:00Z", true, Some("2024-10-27T04:00:00Z"));
+    }
+}
@@ -0,0 +1,139 @@
+use std::{path::{Path, PathBuf}, sync::Arc};
+use gstreamer::{prelude::*, ClockTime, MessageType, MessageView, State};
+use log::*;
+use tokio::sync::{mpsc, watch};
+use crate::{file_sink, models::*, shutdown, storage::Storage};
+
+/// Remuxed recordings are written under this suffix until they replace the original
+pub const REMUX_SUFFIX: &str = ".remux";
+/// Nice value of the remux thread, the gstreamer threads it starts inherit it
+const REMUX_NICENESS: i32 = 19;
+
+pub fn remux_path(path: &Path) -> PathBuf {
+    let mut path = path.as_os_str().to_owned();
+    path.push(REMUX_SUFFIX);
+    path.into()
+}
+
+/// Remuxes closed recordings queued by `file_saver` one at a time, uploads wait until they are done.
+/// Remuxes interrupted by a shutdown or crash are picked up from the index first.
+pub async fn remux_worker(
+    storage: Arc<Storage>,
+    app_data: String,
+    mut queue: mpsc::UnboundedReceiver<Recording>,
+    mut shutdown: watch::Receiver<bool>,
+) {
+    for recording in storage.recordings.pending_remuxes().await {
+        if shutdown::is_requested(&shutdown) {
+            return;
+        }
+        info!("Resuming remux of {}", recording.file_name);
+        remux(&storage, &app_data, recording).await;
+    }
+
+    loop {
+        let recording = tokio::select! {
+            recording = queue.recv() => match recording {
+                Some(recording) => recording,
+                None => break,
+            },
+            _ = shutdown::requested(&mut shutdown) => {
+                break;
+            }
+        };
+        remux(&storage, &app_data, recording).await;
+    }
+}
+
+async fn remux(storage: &Storage, app_data: &str, recording: Recording) {
+    // Retention may have deleted the recording while it was queued
+    let Some(recording) = storage.recordings.get_recording_by_id(recording.id).await else {
+        return;
+    };
+    if !recording.faststart {
+        let path = file_sink::recording_path(app_data, &recording);
+        let target = remux_path(&path);
+        match remux_file(path.clone(), target.clone()).await {
+            Ok(()) => replace(storage, recording.clone(), &path, &target).await,
+            Err(e) => {
+                warn!("Failed to remux {}, keeping it fragmented: {e}", recording.file_name);
+                let _ = tokio::fs::remove_file(&target).await;
+            }
+        }
+    }
+    // Releases the queued upload, with the remuxed file or the original
+    storage.recordings.set_remux_pending(recording.id, false).await;
+}
+
+/// Swaps the remuxed file in and updates the index with its size
+async fn replace(storage: &Storage, mut recording: Recording, path: &Path, target: &Path) {
+    if storage.recordings.get_recording_by_id(recording.id).await.is_none() {
+        let _ = tokio::fs::remove_file(target).await;
+        return;
+    }
+    // filesink doesn't sync, the original must not be replaced by a file still in the page cache
+    if let Ok(file) = tokio::fs::File::open(target).await {
+        if let Err(e) = file.sync_all().await {
+            warn!("Failed to sync remuxed {target:?} {e:?}");
+        }
+    }
+    if let Err(e) = tokio::fs::rename(target, path).await {
+        error!("Failed to replace {path:?} with its remuxed version {e:?}");
+        let _ = tokio::fs::remove_file(target).await;
+        return;
+    }
+
+    match tokio::fs::metadata(path).await {
+        Ok(metadata) => recording.size = metadata.len(),
+        Err(e) => warn!("Failed to read size of remuxed {path:?} {e:?}"),
+    }
+    recording.faststart = true;
+    storage.recordings.update_recording(&recording).await;
+    info!("Remuxed {} into a progressive mp4", recording.file_name);
+}
+
+/// Runs the remux on its own thread at the lowest priority, recording and live view come first
+async fn remux_file(source: PathBuf, target: PathBuf) -> Result<(), String> {
+    let (tx, rx) = tokio::sync::oneshot::channel();
+    std::thread::Builder::new()
+        .name("remux".to_string())
+        .spawn(move || {
+            // On Linux the nice value is per thread, so this leaves the rest of the process alone
+            if unsafe { libc::setpriority(libc::PRIO_PROCESS, 0, REMUX_NICENESS) } != 0 {
+                debug!("Failed to lower remux priority {:?}", std::io::Error::last_os_error());
+            }
+            let _ = tx.send(remux_blocking(&source, &target));
+        })
+        .map_err(|e| format!("Failed to start remux thread {e:?}"))?;
+    rx.await.map_err(|_| "Remux thread stopped".to_string())?
+}
+
+fn remux_blocking(source: &Path, target: &Path) -> Result<(), String> {
+    let pipeline = gstreamer::parse::launch(
+        "filesrc name=src ! qtdemux ! h264parse ! mp4mux faststart=true ! filesink name=sink"
+    )
+        .map_err(|e| format!("Failed to create remux pipeline {e:?}"))?
+        .downcast::<gstreamer::Pipeline>()
+        .map_err(|_| "Remux pipeline is not a pipeline".to_string())?;
+
+    let src = pipeline.by_name("src").ok_or("Remux pipeline has no source")?;
+    src.set_property("location", source.to_string_lossy().as_ref());
+    let sink = pipeline.by_name("sink").ok_or("Remux pipeline has no sink")?;
+    sink.set_property("location", target.to_string_lossy().as_ref());
+    let bus = pipeline.bus().ok_or("Remux pipeline has no bus")?;
+
+    let result = (|| {
+        pipeline.set_state(State::Playing)
+            .map_err(|e| format!("Failed to start remux of {source:?} {e:?}"))?;
+        let message = bus.timed_pop_filtered(ClockTime::NONE, &[MessageType::Eos, MessageType::Error])
+            .ok_or("Remux pipeline stopped without EOS")?;
+        match message.view() {
+            MessageView::Error(e) => Err(format!("Remux of {source:?} failed {} {:?}", e.error(), e.debug())),
+            _ => Ok(()),
+        }
+    })();
+
+    // mp4mux writes the moov on EOS, the file is only complete once the pipeline stopped
+    let _ = pipeline.set_state(State::Null);
+    result
+}
@@ -0,0 +1,271 @@
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+use log::*;
+use crate::{models::*, shutdown, storage::Storage, thumbnails};
+
+/// How often retention is evaluated besides when a new file is opened
+const RETENTION_INTERVAL: Duration = Duration::from_secs(60);
+/// Number of deletions kept in the retention log
+const RETENTION_LOG_SIZE: usize = 100;
+
+pub async fn retention_task(storage: Arc<Storage>, app_data: String, mut shutdown: watch::Receiver<bool>) {
+    let mut config_reciver = storage.file_config.subscribe().await;
+    let mut interval = tokio::time::interval(RETENTION_INTERVAL);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {},
+            _ = config_reciver.recv() => {},
+            _ = shutdown::requested(&mut shutdown) => {
+                break;
+            }
+        }
+        let config = storage.file_config.get().await;
+        enforce(&app_data, &storage, &config).await;
+        crate::timelapse::enforce(&app_data, &storage).await;
+    }
+}
+
+/// Deletes the oldest unlocked recordings until no retention rule is violated
+pub async fn enforce(app_data: &str, storage: &Storage, config: &FileSinkConfig) {
+    let mut events = Vec::new();
+    loop {
+        let oldest = storage.recordings.list_recordings(&RecordingQuery {
+            sort: RecordingSort::StartTime,
+            order: SortOrder::Asc,
+            locked: Some(false),
+            limit: 1,
+            ..Default::default()
+        }).await.items.into_iter().next();
+
+        // The recording being written is never deleted
+        let Some(oldest) = oldest.filter(|r| r.closed) else {
+            break;
+        };
+        let Some(rule) = violated_rule(app_data, storage, config, &oldest).await else {
+            break;
+        };
+        if !remove_recording(app_data, storage, &oldest).await {
+            break;
+        }
+
+        info!("Retention rule {rule:?} deleted recording {}", oldest.file_name);
+        events.push(RetentionEvent {
+            file_name: oldest.file_name,
+            rule,
+            deleted_at: chrono::Utc::now().timestamp_millis(),
+        });
+    }
+
+    if !events.is_empty() {
+        let mut log = storage.retention_log.get().await;
+        log.extend(events);
+        let overflow = log.len().saturating_sub(RETENTION_LOG_SIZE);
+        log.drain(..overflow);
+        storage.retention_log.set(&log).await;
+    }
+}
+
+async fn violated_rule(app_data: &str, storage: &Storage, config: &FileSinkConfig, oldest: &Recording) -> Option<RetentionRule> {
+    if percentage_of_file_system_usage(app_data) > config.max_system_usage {
+        return Some(RetentionRule::MaxSystemUsage);
+    }
+    if let Some(min_free_bytes) = config.min_free_bytes {
+        if fs2::free_space(app_data).map(|free| free < min_free_bytes).unwrap_or(false) {
+            return Some(RetentionRule::MinFreeBytes);
+        }
+    }
+
+    // Locked recordings have their own quota and don't count towards the ring buffer
+    let (count, total_bytes) = storage.recordings.recordings_usage(false).await;
+    if config.max_number_of_file.map(|max| count > max).unwrap_or(false) {
+        return Some(RetentionRule::MaxNumberOfFiles);
+    }
+    if config.max_total_bytes.map(|max| total_bytes > max).unwrap_or(false) {
+        return Some(RetentionRule::MaxTotalBytes);
+    }
+    if let Some(max_age) = config.max_age {
+        let cutoff = chrono::Utc::now().timestamp_millis() - (max_age as i64).saturating_mul(1000);
+        if oldest.end_time.unwrap_or(oldest.start_time) < cutoff {
+            return Some(RetentionRule::MaxAge);
+        }
+    }
+    None
+}
+
+/// Removes a recording from disk and the index, returns false if the file couldn't be removed
+pub async fn remove_recording(app_data: &str, storage: &Storage, recording: &Recording) -> bool {
+    let path = crate::file_sink::recording_path(app_data, recording);
+    if let Err(e) = tokio::fs::remove_file(&path).await {
+        if e.kind() != std::io::ErrorKind::NotFound {
+            error!("Failed to remove recording {path:?} {e:?}");
+            return false;
+        }
+    }
+    storage.recordings.delete_recording(&recording.file_name).await;
+    thumbnails::remove(app_data, recording.id).await;
+    remove_empty_directories(app_data, &path).await;
+    true
+}
+
+/// Removes date directories emptied by deleting `path`, stops at the first one that isn't empty
+async fn remove_empty_directories(app_data: &str, path: &std::path::Path) {
+    let root = std::path::Path::new(app_data);
+    let mut directory = path.parent();
+    while let Some(current) = directory.filter(|d| d.starts_with(root) && *d != root) {
+        if tokio::fs::remove_dir(current).await.is_err() {
+            break;
+        }
+        directory = current.parent();
+    }
+}
+
+pub fn percentage_of_file_system_usage(app_data: &str) -> f64 {
+    let total_space = fs2::total_space(app_data).unwrap_or(1);
+    let free_space = fs2::free_space(app_data).unwrap_or(1);
+    let ret = 1.0 - (free_space as f64 / total_space as f64);
+    return ret;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::test_util::{self, TempDir};
+
+    const HOUR: i64 = 60 * 60 * 1000;
+
+    /// Every rule disabled, the filesystem usage can't exceed 1
+    fn disabled() -> FileSinkConfig {
+        FileSinkConfig {
+            max_system_usage: 1.0,
+            ..Default::default()
+        }
+    }
+
+    /// Writes and indexes a 1000 byte recording started `age` milliseconds ago
+    async fn add_recording(app_data: &TempDir, storage: &Storage, file_name: &str, age: i64, closed: bool) -> Recording {
+        let start_time = chrono::Utc::now().timestamp_millis() - age;
+        let mut recording = Recording {
+            file_name: file_name.to_string(),
+            start_time,
+            end_time: closed.then_some(start_time + 1000),
+            size: 1000,
+            closed,
+            ..Default::default()
+        };
+        let path = crate::file_sink::recording_path(&app_data.app_data(), &recording);
+        std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+        std::fs::write(&path, [0; 1000]).unwrap();
+        recording.id = storage.recordings.create_recording(&recording).await.unwrap();
+        recording
+    }
+
+    async fn remaining(storage: &Storage) -> Vec<String> {
+        let mut names: Vec<String> = storage.recordings.all_recordings().await
+            .into_iter()
+            .map(|r| r.file_name)
+            .collect();
+        names.sort();
+        names
+    }
+
+    #[tokio::test]
+    async fn reports_rules_in_order() {
+        let app_data = TempDir::new("retention-order");
+        let storage = test_util::storage(&app_data).await;
+        let oldest = add_recording(&app_data, &storage, "a.mp4", 2 * HOUR, true).await;
+        add_recording(&app_data, &storage, "b.mp4", HOUR, true).await;
+
+        let mut config = FileSinkConfig {
+            max_system_usage: -1.0,
+            min_free_bytes: Some(u64::MAX),
+            max_number_of_file: Some(1),
+            max_total_bytes: Some(1000),
+            max_age: Some(60),
+            ..Default::default()
+        };
+        let rules = [
+            RetentionRule::MaxSystemUsage,
+            RetentionRule::MinFreeBytes,
+            RetentionRule::MaxNumberOfFiles,
+            RetentionRule::MaxTotalBytes,
+            RetentionRule::MaxAge,
+        ];
+        for rule in rules {
+            assert_eq!(violated_rule(&app_data.app_data(), &storage, &config, &oldest).await, Some(rule));
+            // Lifting the reported rule uncovers the next one
+            match rule {
+                RetentionRule::MaxSystemUsage => config.max_system_usage = 1.0,
+                RetentionRule::MinFreeBytes => config.min_free_bytes = None,
+                RetentionRule::MaxNumberOfFiles => config.max_number_of_file = None,
+                RetentionRule::MaxTotalBytes => config.max_total_bytes = None,
+                RetentionRule::MaxAge => config.max_age = None,
+            }
+        }
+        assert_eq!(violated_rule(&app_data.app_data(), &storage, &config, &oldest).await, None);
+    }
+
+    #[tokio::test]
+    async fn skips_locked_and_open_recordings() {
+        let app_data = TempDir::new("retention-skip");
+        let storage = test_util::storage(&app_data).await;
+        let locked = add_recording(&app_data, &storage, "a.mp4", 3 * HOUR, true).await;
+        storage.recordings.set_locked(locked.id, true).await;
+        add_recording(&app_data, &storage, "b.mp4", 2 * HOUR, true).await;
+        let open = add_recording(&app_data, &storage, "c.mp4", HOUR, false).await;
+
+        let config = FileSinkConfig {
+            max_number_of_file: Some(0),
+            ..disabled()
+        };
+        enforce(&app_data.app_data(), &storage, &config).await;
+
+        assert_eq!(remaining(&storage).await, ["a.mp4", "c.mp4"]);
+        assert!(app_data.path().join("a.mp4").exists());
+        assert!(!app_data.path().join("b.mp4").exists());
+        assert!(crate::file_sink::recording_path(&app_data.app_data(), &open).exists());
+
+        let log = storage.retention_log.get().await;
+        assert_eq!(log.len(), 1);
+        assert_eq!(log[0].file_name, "b.mp4");
+        assert_eq!(log[0].rule, RetentionRule::MaxNumberOfFiles);
+    }
+
+    #[tokio::test]
+    async fn keeps_recordings_within_the_rules() {
+        let app_data = TempDir::new("retention-keep");
+        let storage = test_util::storage(&app_data).await;
+        add_recording(&app_data, &storage, "a.mp4", 2 * HOUR, true).await;
+        add_recording(&app_data, &storage, "b.mp4", HOUR, true).await;
+
+        let config = FileSinkConfig {
+            max_number_of_file: Some(2),
+            max_total_bytes: Some(2000),
+            max_age: Some(3 * 60 * 60),
+            ..disabled()
+        };
+        enforce(&app_data.app_data(), &storage, &config).await;
+
+        assert_eq!(remaining(&storage).await, ["a.mp4", "b.mp4"]);
+        assert!(storage.retention_log.get().await.is_empty());
+    }
+
+    #[tokio::test]
+    async fn removes_emptied_date_directories() {
+        let app_data = TempDir::new("retention-directories");
+        let storage = test_util::storage(&app_data).await;
+        add_recording(&app_data, &storage, "2025/01/31/a.mp4", 3 * HOUR, true).await;
+        add_recording(&app_data, &storage, "2025/02/01/b.mp4", 2 * HOUR, true).await;
+        add_recording(&app_data, &storage, "2025/02/01/c.mp4", HOUR, true).await;
+
+        let config = FileSinkConfig {
+            max_number_of_file: Some(1),
+            ..disabled()
+        };
+        enforce(&app_data.app_data(), &storage, &config).await;
+
+        assert_eq!(remaining(&storage).await, ["2025/02/01/c.mp4"]);
+        assert!(!app_data.path().join("2025/01").exists());
+        assert!(app_data.path().join("2025/02/01/c.mp4").exists());
+        assert!(app_data.path().exists());
+    }
+}
@@ -0,0 +1,46 @@
+use std::time::Duration;
+
+use log::*;
+use tokio::signal::unix::{signal, SignalKind};
+use tokio::sync::watch::{Receiver, Sender};
+
+/// Upper bound for each step of the shutdown sequence
+pub const SHUTDOWN_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long to wait for EOS to travel through the pipeline
+pub const EOS_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// Waits for SIGTERM or Ctrl+C and notifies everyone holding a receiver
+pub async fn wait_for_signal(shutdown: &Sender<bool>) {
+    let terminate = async {
+        match signal(SignalKind::terminate()) {
+            Ok(mut sigterm) => {
+                sigterm.recv().await;
+            },
+            Err(e) => {
+                error!("Failed to listen for SIGTERM {e:?}");
+                futures_util::future::pending::<()>().await;
+            }
+        }
+    };
+
+    tokio::select! {
+        _ = terminate => {}
+        _ = tokio::signal::ctrl_c() => {}
+    }
+
+    info!("Shutdown requested");
+    let _ = shutdown.send(true);
+}
+
+/// Resolves once shutdown has been requested
+pub async fn requested(shutdown: &mut Receiver<bool>) {
+    while !*shutdown.borrow_and_update() {
+        if shutdown.changed().await.is_err() {
+            futures_util::future::pending::<()>().await;
+        }
+    }
+}
+
+pub fn is_requested(shutdown: &Receiver<bool>) -> bool {
+    *shutdown.borrow()
+}
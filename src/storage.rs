@@ -10,8 +10,18 @@ mod simple_observable;
 pub struct Storage {
     pub users: Box<dyn UserStorage + Send + Sync>,
     pub camera_config: Box<dyn ObservableStorage<PipelineConfig> + Send + Sync>,
+    pub pipeline_status: Box<dyn SimpleStorage<PipelineStatus> + Send + Sync>,
     pub file_config: Box<dyn ObservableStorage<FileSinkConfig> + Send + Sync>,
     pub devices: Box<dyn DeviceStorage + Send + Sync>,
+    pub controls: Box<dyn ControlStorage + Send + Sync>,
+    pub profiles: Box<dyn ObservableStorage<Vec<CameraProfile>> + Send + Sync>,
+    pub profile_schedule: Box<dyn ObservableStorage<ProfileSchedule> + Send + Sync>,
+    pub recordings: Box<dyn RecordingStorage + Send + Sync>,
+    pub retention_log: Box<dyn SimpleStorage<Vec<RetentionEvent>> + Send + Sync>,
+    pub upload_config: Box<dyn SimpleStorage<UploadConfig> + Send + Sync>,
+    pub uploads: Box<dyn UploadQueueStorage + Send + Sync>,
+    pub timelapse_config: Box<dyn SimpleStorage<TimelapseConfig> + Send + Sync>,
+    pub timelapses: Box<dyn TimelapseStorage + Send + Sync>,
     pub config: Config
 
 }
@@ -27,6 +37,16 @@ impl Storage {
 
         Self {
             users: Box::new(sqlite_storage.clone()),
+            controls: Box::new(sqlite_storage.clone()),
+            pipeline_status: Box::new(sqlite_storage.clone()),
+            recordings: Box::new(sqlite_storage.clone()),
+            retention_log: Box::new(sqlite_storage.clone()),
+            upload_config: Box::new(sqlite_storage.clone()),
+            uploads: Box::new(sqlite_storage.clone()),
+            timelapse_config: Box::new(sqlite_storage.clone()),
+            timelapses: Box::new(sqlite_storage.clone()),
+            profiles: Box::new(SimpleObservable::new(sqlite_storage.clone())),
+            profile_schedule: Box::new(SimpleObservable::new(sqlite_storage.clone())),
             file_config: Box::new(SimpleObservable::new(sqlite_storage.clone())),
             camera_config: Box::new(SimpleObservable::new(sqlite_storage)),
             devices,
@@ -58,6 +78,55 @@ pub trait DeviceStorage {
     async fn devices(&self) -> &HashMap<String, Device>;
 }
 
+#[async_trait::async_trait]
+pub trait ControlStorage {
+    async fn get_controls(&self, device_id: &str) -> Vec<ControlValue>;
+    async fn set_controls(&self, device_id: &str, controls: &[ControlValue]);
+}
+
+#[async_trait::async_trait]
+pub trait RecordingStorage {
+    /// Returns the id of the new index entry
+    async fn create_recording(&self, recording: &Recording) -> Option<i64>;
+    async fn update_recording(&self, recording: &Recording);
+    async fn get_recording(&self, file_name: &str) -> Option<Recording>;
+    async fn get_recording_by_id(&self, id: i64) -> Option<Recording>;
+    async fn delete_recording(&self, file_name: &str);
+    async fn list_recordings(&self, query: &RecordingQuery) -> RecordingPage;
+    async fn all_recordings(&self) -> Vec<Recording>;
+    /// Lock state is only changed here, `update_recording` leaves it alone
+    async fn set_locked(&self, id: i64, locked: bool);
+    /// Upload state is only changed here, `update_recording` leaves it alone
+    async fn set_upload_status(&self, file_name: &str, status: UploadStatus);
+    /// Remux state is only changed here, `update_recording` leaves it alone
+    async fn set_remux_pending(&self, id: i64, pending: bool);
+    /// Recordings still waiting for their remux, oldest first
+    async fn pending_remuxes(&self) -> Vec<Recording>;
+    /// Number of locked or unlocked recordings and their combined size in bytes
+    async fn recordings_usage(&self, locked: bool) -> (u64, u64);
+}
+
+#[async_trait::async_trait]
+pub trait UploadQueueStorage {
+    async fn enqueue_upload(&self, file_name: &str);
+    /// Jobs whose next attempt is due at `now` (unix ms)
+    async fn due_uploads(&self, now: i64) -> Vec<UploadJob>;
+    async fn list_uploads(&self) -> Vec<UploadJob>;
+    async fn complete_upload(&self, file_name: &str);
+    async fn fail_upload(&self, file_name: &str, error: &str, next_attempt: i64);
+}
+
+#[async_trait::async_trait]
+pub trait TimelapseStorage {
+    /// Returns the id of the new library entry
+    async fn create_timelapse(&self, timelapse: &Timelapse) -> Option<i64>;
+    async fn update_timelapse(&self, timelapse: &Timelapse);
+    async fn get_timelapse(&self, id: i64) -> Option<Timelapse>;
+    /// Newest first
+    async fn list_timelapses(&self) -> Vec<Timelapse>;
+    async fn delete_timelapse(&self, id: i64);
+}
+
 #[async_trait::async_trait]
 pub trait Observable<T> {
     async fn subscribe(&self) -> Receiver<T>;
@@ -1,7 +1,7 @@
 use std::sync::Arc;
-use serde::Serialize;
+use serde::{de::DeserializeOwned, Serialize};
 use sqlx::{migrate::MigrateDatabase, sqlite::SqliteRow, Encode, Row, Sqlite, SqlitePool};
-use super::{SimpleStorage, UserStorage};
+use super::{ControlStorage, RecordingStorage, SimpleStorage, TimelapseStorage, UploadQueueStorage, UserStorage};
 use crate::models::*;
 use log::*;
 
@@ -121,6 +121,401 @@ impl UserStorage for SQLiteStorage {
 }
 
 
+#[async_trait::async_trait]
+impl ControlStorage for SQLiteStorage {
+    async fn get_controls(&self, device_id: &str) -> Vec<ControlValue> {
+        let res = sqlx::query("SELECT control_id, value FROM camera_controls WHERE device_id = $1;")
+            .bind(device_id)
+            .fetch_all(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch camera controls for {device_id}: {e:?}");
+                Vec::new()}
+            );
+
+        res.into_iter()
+            .map(|r| ControlValue {
+                id: r.get::<u32, &str>("control_id"),
+                value: r.get::<i64, &str>("value")
+            })
+            .collect()
+    }
+
+    async fn set_controls(&self, device_id: &str, controls: &[ControlValue]) {
+        for control in controls {
+            let _ = sqlx::query("INSERT INTO camera_controls (device_id, control_id, value) values ($1, $2, $3)
+                ON CONFLICT(device_id, control_id) DO UPDATE SET value=excluded.value")
+                .bind(device_id)
+                .bind(control.id)
+                .bind(control.value)
+                .execute(self.db.as_ref())
+                .await
+                .map_err(|e| {
+                    error!("Error saving camera control {} for {device_id}: {e:?}", control.id)
+                });
+        }
+    }
+}
+
+const RECORDING_COLUMNS: &str = "id, file_name, start_time, end_time, end_time - start_time AS duration, size, width, height, camera, closed, locked, upload_status, faststart, remux_pending";
+const RECORDING_FILTER: &str = "WHERE ($1 IS NULL OR end_time IS NULL OR end_time >= $1)
+    AND ($2 IS NULL OR start_time <= $2)
+    AND ($3 IS NULL OR camera = $3)
+    AND ($4 IS NULL OR locked = $4)";
+
+fn recording_from_row(r: SqliteRow) -> Recording {
+    Recording {
+        id: r.get::<i64, &str>("id"),
+        file_name: r.get::<String, &str>("file_name"),
+        start_time: r.get::<i64, &str>("start_time"),
+        end_time: r.get::<Option<i64>, &str>("end_time"),
+        duration: r.get::<Option<i64>, &str>("duration"),
+        size: r.get::<i64, &str>("size") as u64,
+        width: r.get::<Option<u32>, &str>("width"),
+        height: r.get::<Option<u32>, &str>("height"),
+        camera: r.get::<Option<String>, &str>("camera"),
+        closed: r.get::<bool, &str>("closed"),
+        locked: r.get::<bool, &str>("locked"),
+        upload_status: r.get::<Option<UploadStatus>, &str>("upload_status"),
+        faststart: r.get::<bool, &str>("faststart"),
+        remux_pending: r.get::<bool, &str>("remux_pending")
+    }
+}
+
+#[async_trait::async_trait]
+impl RecordingStorage for SQLiteStorage {
+    async fn create_recording(&self, recording: &Recording) -> Option<i64> {
+        sqlx::query("INSERT INTO recordings (file_name, start_time, end_time, size, width, height, camera, closed)
+            values ($1, $2, $3, $4, $5, $6, $7, $8)")
+            .bind(&recording.file_name)
+            .bind(recording.start_time)
+            .bind(recording.end_time)
+            .bind(recording.size as i64)
+            .bind(recording.width)
+            .bind(recording.height)
+            .bind(&recording.camera)
+            .bind(recording.closed)
+            .execute(self.db.as_ref())
+            .await
+            .map(|r| r.last_insert_rowid())
+            .map_err(|e| {
+                error!("Error indexing recording {}: {e:?}", recording.file_name)
+            })
+            .ok()
+    }
+
+    async fn update_recording(&self, recording: &Recording) {
+        let _ = sqlx::query("UPDATE recordings SET end_time=$1, size=$2, width=$3, height=$4, camera=$5, closed=$6, faststart=$7 WHERE id=$8")
+            .bind(recording.end_time)
+            .bind(recording.size as i64)
+            .bind(recording.width)
+            .bind(recording.height)
+            .bind(&recording.camera)
+            .bind(recording.closed)
+            .bind(recording.faststart)
+            .bind(recording.id)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error updating recording {}: {e:?}", recording.file_name)
+            });
+    }
+
+    async fn get_recording(&self, file_name: &str) -> Option<Recording> {
+        Some(recording_from_row(sqlx::query(&format!("SELECT {RECORDING_COLUMNS} FROM recordings WHERE file_name = $1;"))
+            .bind(file_name)
+            .fetch_one(self.db.as_ref())
+            .await
+            .ok()?))
+    }
+
+    async fn get_recording_by_id(&self, id: i64) -> Option<Recording> {
+        Some(recording_from_row(sqlx::query(&format!("SELECT {RECORDING_COLUMNS} FROM recordings WHERE id = $1;"))
+            .bind(id)
+            .fetch_one(self.db.as_ref())
+            .await
+            .ok()?))
+    }
+
+    async fn delete_recording(&self, file_name: &str) {
+        let _ = sqlx::query("DELETE FROM recordings WHERE file_name=$1")
+            .bind(file_name)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error deleting recording {file_name}: {e:?}")
+            });
+    }
+
+    async fn list_recordings(&self, query: &RecordingQuery) -> RecordingPage {
+        let total: i64 = sqlx::query_scalar(&format!("SELECT COUNT(*) FROM recordings {RECORDING_FILTER};"))
+            .bind(query.from)
+            .bind(query.to)
+            .bind(&query.camera)
+            .bind(query.locked)
+            .fetch_one(self.db.as_ref())
+            .await
+            .unwrap_or_default();
+
+        // Sort column and direction come from enums, never from user strings
+        let sort = match query.sort {
+            RecordingSort::StartTime => "start_time",
+            RecordingSort::Duration => "duration",
+            RecordingSort::Size => "size",
+        };
+        let order = match query.order {
+            SortOrder::Asc => "ASC",
+            SortOrder::Desc => "DESC",
+        };
+        let res = sqlx::query(&format!("SELECT {RECORDING_COLUMNS} FROM recordings {RECORDING_FILTER}
+            ORDER BY {sort} {order}, id {order} LIMIT $5 OFFSET $6;"))
+            .bind(query.from)
+            .bind(query.to)
+            .bind(&query.camera)
+            .bind(query.locked)
+            .bind(query.limit)
+            .bind(query.offset)
+            .fetch_all(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch recordings: {e:?}");
+                Vec::new()}
+            );
+
+        RecordingPage {
+            total: total as u64,
+            items: res.into_iter().map(recording_from_row).collect()
+        }
+    }
+
+    async fn all_recordings(&self) -> Vec<Recording> {
+        let res = sqlx::query(&format!("SELECT {RECORDING_COLUMNS} FROM recordings ORDER BY start_time;"))
+            .fetch_all(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch recordings: {e:?}");
+                Vec::new()}
+            );
+
+        res.into_iter().map(recording_from_row).collect()
+    }
+
+    async fn set_locked(&self, id: i64, locked: bool) {
+        let _ = sqlx::query("UPDATE recordings SET locked=$1 WHERE id=$2")
+            .bind(locked)
+            .bind(id)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error locking recording {id}: {e:?}")
+            });
+    }
+
+    async fn set_upload_status(&self, file_name: &str, status: UploadStatus) {
+        let _ = sqlx::query("UPDATE recordings SET upload_status=$1 WHERE file_name=$2")
+            .bind(status)
+            .bind(file_name)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error updating upload status of {file_name}: {e:?}")
+            });
+    }
+
+    async fn set_remux_pending(&self, id: i64, pending: bool) {
+        let _ = sqlx::query("UPDATE recordings SET remux_pending=$1 WHERE id=$2")
+            .bind(pending)
+            .bind(id)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error updating remux state of recording {id}: {e:?}")
+            });
+    }
+
+    async fn pending_remuxes(&self) -> Vec<Recording> {
+        let res = sqlx::query(&format!("SELECT {RECORDING_COLUMNS} FROM recordings WHERE remux_pending = TRUE ORDER BY start_time;"))
+            .fetch_all(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch pending remuxes: {e:?}");
+                Vec::new()}
+            );
+
+        res.into_iter().map(recording_from_row).collect()
+    }
+
+    async fn recordings_usage(&self, locked: bool) -> (u64, u64) {
+        let (count, size): (i64, i64) = sqlx::query_as("SELECT COUNT(*), COALESCE(SUM(size), 0) FROM recordings WHERE locked = $1;")
+            .bind(locked)
+            .fetch_one(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch recordings usage: {e:?}");
+                (0, 0)
+            });
+        (count as u64, size as u64)
+    }
+}
+
+fn upload_job_from_row(r: SqliteRow) -> UploadJob {
+    UploadJob {
+        file_name: r.get::<String, &str>("file_name"),
+        attempts: r.get::<u32, &str>("attempts"),
+        next_attempt: r.get::<i64, &str>("next_attempt"),
+        last_error: r.get::<Option<String>, &str>("last_error")
+    }
+}
+
+#[async_trait::async_trait]
+impl UploadQueueStorage for SQLiteStorage {
+    async fn enqueue_upload(&self, file_name: &str) {
+        let _ = sqlx::query("INSERT INTO upload_queue (file_name, next_attempt) values ($1, $2)
+            ON CONFLICT(file_name) DO NOTHING")
+            .bind(file_name)
+            .bind(chrono::Utc::now().timestamp_millis())
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error queueing upload of {file_name}: {e:?}")
+            });
+    }
+
+    async fn due_uploads(&self, now: i64) -> Vec<UploadJob> {
+        let res = sqlx::query("SELECT file_name, attempts, next_attempt, last_error FROM upload_queue
+            WHERE next_attempt <= $1 ORDER BY next_attempt;")
+            .bind(now)
+            .fetch_all(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch upload queue: {e:?}");
+                Vec::new()}
+            );
+
+        res.into_iter().map(upload_job_from_row).collect()
+    }
+
+    async fn list_uploads(&self) -> Vec<UploadJob> {
+        let res = sqlx::query("SELECT file_name, attempts, next_attempt, last_error FROM upload_queue ORDER BY next_attempt;")
+            .fetch_all(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch upload queue: {e:?}");
+                Vec::new()}
+            );
+
+        res.into_iter().map(upload_job_from_row).collect()
+    }
+
+    async fn complete_upload(&self, file_name: &str) {
+        let _ = sqlx::query("DELETE FROM upload_queue WHERE file_name=$1")
+            .bind(file_name)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error removing {file_name} from upload queue: {e:?}")
+            });
+    }
+
+    async fn fail_upload(&self, file_name: &str, error: &str, next_attempt: i64) {
+        let _ = sqlx::query("UPDATE upload_queue SET attempts=attempts + 1, last_error=$1, next_attempt=$2 WHERE file_name=$3")
+            .bind(error)
+            .bind(next_attempt)
+            .bind(file_name)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error updating upload of {file_name}: {e:?}")
+            });
+    }
+}
+
+const TIMELAPSE_COLUMNS: &str = "id, source, from_time, to_time, interval, fps, state, progress, frames, size, error, created_at";
+
+fn timelapse_from_row(r: SqliteRow) -> Timelapse {
+    Timelapse {
+        id: r.get::<i64, &str>("id"),
+        source: r.get::<TimelapseSource, &str>("source"),
+        from: r.get::<i64, &str>("from_time"),
+        to: r.get::<i64, &str>("to_time"),
+        interval: r.get::<i64, &str>("interval") as u64,
+        fps: r.get::<u32, &str>("fps"),
+        state: r.get::<TimelapseState, &str>("state"),
+        progress: r.get::<f64, &str>("progress"),
+        frames: r.get::<u32, &str>("frames"),
+        size: r.get::<i64, &str>("size") as u64,
+        error: r.get::<Option<String>, &str>("error"),
+        created_at: r.get::<i64, &str>("created_at")
+    }
+}
+
+#[async_trait::async_trait]
+impl TimelapseStorage for SQLiteStorage {
+    async fn create_timelapse(&self, timelapse: &Timelapse) -> Option<i64> {
+        sqlx::query("INSERT INTO timelapses (source, from_time, to_time, interval, fps, state, created_at)
+            values ($1, $2, $3, $4, $5, $6, $7)")
+            .bind(timelapse.source)
+            .bind(timelapse.from)
+            .bind(timelapse.to)
+            .bind(timelapse.interval as i64)
+            .bind(timelapse.fps)
+            .bind(timelapse.state)
+            .bind(timelapse.created_at)
+            .execute(self.db.as_ref())
+            .await
+            .map(|r| r.last_insert_rowid())
+            .map_err(|e| {
+                error!("Error creating timelapse: {e:?}")
+            })
+            .ok()
+    }
+
+    async fn update_timelapse(&self, timelapse: &Timelapse) {
+        let _ = sqlx::query("UPDATE timelapses SET state=$1, progress=$2, frames=$3, size=$4, error=$5 WHERE id=$6")
+            .bind(timelapse.state)
+            .bind(timelapse.progress)
+            .bind(timelapse.frames)
+            .bind(timelapse.size as i64)
+            .bind(&timelapse.error)
+            .bind(timelapse.id)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error updating timelapse {}: {e:?}", timelapse.id)
+            });
+    }
+
+    async fn get_timelapse(&self, id: i64) -> Option<Timelapse> {
+        Some(timelapse_from_row(sqlx::query(&format!("SELECT {TIMELAPSE_COLUMNS} FROM timelapses WHERE id = $1;"))
+            .bind(id)
+            .fetch_one(self.db.as_ref())
+            .await
+            .ok()?))
+    }
+
+    async fn list_timelapses(&self) -> Vec<Timelapse> {
+        let res = sqlx::query(&format!("SELECT {TIMELAPSE_COLUMNS} FROM timelapses ORDER BY created_at DESC, id DESC;"))
+            .fetch_all(self.db.as_ref())
+            .await
+            .unwrap_or_else(|e| {
+                error!("Failed to fetch timelapses: {e:?}");
+                Vec::new()}
+            );
+
+        res.into_iter().map(timelapse_from_row).collect()
+    }
+
+    async fn delete_timelapse(&self, id: i64) {
+        let _ = sqlx::query("DELETE FROM timelapses WHERE id=$1")
+            .bind(id)
+            .execute(self.db.as_ref())
+            .await
+            .map_err(|e| {
+                error!("Error deleting timelapse {id}: {e:?}")
+            });
+    }
+}
+
 async fn fetch_config(key: &str, db: &SqlitePool) -> Option<SqliteRow>{
     let row = sqlx::query("SELECT key, value FROM config WHERE key = $1")
         .bind(key)
@@ -141,6 +536,17 @@ async fn fetch_config(key: &str, db: &SqlitePool) -> Option<SqliteRow>{
 
 }
 
+async fn fetch_json_config<T: DeserializeOwned + Default>(key: &str, db: &SqlitePool) -> T {
+    fetch_config(key, db)
+        .await
+        .map(|r| {
+            let ret: sqlx::types::JsonValue = r.get("value");
+            ret
+            })
+        .map(|j| serde_json::from_value(j).ok())
+        .flatten().unwrap_or_default()
+}
+
 async fn set_config<'a, T: sqlx::Type<Sqlite> + Encode<'a, Sqlite>>(key: &'a str, value: &'a T, db: &SqlitePool ) {
     if let Err(e) = sqlx::query("INSERT INTO config(key, value) VALUES ($1, $2)")
         .bind(key)
@@ -204,4 +610,82 @@ impl SimpleStorage<FileSinkConfig> for SQLiteStorage {
     async fn set(&self, value: &FileSinkConfig) {
         update_paramter(FILE_SINK_CONFIG, &Some(value), self.db.as_ref()).await;
     }
-}
\ No newline at end of file
+}
+
+
+const CAMERA_PROFILES: &str = "camera_profiles";
+#[async_trait::async_trait]
+impl SimpleStorage<Vec<CameraProfile>> for SQLiteStorage {
+    async fn get(&self) -> Vec<CameraProfile> {
+        fetch_json_config(CAMERA_PROFILES, self.db.as_ref()).await
+    }
+
+    async fn set(&self, value: &Vec<CameraProfile>) {
+        update_paramter(CAMERA_PROFILES, &Some(value), self.db.as_ref()).await;
+    }
+}
+
+
+const PROFILE_SCHEDULE: &str = "profile_schedule";
+#[async_trait::async_trait]
+impl SimpleStorage<ProfileSchedule> for SQLiteStorage {
+    async fn get(&self) -> ProfileSchedule {
+        fetch_json_config(PROFILE_SCHEDULE, self.db.as_ref()).await
+    }
+
+    async fn set(&self, value: &ProfileSchedule) {
+        update_paramter(PROFILE_SCHEDULE, &Some(value), self.db.as_ref()).await;
+    }
+}
+
+
+const PIPELINE_STATUS: &str = "pipeline_status";
+#[async_trait::async_trait]
+impl SimpleStorage<PipelineStatus> for SQLiteStorage {
+    async fn get(&self) -> PipelineStatus {
+        fetch_json_config(PIPELINE_STATUS, self.db.as_ref()).await
+    }
+
+    async fn set(&self, value: &PipelineStatus) {
+        update_paramter(PIPELINE_STATUS, &Some(value), self.db.as_ref()).await;
+    }
+}
+
+
+const RETENTION_LOG: &str = "retention_log";
+#[async_trait::async_trait]
+impl SimpleStorage<Vec<RetentionEvent>> for SQLiteStorage {
+    async fn get(&self) -> Vec<RetentionEvent> {
+        fetch_json_config(RETENTION_LOG, self.db.as_ref()).await
+    }
+
+    async fn set(&self, value: &Vec<RetentionEvent>) {
+        update_paramter(RETENTION_LOG, &Some(value), self.db.as_ref()).await;
+    }
+}
+
+
+const UPLOAD_CONFIG: &str = "upload_config";
+#[async_trait::async_trait]
+impl SimpleStorage<UploadConfig> for SQLiteStorage {
+    async fn get(&self) -> UploadConfig {
+        fetch_json_config(UPLOAD_CONFIG, self.db.as_ref()).await
+    }
+
+    async fn set(&self, value: &UploadConfig) {
+        update_paramter(UPLOAD_CONFIG, &Some(value), self.db.as_ref()).await;
+    }
+}
+
+
+const TIMELAPSE_CONFIG: &str = "timelapse_config";
+#[async_trait::async_trait]
+impl SimpleStorage<TimelapseConfig> for SQLiteStorage {
+    async fn get(&self) -> TimelapseConfig {
+        fetch_json_config(TIMELAPSE_CONFIG, self.db.as_ref()).await
+    }
+
+    async fn set(&self, value: &TimelapseConfig) {
+        update_paramter(TIMELAPSE_CONFIG, &Some(value), self.db.as_ref()).await;
+    }
+}
@@ -0,0 +1,41 @@
+//! Helpers shared by unit tests
+use std::{path::{Path, PathBuf}, sync::atomic::{AtomicU64, Ordering}};
+use crate::storage::Storage;
+
+/// Directory below the system temp dir, removed again when dropped
+pub struct TempDir(PathBuf);
+
+impl TempDir {
+    pub fn new(name: &str) -> Self {
+        static NEXT: AtomicU64 = AtomicU64::new(0);
+        let path = std::env::temp_dir().join(format!(
+            "picam-{}-{name}-{}",
+            std::process::id(),
+            NEXT.fetch_add(1, Ordering::Relaxed)
+        ));
+        std::fs::create_dir_all(&path).unwrap();
+        Self(path)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.0
+    }
+
+    /// Path in the form the app_data setting is passed around
+    pub fn app_data(&self) -> String {
+        self.0.to_string_lossy().into_owned()
+    }
+}
+
+impl Drop for TempDir {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_dir_all(&self.0);
+    }
+}
+
+/// Storage on a fresh in-memory database with all migrations applied, using `app_data` for files
+pub async fn storage(app_data: &TempDir) -> Storage {
+    let mut storage = Storage::new_sqlite("sqlite::memory:").await;
+    storage.config.app_data = app_data.app_data();
+    storage
+}
@@ -0,0 +1,126 @@
+use std::path::{Path, PathBuf};
+use gstreamer::{prelude::*, ClockTime, SeekFlags, State};
+use gstreamer_app::AppSink;
+use log::*;
+use crate::models::Recording;
+
+/// Cached thumbnails and preview frames of recordings
+pub const THUMBNAIL_DIR: &str = "thumbnails";
+const THUMBNAIL_WIDTH: u32 = 320;
+const MAX_PREVIEWS: u64 = 20;
+const FRAME_TIMEOUT: ClockTime = ClockTime::from_seconds(5);
+
+pub fn thumbnail_path(app_data: &str, id: i64) -> PathBuf {
+    crate::file_sink::private_dir(app_data, THUMBNAIL_DIR).join(format!("{id}.jpg"))
+}
+
+/// Preview `index` is taken `index * preview_interval` seconds into the recording
+pub fn preview_path(app_data: &str, id: i64, index: u64) -> PathBuf {
+    crate::file_sink::private_dir(app_data, THUMBNAIL_DIR).join(format!("{id}-{index}.jpg"))
+}
+
+/// Generates the thumbnail and preview frames of a closed recording
+pub async fn generate(app_data: &str, recording: &Recording, preview_interval: Option<u64>) -> Result<(), String> {
+    let mut positions = vec![ClockTime::ZERO];
+    if let (Some(interval), Some(duration)) = (preview_interval.filter(|i| *i > 0), recording.duration) {
+        let count = (duration.max(0) as u64 / 1000 / interval).min(MAX_PREVIEWS);
+        positions.extend((1..=count).map(|i| ClockTime::from_seconds(i * interval)));
+    }
+
+    let path = crate::file_sink::recording_path(app_data, recording);
+    let frames = tokio::task::spawn_blocking(move || extract_frames(&path, &positions, Some(THUMBNAIL_WIDTH)))
+        .await
+        .map_err(|e| format!("Thumbnail task failed {e:?}"))??;
+
+    tokio::fs::create_dir_all(crate::file_sink::private_dir(app_data, THUMBNAIL_DIR)).await
+        .map_err(|e| format!("Failed to create thumbnail directory {e:?}"))?;
+    for (index, frame) in frames.iter().enumerate() {
+        let target = match index {
+            0 => thumbnail_path(app_data, recording.id),
+            i => preview_path(app_data, recording.id, i as u64),
+        };
+        // Written under a temporary name so concurrent readers never see half a jpeg
+        let tmp = target.with_extension("jpg.tmp");
+        tokio::fs::write(&tmp, frame).await
+            .map_err(|e| format!("Failed to write thumbnail {tmp:?} {e:?}"))?;
+        tokio::fs::rename(&tmp, &target).await
+            .map_err(|e| format!("Failed to write thumbnail {target:?} {e:?}"))?;
+    }
+    Ok(())
+}
+
+/// Returns the thumbnail of a recording, generating it on first use
+pub async fn thumbnail(app_data: &str, recording: &Recording, preview_interval: Option<u64>) -> Result<PathBuf, String> {
+    let path = thumbnail_path(app_data, recording.id);
+    if !tokio::fs::try_exists(&path).await.unwrap_or(false) {
+        generate(app_data, recording, preview_interval).await?;
+    }
+    Ok(path)
+}
+
+/// Removes the thumbnail and preview frames of a recording
+pub async fn remove(app_data: &str, id: i64) {
+    let mut files = match tokio::fs::read_dir(crate::file_sink::private_dir(app_data, THUMBNAIL_DIR)).await {
+        Ok(files) => files,
+        Err(_) => return,
+    };
+    let thumbnail = format!("{id}.jpg");
+    let preview_prefix = format!("{id}-");
+    while let Ok(Some(file)) = files.next_entry().await {
+        let name = file.file_name();
+        let name = name.to_string_lossy();
+        if name == thumbnail || name.starts_with(&preview_prefix) {
+            if let Err(e) = tokio::fs::remove_file(file.path()).await {
+                warn!("Failed to remove thumbnail {:?} {e:?}", file.path());
+            }
+        }
+    }
+}
+
+/// Decodes the keyframes at `positions` and encodes them as jpeg, scaled to `width` if set
+pub fn extract_frames(path: &Path, positions: &[ClockTime], width: Option<u32>) -> Result<Vec<Vec<u8>>, String> {
+    let scale = width.map(|w| format!("width={w},")).unwrap_or_default();
+    let pipeline = gstreamer::parse::launch(&format!(
+        "filesrc name=src ! decodebin ! videoconvert ! videoscale ! \
+        video/x-raw,{scale}pixel-aspect-ratio=1/1 ! jpegenc ! appsink name=sink sync=false"
+    ))
+        .map_err(|e| format!("Failed to create thumbnail pipeline {e:?}"))?
+        .downcast::<gstreamer::Pipeline>()
+        .map_err(|_| "Thumbnail pipeline is not a pipeline".to_string())?;
+
+    let src = pipeline.by_name("src").ok_or("Thumbnail pipeline has no source")?;
+    src.set_property("location", path.to_string_lossy().as_ref());
+    let sink = pipeline.by_name("sink")
+        .and_then(|s| s.downcast::<AppSink>().ok())
+        .ok_or("Thumbnail pipeline has no sink")?;
+
+    let frames = (|| {
+        pipeline.set_state(State::Paused)
+            .map_err(|e| format!("Failed to open {path:?} {e:?}"))?;
+
+        let mut frames = Vec::new();
+        for position in positions {
+            if *position > ClockTime::ZERO {
+                let _ = pipeline.state(FRAME_TIMEOUT);
+                if pipeline.seek_simple(SeekFlags::FLUSH | SeekFlags::KEY_UNIT, *position).is_err() {
+                    warn!("Failed to seek {path:?} to {position}");
+                    break;
+                }
+            }
+            let Some(sample) = sink.try_pull_preroll(FRAME_TIMEOUT) else {
+                break;
+            };
+            let buffer = sample.buffer().ok_or("Thumbnail sample has no buffer")?;
+            let map = buffer.map_readable().map_err(|e| format!("Failed to map thumbnail {e:?}"))?;
+            frames.push(map.as_slice().to_vec());
+        }
+        Ok::<_, String>(frames)
+    })();
+
+    let _ = pipeline.set_state(State::Null);
+    let frames = frames?;
+    if frames.is_empty() {
+        return Err(format!("No frame decoded from {path:?}"));
+    }
+    Ok(frames)
+}
@@ -0,0 +1,355 @@
+use std::{path::{Path, PathBuf}, sync::Arc, time::Duration};
+use gstreamer::{prelude::*, ClockTime, MessageView, State};
+use log::*;
+use tokio::sync::{broadcast::{error::RecvError, Sender}, RwLock, Semaphore};
+use crate::{file_sink, models::*, storage::Storage, thumbnails, video::InitSegment, MessageType, ParsedBuffer};
+
+/// Rendered timelapses and the frames sampled for them
+pub const TIMELAPSE_DIR: &str = "timelapses";
+const MAX_FRAMES: u64 = 100_000;
+const MAX_FPS: u32 = 120;
+/// The recordings of a timelapse are listed in one query before sampling starts, MAX_FRAMES bounds the work but not that list
+const MAX_TIMELAPSE_RECORDINGS: u32 = 10_000;
+/// How long a live sample waits for the next keyframe
+const KEYFRAME_TIMEOUT: Duration = Duration::from_secs(30);
+/// Part of the progress taken by sampling, the rest is encoding
+const SAMPLING_PROGRESS: f64 = 0.9;
+
+pub fn timelapse_path(app_data: &str, id: i64) -> PathBuf {
+    file_sink::private_dir(app_data, TIMELAPSE_DIR).join(format!("{id}.mp4"))
+}
+
+/// Sampled frames waiting to be encoded
+fn frames_dir(app_data: &str, id: i64) -> PathBuf {
+    file_sink::private_dir(app_data, TIMELAPSE_DIR).join(format!("{id}-frames"))
+}
+
+pub fn validate_config(config: &TimelapseConfig) -> Result<(), String> {
+    if config.fps == 0 || config.fps > MAX_FPS {
+        return Err(format!("fps has to be between 1 and {MAX_FPS}"));
+    }
+    if config.width == Some(0) {
+        return Err("width has to be positive".to_string());
+    }
+    Ok(())
+}
+
+/// Jobs don't survive a restart, marks the interrupted ones as failed
+pub async fn recover(app_data: &str, storage: &Storage) {
+    for mut timelapse in storage.timelapses.list_timelapses().await {
+        if !matches!(timelapse.state, TimelapseState::Queued | TimelapseState::Running) {
+            continue;
+        }
+        warn!("Timelapse {} was interrupted by a restart", timelapse.id);
+        let _ = tokio::fs::remove_dir_all(frames_dir(app_data, timelapse.id)).await;
+        let _ = tokio::fs::remove_file(timelapse_path(app_data, timelapse.id)).await;
+        timelapse.state = TimelapseState::Failed;
+        timelapse.error = Some("Interrupted by a restart".to_string());
+        storage.timelapses.update_timelapse(&timelapse).await;
+    }
+}
+
+/// Deletes finished timelapses beyond the library's age, count and size limits, the newest are kept
+pub async fn enforce(app_data: &str, storage: &Storage) {
+    let config = storage.timelapse_config.get().await;
+    let now = chrono::Utc::now().timestamp_millis();
+    let mut count = 0;
+    let mut total_bytes = 0;
+    for timelapse in storage.timelapses.list_timelapses().await {
+        if !matches!(timelapse.state, TimelapseState::Done | TimelapseState::Failed) {
+            continue;
+        }
+        count += 1;
+        total_bytes += timelapse.size;
+
+        let expired = config.max_age.is_some_and(|age| timelapse.created_at + age as i64 * 1000 < now)
+            || config.max_count.is_some_and(|max| count > max)
+            || config.max_total_bytes.is_some_and(|max| total_bytes > max);
+        if !expired {
+            continue;
+        }
+        let path = timelapse_path(app_data, timelapse.id);
+        match tokio::fs::remove_file(&path).await {
+            Ok(()) => {},
+            Err(e) if e.kind() == std::io::ErrorKind::NotFound => {},
+            Err(e) => {
+                error!("Failed to remove timelapse {path:?} {e:?}");
+                continue;
+            }
+        }
+        info!("Timelapse retention deleted timelapse {}", timelapse.id);
+        storage.timelapses.delete_timelapse(timelapse.id).await;
+    }
+}
+
+/// Starts timelapse jobs, only one at a time decodes recordings or encodes
+#[derive(Clone)]
+pub struct Timelapses {
+    storage: Arc<Storage>,
+    live: Sender<Arc<ParsedBuffer>>,
+    moov: Arc<RwLock<InitSegment>>,
+    running: Arc<Semaphore>
+}
+
+impl Timelapses {
+    pub fn new(storage: Arc<Storage>, live: Sender<Arc<ParsedBuffer>>, moov: Arc<RwLock<InitSegment>>) -> Self {
+        Self {
+            storage,
+            live,
+            moov,
+            running: Arc::new(Semaphore::new(1))
+        }
+    }
+
+    /// Adds a timelapse to the library and samples it in the background
+    pub async fn start(&self, request: TimelapseRequest) -> Result<Timelapse, String> {
+        let config = self.storage.timelapse_config.get().await;
+        let now = chrono::Utc::now().timestamp_millis();
+        let from = match request.source {
+            TimelapseSource::Live => request.from.unwrap_or(now).max(now),
+            TimelapseSource::Recordings => request.from.ok_or("from is required for recordings")?,
+        };
+        if request.interval == 0 {
+            return Err("interval has to be at least a second".to_string());
+        }
+        if from >= request.to {
+            return Err("from has to be before to".to_string());
+        }
+        if (request.to - from) as u64 / 1000 / request.interval > MAX_FRAMES {
+            return Err(format!("A timelapse can't have more than {MAX_FRAMES} frames"));
+        }
+        let fps = request.fps.unwrap_or(config.fps);
+        if fps == 0 || fps > MAX_FPS {
+            return Err(format!("fps has to be between 1 and {MAX_FPS}"));
+        }
+
+        let mut timelapse = Timelapse {
+            source: request.source,
+            from,
+            to: request.to,
+            interval: request.interval,
+            fps,
+            created_at: now,
+            ..Default::default()
+        };
+        timelapse.id = self.storage.timelapses.create_timelapse(&timelapse).await
+            .ok_or("Failed to add timelapse to the library")?;
+
+        let timelapses = self.clone();
+        let job = timelapse.clone();
+        tokio::spawn(async move {
+            timelapses.run(job).await;
+        });
+        Ok(timelapse)
+    }
+
+    async fn run(&self, mut timelapse: Timelapse) {
+        let app_data = &self.storage.config.app_data;
+        let dir = frames_dir(app_data, timelapse.id);
+        let result = match tokio::fs::create_dir_all(&dir).await {
+            Err(e) => Err(format!("Failed to create {dir:?} {e:?}")),
+            Ok(()) => self.sample_and_encode(&mut timelapse, &dir).await,
+        };
+        if let Err(e) = tokio::fs::remove_dir_all(&dir).await {
+            warn!("Failed to remove timelapse frames {dir:?} {e:?}");
+        }
+
+        match result {
+            Ok(size) => {
+                info!("Timelapse {} finished with {} frames", timelapse.id, timelapse.frames);
+                timelapse.state = TimelapseState::Done;
+                timelapse.progress = 1.0;
+                timelapse.size = size;
+            },
+            Err(e) => {
+                warn!("Timelapse {} failed {e}", timelapse.id);
+                let _ = tokio::fs::remove_file(timelapse_path(app_data, timelapse.id)).await;
+                timelapse.state = TimelapseState::Failed;
+                timelapse.error = Some(e);
+            }
+        }
+        self.storage.timelapses.update_timelapse(&timelapse).await;
+        enforce(app_data, &self.storage).await;
+    }
+
+    /// Returns the size of the encoded timelapse
+    async fn sample_and_encode(&self, timelapse: &mut Timelapse, dir: &Path) -> Result<u64, String> {
+        let width = self.storage.timelapse_config.get().await.width;
+
+        // Live sampling mostly waits, it doesn't hold up other jobs until it's time to encode
+        if timelapse.source == TimelapseSource::Live {
+            self.set_running(timelapse).await;
+            self.sample_live(timelapse, dir, width).await?;
+        }
+        let _permit = self.running.acquire().await
+            .map_err(|e| format!("Timelapse queue closed {e:?}"))?;
+        if timelapse.source == TimelapseSource::Recordings {
+            self.set_running(timelapse).await;
+            self.sample_recordings(timelapse, dir, width).await?;
+        }
+        if timelapse.frames == 0 {
+            return Err("No frames found in the requested range".to_string());
+        }
+
+        let target = timelapse_path(&self.storage.config.app_data, timelapse.id);
+        let frames = dir.to_path_buf();
+        let fps = timelapse.fps;
+        let encoded = target.clone();
+        tokio::task::spawn_blocking(move || encode(&frames, &encoded, fps))
+            .await
+            .map_err(|e| format!("Timelapse task failed {e:?}"))??;
+
+        tokio::fs::metadata(&target).await
+            .map(|m| m.len())
+            .map_err(|e| format!("Failed to read {target:?} {e:?}"))
+    }
+
+    async fn set_running(&self, timelapse: &mut Timelapse) {
+        timelapse.state = TimelapseState::Running;
+        self.storage.timelapses.update_timelapse(timelapse).await;
+    }
+
+    /// Stores the frame sampled at `time` (unix ms) under the next number
+    async fn save_frame(&self, timelapse: &mut Timelapse, dir: &Path, frame: &[u8], time: i64) -> Result<(), String> {
+        let path = dir.join(format!("{:06}.jpg", timelapse.frames));
+        tokio::fs::write(&path, frame).await
+            .map_err(|e| format!("Failed to write frame {path:?} {e:?}"))?;
+        timelapse.frames += 1;
+        let sampled = (time - timelapse.from) as f64 / (timelapse.to - timelapse.from).max(1) as f64;
+        timelapse.progress = sampled.clamp(0.0, 1.0) * SAMPLING_PROGRESS;
+        self.storage.timelapses.update_timelapse(timelapse).await;
+        Ok(())
+    }
+
+    /// Samples `from + n * interval` from the recordings on disk, gaps between recordings are skipped
+    async fn sample_recordings(&self, timelapse: &mut Timelapse, dir: &Path, width: Option<u32>) -> Result<(), String> {
+        let app_data = &self.storage.config.app_data;
+        let recordings = self.storage.recordings.list_recordings(&RecordingQuery {
+            from: Some(timelapse.from),
+            to: Some(timelapse.to),
+            sort: RecordingSort::StartTime,
+            order: SortOrder::Asc,
+            limit: MAX_TIMELAPSE_RECORDINGS,
+            ..Default::default()
+        }).await.items;
+
+        let interval = timelapse.interval as i64 * 1000;
+        for recording in recordings {
+            let start = recording.start_time.max(timelapse.from);
+            let end = recording.end_time.unwrap_or(timelapse.to).min(timelapse.to);
+            // First point of the sampling grid inside the recording
+            let first = timelapse.from + (start - timelapse.from + interval - 1) / interval * interval;
+            let times: Vec<i64> = (first..end).step_by(interval as usize).collect();
+            if times.is_empty() {
+                continue;
+            }
+
+            let positions: Vec<ClockTime> = times.iter()
+                .map(|t| ClockTime::from_mseconds((t - recording.start_time) as u64))
+                .collect();
+            let path = file_sink::recording_path(app_data, &recording);
+            let frames = tokio::task::spawn_blocking(move || thumbnails::extract_frames(&path, &positions, width))
+                .await
+                .map_err(|e| format!("Timelapse task failed {e:?}"))?;
+            let frames = match frames {
+                Ok(frames) => frames,
+                Err(e) => {
+                    warn!("Timelapse {} skips recording {} {e}", timelapse.id, recording.file_name);
+                    continue;
+                }
+            };
+            for (frame, time) in frames.iter().zip(times) {
+                self.save_frame(timelapse, dir, frame, time).await?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Samples the live stream every `interval` until `to`, frames missed while the camera is down are skipped
+    async fn sample_live(&self, timelapse: &mut Timelapse, dir: &Path, width: Option<u32>) -> Result<(), String> {
+        let interval = timelapse.interval as i64 * 1000;
+        let mut next = timelapse.from;
+        while next <= timelapse.to {
+            let wait = next - chrono::Utc::now().timestamp_millis();
+            if wait > 0 {
+                tokio::time::sleep(Duration::from_millis(wait as u64)).await;
+            }
+            match self.live_frame(dir, width).await {
+                Ok(frame) => self.save_frame(timelapse, dir, &frame, next).await?,
+                Err(e) => warn!("Timelapse {} missed a frame {e}", timelapse.id),
+            }
+
+            // Stay on the grid when decoding took longer than the interval
+            let now = chrono::Utc::now().timestamp_millis();
+            next += interval;
+            if next < now {
+                next += (now - next + interval - 1) / interval * interval;
+            }
+        }
+        Ok(())
+    }
+
+    /// Decodes the next keyframe of the live stream
+    async fn live_frame(&self, dir: &Path, width: Option<u32>) -> Result<Vec<u8>, String> {
+        let mut recv = self.live.subscribe();
+        let keyframe = tokio::time::timeout(KEYFRAME_TIMEOUT, async {
+            loop {
+                match recv.recv().await {
+                    Ok(buffer) if buffer.message_type == MessageType::KeyFrame => return Some(buffer),
+                    Ok(_) | Err(RecvError::Lagged(_)) => {},
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }).await
+            .ok()
+            .flatten()
+            .ok_or("No keyframe from the live stream")?;
+
+        let init = self.moov.read().await.clone();
+        if init.generation != keyframe.generation {
+            return Err("Pipeline restarted while sampling".to_string());
+        }
+        let mut data = init.packets.concat();
+        data.extend_from_slice(&keyframe.data);
+        let path = dir.join("live.mp4");
+        tokio::fs::write(&path, data).await
+            .map_err(|e| format!("Failed to write {path:?} {e:?}"))?;
+
+        let frames = tokio::task::spawn_blocking(move || thumbnails::extract_frames(&path, &[ClockTime::ZERO], width))
+            .await
+            .map_err(|e| format!("Timelapse task failed {e:?}"))??;
+        frames.into_iter().next().ok_or_else(|| "No frame decoded from the live stream".to_string())
+    }
+}
+
+/// Encodes the numbered jpeg frames in `dir` into an mp4 at `fps`
+fn encode(dir: &Path, target: &Path, fps: u32) -> Result<(), String> {
+    let pipeline = gstreamer::parse::launch(&format!(
+        "multifilesrc name=src index=0 caps=image/jpeg,framerate={fps}/1 ! jpegdec ! videoconvert ! \
+        x264enc speed-preset=veryfast ! h264parse ! mp4mux faststart=true ! filesink name=sink"
+    ))
+        .map_err(|e| format!("Failed to create timelapse pipeline {e:?}"))?
+        .downcast::<gstreamer::Pipeline>()
+        .map_err(|_| "Timelapse pipeline is not a pipeline".to_string())?;
+
+    let src = pipeline.by_name("src").ok_or("Timelapse pipeline has no source")?;
+    src.set_property("location", dir.join("%06d.jpg").to_string_lossy().as_ref());
+    let sink = pipeline.by_name("sink").ok_or("Timelapse pipeline has no sink")?;
+    sink.set_property("location", target.to_string_lossy().as_ref());
+    let bus = pipeline.bus().ok_or("Timelapse pipeline has no bus")?;
+
+    let result = (|| {
+        pipeline.set_state(State::Playing)
+            .map_err(|e| format!("Failed to start timelapse encoding {e:?}"))?;
+        // multifilesrc sends EOS after the last numbered frame
+        let message = bus.timed_pop_filtered(ClockTime::NONE, &[gstreamer::MessageType::Eos, gstreamer::MessageType::Error])
+            .ok_or("Timelapse pipeline stopped without EOS")?;
+        match message.view() {
+            MessageView::Error(e) => Err(format!("Timelapse encoding failed {} {:?}", e.error(), e.debug())),
+            _ => Ok(()),
+        }
+    })();
+
+    let _ = pipeline.set_state(State::Null);
+    result
+}
@@ -0,0 +1,90 @@
+use std::{sync::Arc, time::Duration};
+use tokio::sync::watch;
+use log::*;
+use crate::{backends, file_sink, models::*, retention, shutdown, storage::Storage};
+
+const UPLOAD_INTERVAL: Duration = Duration::from_secs(10);
+const RETRY_BASE: Duration = Duration::from_secs(30);
+const MAX_RETRY: Duration = Duration::from_secs(60 * 60);
+
+/// Queues a closed recording for upload when a remote backend is configured
+pub async fn enqueue(storage: &Storage, recording: &Recording) {
+    if storage.upload_config.get().await.backend != UploadBackend::Local {
+        storage.uploads.enqueue_upload(&recording.file_name).await;
+        storage.recordings.set_upload_status(&recording.file_name, UploadStatus::Pending).await;
+    }
+}
+
+/// Works through the persistent upload queue, failed uploads are retried with backoff
+pub async fn upload_worker(storage: Arc<Storage>, app_data: String, mut shutdown: watch::Receiver<bool>) {
+    loop {
+        tokio::select! {
+            _ = tokio::time::sleep(UPLOAD_INTERVAL) => {},
+            _ = shutdown::requested(&mut shutdown) => {
+                break;
+            }
+        }
+
+        let jobs = storage.uploads.due_uploads(chrono::Utc::now().timestamp_millis()).await;
+        if jobs.is_empty() {
+            continue;
+        }
+
+        let config = storage.upload_config.get().await;
+        let backend = match backends::from_config(&config) {
+            Ok(backend) => backend,
+            Err(e) => {
+                error!("Upload backend is misconfigured {e}");
+                continue;
+            }
+        };
+
+        for job in jobs {
+            if shutdown::is_requested(&shutdown) {
+                break;
+            }
+            upload(&storage, &app_data, &config, backend.as_ref(), job).await;
+        }
+    }
+}
+
+async fn upload(
+    storage: &Storage,
+    app_data: &str,
+    config: &UploadConfig,
+    backend: &(dyn backends::RecordingBackend + Send + Sync),
+    job: UploadJob,
+) {
+    let Some(recording) = storage.recordings.get_recording(&job.file_name).await else {
+        info!("Recording {} was deleted before it was uploaded", job.file_name);
+        storage.uploads.complete_upload(&job.file_name).await;
+        return;
+    };
+    if recording.remux_pending {
+        // The file is about to be replaced, the job stays due and is retried on the next pass
+        debug!("Upload of {} waits for its remux", recording.file_name);
+        return;
+    }
+
+    let path = file_sink::recording_path(app_data, &recording);
+    match backend.store(&path, &recording).await {
+        Ok(()) => {
+            info!("Uploaded {} to {}", recording.file_name, backend.name());
+            storage.uploads.complete_upload(&job.file_name).await;
+            storage.recordings.set_upload_status(&job.file_name, UploadStatus::Uploaded).await;
+            if backend.is_remote() && config.local_copy == LocalCopyPolicy::DeleteAfterUpload && !recording.locked {
+                info!("Removing local copy of uploaded recording {}", recording.file_name);
+                retention::remove_recording(app_data, storage, &recording).await;
+            }
+        },
+        Err(e) => {
+            let delay = RETRY_BASE
+                .saturating_mul(2u32.saturating_pow(job.attempts))
+                .min(MAX_RETRY);
+            warn!("Upload of {} failed, attempt {}, retrying in {delay:?}: {e}", recording.file_name, job.attempts + 1);
+            let next_attempt = chrono::Utc::now().timestamp_millis() + delay.as_millis() as i64;
+            storage.uploads.fail_upload(&job.file_name, &e, next_attempt).await;
+            storage.recordings.set_upload_status(&job.file_name, UploadStatus::Failed).await;
+        }
+    }
+}
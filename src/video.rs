@@ -3,10 +3,10 @@ use std::collections::HashMap;
 use std::sync::Arc;
 
 use gstreamer::Element;
-use tokio::sync::broadcast::{Receiver, Sender};
+use tokio::sync::broadcast::{error::RecvError, Receiver, Sender};
 use log::*;
 
-use gstreamer::{prelude::*, BufferFlags, FlowSuccess};
+use gstreamer::{prelude::*, BufferFlags, ClockTime, FlowError, FlowSuccess};
 use gstreamer::{ElementFactory, Pipeline};
 use gstreamer_app::AppSink;
 use tokio::sync::RwLock;
@@ -14,15 +14,25 @@ use v4l::framesize::FrameSizeEnum;
 
 
 use crate::models::*;
+use crate::models::devices::Capabilties;
 use crate::ParsedBuffer;
 
+const FRAMERATE: u32 = 30;
+/// Default low latency chunk duration in milliseconds
+const DEFAULT_CHUNK_DURATION: u32 = 200;
+/// Target fragment duration in low latency mode, fragments still start on keyframes
+const LOW_LATENCY_FRAGMENT_DURATION: ClockTime = ClockTime::from_seconds(2);
+
 
 #[derive(Clone, Debug)]
 pub struct Config {
     source: String,
     use_cam_builtin_encoder: bool,
     width: i32,
-    height: i32
+    height: i32,
+    pipeline_override: Option<String>,
+    latency_mode: LatencyMode,
+    chunk_duration: u32
 }
 
 
@@ -33,7 +43,7 @@ fn short_pipeline(config: &Config) -> Result<Vec<Element>, String> {
             .field("format", "I420")
             .field("width", config.width)
             .field("height", config.height)
-            .field("framerate",  gstreamer::Fraction::new(30, 1))
+            .field("framerate",  gstreamer::Fraction::new(FRAMERATE as i32, 1))
             .build()
         )
         .build()
@@ -52,7 +62,7 @@ fn long_pipeline(config: &Config) -> Result<Vec<Element>, String> {
             .field("format", "I420")
             .field("width", config.width)
             .field("height", config.height)
-            .field("framerate",  gstreamer::Fraction::new(30, 1))
+            .field("framerate",  gstreamer::Fraction::new(FRAMERATE as i32, 1))
             .build()
         )
         .build()
@@ -72,36 +82,144 @@ fn long_pipeline(config: &Config) -> Result<Vec<Element>, String> {
     Ok(vec![videoconvert, capsfilter, x264enc])
 }
 
-pub fn build_gstreamer_pipline(send: Sender<Arc<ParsedBuffer>>, config: &Config) -> Result<Pipeline, String> {
-    debug!("Createing new pipeline");
-    // Create the elements
-    let pipeline = Pipeline::new();
+/// Fragment collected from the muxer until the next moof arrives
+struct PendingFragment {
+    data: Vec<u8>,
+    /// Whether the first sample after the moof is a keyframe, unknown until it arrives
+    key_frame: Option<bool>,
+    timestamp: Option<ClockTime>,
+    /// Trex defaults of the running pipeline, needed to read sample flags from a moof
+    track: Option<crate::mp4::TrackInfo>
+}
 
-    let v4l2src = ElementFactory::make("v4l2src", )
-        .name("v4l2src")
-        .property("device", config.source.clone())
-        .property("num-buffers", -1)
+impl Default for PendingFragment {
+    fn default() -> Self {
+        Self {
+            data: Vec::with_capacity(1024),
+            key_frame: None,
+            timestamp: None,
+            track: None
+        }
+    }
+}
+
+impl PendingFragment {
+    fn take(&mut self, generation: u64) -> ParsedBuffer {
+        let pending = std::mem::replace(self, Self {
+            track: self.track,
+            ..Default::default()
+        });
+        ParsedBuffer {
+            generation,
+            data: pending.data,
+            message_type: if pending.key_frame == Some(true) {
+                crate::MessageType::KeyFrame
+            } else {
+                crate::MessageType::Fragment
+            },
+            timestamp: pending.timestamp
+        }
+    }
+}
+
+fn mp4mux(fragment_duration: u32) -> Result<Element, String> {
+    ElementFactory::make("mp4mux")
+        .name("mp4mux")
+        .property("streamable", true)
+        .property("force-chunks", true)
+        .property("fragment-duration", fragment_duration)
+        .property("faststart", true)
         .build()
+        .map_err(|e| e.to_string())
+}
+
+fn muxer(config: &Config) -> Result<Element, String> {
+    if config.latency_mode == LatencyMode::Standard {
+        return mp4mux(1);
+    }
+
+    // fmp4 muxers from gst-plugins-rs split keyframe aligned fragments into chunks
+    for factory in ["cmafmux", "isofmp4mux"] {
+        if ElementFactory::find(factory).is_some() {
+            info!("Using {factory} with {}ms chunks", config.chunk_duration);
+            return ElementFactory::make(factory)
+                .name("mp4mux")
+                .property("fragment-duration", LOW_LATENCY_FRAGMENT_DURATION)
+                .property("chunk-duration", ClockTime::from_mseconds(config.chunk_duration as u64))
+                .build()
+                .map_err(|e| e.to_string());
+        }
+    }
+
+    warn!("cmafmux/isofmp4mux not available, using mp4mux with {}ms fragments", config.chunk_duration);
+    mp4mux(config.chunk_duration)
+}
+
+/// Source/convert/encode segment given by the user as a `gst-launch` fragment
+fn custom_pipeline(description: &str) -> Result<Element, String> {
+    let bin = gstreamer::parse::bin_from_description(description, true)
         .map_err(|e| e.to_string())?;
 
-    let video_elements = if config.use_cam_builtin_encoder {
-        short_pipeline(&config)
+    if bin.static_pad("sink").is_some() {
+        return Err("Custom pipeline must start with a source element".to_string());
+    }
+    if bin.static_pad("src").is_none() {
+        return Err("Custom pipeline must have an unlinked src pad".to_string());
+    }
+
+    Ok(bin.upcast())
+}
+
+/// Checks that a config can be turned into a pipeline before it is stored
+pub fn validate_pipeline_config(config: &PipelineConfig) -> Result<(), String> {
+    let Some(ref description) = config.pipeline_override else {
+        return Ok(());
+    };
+
+    gstreamer::init().map_err(|e| e.to_string())?;
+    let custom = custom_pipeline(description)?;
+    let h264parse = ElementFactory::make("h264parse")
+        .build()
+        .map_err(|e| e.to_string())?;
+
+    let pipeline = Pipeline::new();
+    pipeline.add_many(&[&custom, &h264parse])
+        .map_err(|e| e.to_string())?;
+    custom.link_filtered(&h264parse, &gstreamer::Caps::builder("video/x-h264").build())
+        .map_err(|_| "Custom pipeline must end in H.264 ahead of h264parse".to_string())?;
+
+    Ok(())
+}
+
+pub fn build_gstreamer_pipline(send: Sender<Arc<ParsedBuffer>>, config: &Config, generation: u64) -> Result<Pipeline, String> {
+    debug!("Createing new pipeline");
+    // Create the elements
+    let pipeline = Pipeline::new();
+
+    let video_elements = if let Some(ref description) = config.pipeline_override {
+        vec![custom_pipeline(description)?]
     } else {
-        long_pipeline(&config)
-    }?;
+        let v4l2src = ElementFactory::make("v4l2src", )
+            .name("v4l2src")
+            .property("device", config.source.clone())
+            .property("num-buffers", -1)
+            .build()
+            .map_err(|e| e.to_string())?;
+
+        let encoder_elements = if config.use_cam_builtin_encoder {
+            short_pipeline(&config)
+        } else {
+            long_pipeline(&config)
+        }?;
+
+        [vec![v4l2src], encoder_elements].concat()
+    };
 
 
     let h264parse = ElementFactory::make_with_name("h264parse", Some("h264parse"))
         .map_err(|e| e.to_string())?;
 
-    let mpegtsmux = ElementFactory::make("mp4mux")
-        .name("mp4mux")
-        .property("streamable", true)
-        .property("force-chunks", true)
-        .property("fragment-duration", 1u32)
-        .property("faststart", true)
-        .build()
-        .map_err(|e| e.to_string())?;
+    let mpegtsmux = muxer(config)?;
 
     let appsink = AppSink::builder()
         .name("app_sink")
@@ -113,7 +231,6 @@ pub fn build_gstreamer_pipline(send: Sender<Arc<ParsedBuffer>>, config: &Config)
         .map_err(|e| e.to_string())?;
 
     pipeline.add_many(&[
-        &v4l2src,
         &h264parse,
         &mpegtsmux,
         appsink.upcast_ref(),
@@ -121,10 +238,6 @@ pub fn build_gstreamer_pipline(send: Sender<Arc<ParsedBuffer>>, config: &Config)
         .map_err(|e| e.to_string())?;
 
     // Link elements in the pipeline
-    gstreamer::Element::link_many(&[
-        &v4l2src,
-        video_elements.first().unwrap()
-    ]).map_err(|e| e.to_string())?;
     if video_elements.len() > 1 {
         gstreamer::Element::link_many(&video_elements)
             .map_err(|e| e.to_string())?;
@@ -137,96 +250,206 @@ pub fn build_gstreamer_pipline(send: Sender<Arc<ParsedBuffer>>, config: &Config)
     ]).map_err(|e| e.to_string())?;
 
 
-    let mut vec = Vec::with_capacity(1024);
-
-    let mut key_frame = true;
-    let mut timestamp = None;
-    let mut number_of_messages_to_forward = 0;
+    let pending = Arc::new(std::sync::Mutex::new(PendingFragment::default()));
+    let pending_eos = Arc::clone(&pending);
+    let send_eos = send.clone();
 
     appsink.set_callbacks(gstreamer_app::AppSinkCallbacks::builder()
         .new_sample(move |app_sink| {
             if let Ok(sample) = app_sink.pull_sample() {
                 if let Some(buffer) = sample.buffer_owned() {
+                    let Ok(mut pending) = pending.lock() else {
+                        return Err(FlowError::Error);
+                    };
      
                     if let Some(pts) = buffer.pts() {
-                        let _ = timestamp.insert(pts);
+                        let _ = pending.timestamp.insert(pts);
                     }
             
                     let mapa = buffer.map_readable().unwrap();
                     let mut slice = mapa.to_vec();
 
-                    if number_of_messages_to_forward < 2 {
+                    if slice.len() < 8 {
+                        pending.data.append(&mut slice);
+                        return Ok(FlowSuccess::Ok)
+                    }
+
+                    // Init segment, fmp4 muxers send ftyp and moov in a single buffer
+                    let box_type = [slice[4], slice[5], slice[6], slice[7]];
+                    if &box_type == b"ftyp" || &box_type == b"moov" {
+                        if let Some(track) = crate::mp4::parse_init(&slice) {
+                            pending.track = Some(track);
+                        }
                         let _ =send.send(Arc::new(ParsedBuffer{
+                            generation,
                             data: slice,
-                            message_type: if number_of_messages_to_forward == 0 {
+                            message_type: if &box_type == b"ftyp" {
                                 crate::MessageType::FirstFrame
                             } else {
                                 crate::MessageType::MoovPacket
                             },
                             timestamp: None,
                         }));
-                        number_of_messages_to_forward += 1; 
                         return Ok(FlowSuccess::Ok)
                     }
-            
-                        // Check for I FRAME
-                    if buffer.flags().iter().any(|f| {
-                        BufferFlags::DELTA_UNIT == f
-                    }) {
-                        key_frame = false;
-                    };
-            
-                    // moof 6d6f 6f66
-                    if slice[4] == 0x6d && slice[5] == 0x6f && slice[6] == 0x6f && slice[7] == 0x66 {
-                        if let Err(_) = send.send(Arc::new(ParsedBuffer {
-                            data: vec.clone(),
-                            message_type: if key_frame {
-                                crate::MessageType::KeyFrame
-                            } else {
-                                crate::MessageType::Fragment
-                            },
-                            timestamp
-                        })) {
+
+                    if &box_type == b"moof" {
+                        if !pending.data.is_empty() {
+                            if let Err(_) = send.send(Arc::new(pending.take(generation))) {
                                 // TODO log and handle error
                             }
-                            vec.clear();
-                            key_frame = true;
-                            timestamp.take();
                         }
-                        vec.append(&mut slice);
-            
+                        // The first sample decides whether the fragment or chunk can start playback
+                        pending.key_frame = pending.track
+                            .and_then(|track| crate::mp4::parse_moof(&slice, &track))
+                            .map(|info| info.sync);
+                    } else if &box_type != b"mdat" && pending.key_frame.is_none() {
+                        // Moof could not be read, fall back to the flags of the first sample buffer
+                        pending.key_frame = Some(!buffer.flags().contains(BufferFlags::DELTA_UNIT));
+                    }
+                    pending.data.append(&mut slice);
                 }
             }
-            
+
             Ok(FlowSuccess::Ok)
-        }).build()
+        })
+        .eos(move |_| {
+            // Flush the last fragment, it won't be followed by another moof
+            if let Ok(mut pending) = pending_eos.lock() {
+                if !pending.data.is_empty() {
+                    let _ = send_eos.send(Arc::new(pending.take(generation)));
+                }
+            }
+            let _ = send_eos.send(Arc::new(ParsedBuffer {
+                generation,
+                data: Vec::new(),
+                message_type: crate::MessageType::EndOfStream,
+                timestamp: None
+            }));
+        })
+        .build()
     );
 
     Ok(pipeline)
 }
 
 
-pub async  fn init_moov_header(mut recv: Receiver<Arc<ParsedBuffer>>, moov: Arc<RwLock<Vec<Vec<u8>>>>) -> Arc<Vec<Vec<u8>>> {
+/// Init segment (ftyp + moov) of the running pipeline
+#[derive(Debug, Default, Clone)]
+pub struct InitSegment {
+    /// Incremented every time a pipeline is (re)started
+    pub generation: u64,
+    pub packets: Vec<Vec<u8>>,
+    pub codec: Option<String>,
+    pub width: Option<u16>,
+    pub height: Option<u16>
+}
 
-    loop { 
-        let mut moov = moov.write().await;
-        loop {
-            if let Ok(buffer) = recv.recv().await {
-                if crate::MessageType::FirstFrame != buffer.message_type
-                    && crate::MessageType::MoovPacket != buffer.message_type {
-                        break;
-                }
-                moov.push(buffer.data.clone());
-            } else {
-                break;
-            }
+impl InitSegment {
+    fn new(first_frame: &ParsedBuffer) -> Self {
+        Self {
+            generation: first_frame.generation,
+            packets: vec![first_frame.data.clone()],
+            ..Default::default()
+        }
+    }
+
+    /// Init segment read back from a recording
+    pub fn from_packets(packets: Vec<Vec<u8>>) -> Self {
+        let mut init = Self {
+            packets,
+            ..Default::default()
+        };
+        init.parse_media_info();
+        init
+    }
+
+    /// Fills in codec and resolution from the avc1 sample entry
+    fn parse_media_info(&mut self) {
+        let data = self.packets.concat();
+
+        if let Some(avcc) = find_box(&data, b"avcC").filter(|b| b.len() >= 4) {
+            self.codec = Some(format!("avc1.{:02X}{:02X}{:02X}", avcc[1], avcc[2], avcc[3]));
+        }
+        // avc1: 6 reserved, data ref index, 16 bytes predefined/reserved, then width and height
+        if let Some(avc1) = find_box(&data, b"avc1").filter(|b| b.len() >= 28) {
+            self.width = Some(u16::from_be_bytes([avc1[24], avc1[25]]));
+            self.height = Some(u16::from_be_bytes([avc1[26], avc1[27]]));
+        }
+    }
+
+    /// Control message announcing this init segment to live view clients
+    pub fn control_message(&self) -> String {
+        serde_json::json!({
+            "type": "init",
+            "generation": self.generation,
+            "codec": self.codec,
+            "width": self.width,
+            "height": self.height
+        }).to_string()
+    }
+}
+
+/// Returns the content following the first box of type `name`
+fn find_box<'a>(data: &'a [u8], name: &[u8]) -> Option<&'a [u8]> {
+    data.windows(4)
+        .position(|w| w == name)
+        .map(|p| &data[p + 4..])
+}
+
+/// Collects init segment packets of a new pipeline generation
+#[derive(Default)]
+pub struct InitSegmentCollector {
+    pending: Option<InitSegment>
+}
+
+impl InitSegmentCollector {
+    pub fn new() -> Self {
+        Self {
+            pending: None
         }
+    }
 
-        drop(moov);
+    /// Feeds a buffer, returns the init segment once the first fragment after it arrives
+    pub fn push(&mut self, buffer: &ParsedBuffer) -> Option<InitSegment> {
+        match buffer.message_type {
+            crate::MessageType::FirstFrame => {
+                self.pending = Some(InitSegment::new(buffer));
+                None
+            },
+            crate::MessageType::MoovPacket => {
+                if let Some(ref mut pending) = self.pending {
+                    pending.packets.push(buffer.data.clone());
+                }
+                None
+            },
+            crate::MessageType::KeyFrame | crate::MessageType::Fragment => {
+                let mut init = self.pending.take()?;
+                init.parse_media_info();
+                Some(init)
+            },
+            crate::MessageType::EndOfStream => None
+        }
+    }
+}
 
-        while let Ok(buffer) = recv.recv().await {
-            if let crate::MessageType::FirstFrame = buffer.message_type {
-                break;
+pub async fn init_moov_header(mut recv: Receiver<Arc<ParsedBuffer>>, moov: Arc<RwLock<InitSegment>>) {
+    let mut collector = InitSegmentCollector::new();
+    loop {
+        match recv.recv().await {
+            Ok(buffer) => {
+                if buffer.message_type == crate::MessageType::FirstFrame {
+                    info!("New init segment, generation {}", buffer.generation);
+                }
+                if let Some(init) = collector.push(&buffer) {
+                    *moov.write().await = init;
+                }
+            },
+            Err(RecvError::Lagged(skipped)) => {
+                warn!("Init segment collector lagged by {skipped} messages");
+            },
+            Err(RecvError::Closed) => {
+                return;
             }
         }
     }
@@ -235,6 +458,23 @@ pub async  fn init_moov_header(mut recv: Receiver<Arc<ParsedBuffer>>, moov: Arc<
 
 impl Config {
 
+    pub fn source(&self) -> &str {
+        &self.source
+    }
+
+    pub fn preview(&self, devices: &HashMap<String, Device>, unsatisfied: Vec<String>) -> PipelinePreview {
+        PipelinePreview {
+            source: self.source.clone(),
+            device_id: Device::find(devices, &self.source).map(|d| d.id.clone()),
+            use_cam_builtin_encoder: self.use_cam_builtin_encoder,
+            custom_pipeline: self.pipeline_override.is_some(),
+            width: self.width as u32,
+            height: self.height as u32,
+            fps: FRAMERATE,
+            unsatisfied
+        }
+    }
+
     pub fn find_optimal_settings(devices: &HashMap<String, Device>, config: PipelineConfig) -> Self {
 
         let mut source = "".to_string();
@@ -245,7 +485,7 @@ impl Config {
 
 
         for (_, device) in devices {
-            if check_set_parameter(&device.path.to_string(), &config.source) {
+            if config.source.as_ref().map(|s| !device.matches(s)).unwrap_or(false) {
                 continue;
             }
 
@@ -256,17 +496,8 @@ impl Config {
                 }
                 if support_for_builtin_encoder || !use_cam_builtin_encoder {
                     for res in cap.resolution.iter() {
-                        let (res, width, height) = match res {
-                            FrameSizeEnum::Discrete(d) => {
-                                (d.width *d.height ,d.width, d.height)
-                            },
-                            FrameSizeEnum::Stepwise(s) => {
-                                let width = config.width.clone().unwrap_or(0).max(s.max_width);
-                                let height = config.height.clone().unwrap_or(0).max(s.max_height);
-                                // TODO keep the aspect ratio
-                                (width * height, width, height)
-                            }
-                        };
+                        let (width, height) = frame_size(res, &config);
+                        let res = width * height;
                         if check_set_parameter(&width, &config.width) ||
                             check_set_parameter(&height, &config.height) {
                             continue;
@@ -288,13 +519,75 @@ impl Config {
             source,
             use_cam_builtin_encoder,
             width: max_width as i32,
-            height: max_height as i32
+            height: max_height as i32,
+            pipeline_override: config.pipeline_override,
+            latency_mode: config.latency_mode.unwrap_or_default(),
+            chunk_duration: config.chunk_duration.unwrap_or(DEFAULT_CHUNK_DURATION)
+        }
+    }
+}
+
+
+fn frame_size(res: &FrameSizeEnum, config: &PipelineConfig) -> (u32, u32) {
+    match res {
+        FrameSizeEnum::Discrete(d) => {
+            (d.width, d.height)
+        },
+        FrameSizeEnum::Stepwise(s) => {
+            let width = config.width.clone().unwrap_or(0).max(s.max_width);
+            let height = config.height.clone().unwrap_or(0).max(s.max_height);
+            // TODO keep the aspect ratio
+            (width, height)
         }
     }
 }
 
+/// Explains which of the requested constraints no device can satisfy
+pub fn unsatisfied_constraints(devices: &HashMap<String, Device>, config: &PipelineConfig) -> Vec<String> {
+    let mut reasons = Vec::new();
+    if let Err(e) = validate_pipeline_config(config) {
+        reasons.push(e);
+    }
+
+    let devices: Vec<&Device> = devices.values()
+        .filter(|d| config.source.as_ref().map(|s| d.matches(s)).unwrap_or(true))
+        .collect();
+    if devices.is_empty() {
+        match config.source {
+            Some(ref source) => reasons.push(format!("no device matches source {source}")),
+            None => reasons.push("no capture device found".to_string())
+        }
+        return reasons;
+    }
+
+    let caps: Vec<&Capabilties> = devices.iter()
+        .flat_map(|d| d.capabilities.iter())
+        .filter(|c| !check_set_parameter(&format_supports_builtin_encoder(&c.format), &config.use_cam_builtin_encoder))
+        .collect();
+    if caps.is_empty() {
+        reasons.push(match config.use_cam_builtin_encoder {
+            Some(true) => "no device offers an H264 format, which use_cam_builtin_encoder=true requires".to_string(),
+            Some(false) => "no device offers a format other than H264, which use_cam_builtin_encoder=false requires".to_string(),
+            None => "no device reports any capture format".to_string(),
+        });
+        return reasons;
+    }
+
+    let supported = caps.iter()
+        .flat_map(|c| c.resolution.iter())
+        .map(|r| frame_size(r, config))
+        .any(|(width, height)| !check_set_parameter(&width, &config.width) && !check_set_parameter(&height, &config.height));
+    if !supported {
+        let width = config.width.map(|w| w.to_string()).unwrap_or("any".to_string());
+        let height = config.height.map(|h| h.to_string()).unwrap_or("any".to_string());
+        let encoder = if config.use_cam_builtin_encoder.unwrap_or(false) { " H264" } else { "" };
+        reasons.push(format!("no device supports {width}x{height}{encoder}"));
+    }
+
+    reasons
+}
 
-fn format_supports_builtin_encoder(format: &str) -> bool {
+pub fn format_supports_builtin_encoder(format: &str) -> bool {
     format.to_uppercase() == "H264"
 }
 
@@ -0,0 +1,191 @@
+use std::collections::HashMap;
+use std::sync::atomic::{AtomicU64, AtomicUsize, Ordering};
+use std::sync::{Arc, Mutex};
+
+use futures_util::{Sink, SinkExt};
+use poem::web::websocket::Message;
+use tokio::sync::mpsc;
+
+use crate::models::ViewerStats;
+
+/// Bytes a single viewer may have queued before fragments are dropped
+pub const VIEWER_QUEUE_BUDGET: usize = 4 * 1024 * 1024;
+
+struct Viewer {
+    stats: ViewerStats,
+    queued: Arc<AtomicUsize>
+}
+
+/// Live view clients currently connected to `/ws`
+#[derive(Clone, Default)]
+pub struct Viewers {
+    next_id: Arc<AtomicU64>,
+    clients: Arc<Mutex<HashMap<u64, Viewer>>>
+}
+
+impl Viewers {
+    pub fn register(&self, address: String, queue: &ViewerQueue) -> u64 {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.insert(id, Viewer {
+                stats: ViewerStats {
+                    id,
+                    address,
+                    connected_at: chrono::Local::now().to_rfc3339(),
+                    ..Default::default()
+                },
+                queued: Arc::clone(&queue.queued)
+            });
+        }
+        id
+    }
+
+    pub fn update(&self, id: u64, update: impl FnOnce(&mut ViewerStats)) {
+        if let Ok(mut clients) = self.clients.lock() {
+            if let Some(viewer) = clients.get_mut(&id) {
+                update(&mut viewer.stats);
+            }
+        }
+    }
+
+    pub fn unregister(&self, id: u64) {
+        if let Ok(mut clients) = self.clients.lock() {
+            clients.remove(&id);
+        }
+    }
+
+    pub fn list(&self) -> Vec<ViewerStats> {
+        let Ok(clients) = self.clients.lock() else {
+            return Vec::new();
+        };
+        let mut viewers: Vec<ViewerStats> = clients.values()
+            .map(|v| ViewerStats {
+                queued_bytes: v.queued.load(Ordering::Relaxed) as u64,
+                ..v.stats.clone()
+            })
+            .collect();
+        viewers.sort_by_key(|v| v.id);
+        viewers
+    }
+}
+
+#[derive(Debug, PartialEq)]
+pub enum QueueError {
+    Full,
+    Closed
+}
+
+/// Per viewer send queue, a writer task drains it into the socket
+pub struct ViewerQueue {
+    tx: mpsc::UnboundedSender<Message>,
+    queued: Arc<AtomicUsize>
+}
+
+impl ViewerQueue {
+    pub fn spawn<S>(mut sink: S) -> Self
+    where S: Sink<Message> + Unpin + Send + 'static
+    {
+        let (tx, mut rx) = mpsc::unbounded_channel::<Message>();
+        let queued = Arc::new(AtomicUsize::new(0));
+
+        let queued_ref = Arc::clone(&queued);
+        tokio::spawn(async move {
+            while let Some(msg) = rx.recv().await {
+                let len = msg.as_bytes().len();
+                let is_close = msg.is_close();
+                let sent = sink.send(msg).await;
+                queued_ref.fetch_sub(len, Ordering::Relaxed);
+                if sent.is_err() || is_close {
+                    break;
+                }
+            }
+            let _ = sink.close().await;
+        });
+
+        Self {
+            tx,
+            queued
+        }
+    }
+
+    /// Queues a message if it fits in the byte budget
+    pub fn try_push(&self, msg: Message) -> Result<(), QueueError> {
+        let len = msg.as_bytes().len();
+        // Checking and reserving in one step, concurrent pushers could overshoot the budget otherwise
+        self.queued.fetch_update(Ordering::Relaxed, Ordering::Relaxed, |queued| {
+            Some(queued + len).filter(|total| *total <= VIEWER_QUEUE_BUDGET)
+        }).map_err(|_| QueueError::Full)?;
+        self.tx.send(msg).map_err(|_| {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+            QueueError::Closed
+        })
+    }
+
+    /// Queues a message regardless of the budget, used for init segments and control messages
+    pub fn push(&self, msg: Message) -> Result<(), QueueError> {
+        let len = msg.as_bytes().len();
+        self.queued.fetch_add(len, Ordering::Relaxed);
+        self.tx.send(msg).map_err(|_| {
+            self.queued.fetch_sub(len, Ordering::Relaxed);
+            QueueError::Closed
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+    use tokio::sync::Semaphore;
+    use super::*;
+
+    /// Queue whose writer sends one message per permit added to the returned semaphore
+    fn gated_queue() -> (ViewerQueue, Arc<Semaphore>) {
+        let gate = Arc::new(Semaphore::new(0));
+        let sink = futures_util::sink::unfold(Arc::clone(&gate), |gate, _: Message| async move {
+            gate.acquire().await.unwrap().forget();
+            Ok::<_, ()>(gate)
+        });
+        (ViewerQueue::spawn(Box::pin(sink)), gate)
+    }
+
+    fn fragment(len: usize) -> Message {
+        Message::Binary(vec![0; len])
+    }
+
+    #[tokio::test]
+    async fn try_push_stops_at_the_budget() {
+        let (queue, gate) = gated_queue();
+        for _ in 0..4 {
+            assert_eq!(queue.try_push(fragment(VIEWER_QUEUE_BUDGET / 4)), Ok(()));
+        }
+        assert_eq!(queue.try_push(fragment(1)), Err(QueueError::Full));
+
+        gate.add_permits(4);
+        tokio::time::timeout(Duration::from_secs(5), async {
+            while queue.queued.load(Ordering::Relaxed) > 0 {
+                tokio::time::sleep(Duration::from_millis(1)).await;
+            }
+        }).await.expect("Writer didn't drain the queue");
+        assert_eq!(queue.try_push(fragment(VIEWER_QUEUE_BUDGET / 4)), Ok(()));
+    }
+
+    #[tokio::test]
+    async fn concurrent_pushers_stay_within_the_budget() {
+        let (queue, _gate) = gated_queue();
+        let len = VIEWER_QUEUE_BUDGET / 16;
+        let pushed = AtomicUsize::new(0);
+        std::thread::scope(|scope| {
+            for _ in 0..8 {
+                scope.spawn(|| {
+                    for _ in 0..16 {
+                        if queue.try_push(fragment(len)).is_ok() {
+                            pushed.fetch_add(1, Ordering::Relaxed);
+                        }
+                    }
+                });
+            }
+        });
+        assert_eq!(pushed.load(Ordering::Relaxed), 16);
+        assert_eq!(queue.queued.load(Ordering::Relaxed), VIEWER_QUEUE_BUDGET);
+    }
+}